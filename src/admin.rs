@@ -0,0 +1,93 @@
+use crate::config::Config;
+use crate::serve::Cache;
+use crate::signature::tokens_match;
+use rocket::fairing::AdHoc;
+use rocket::http::Status;
+use rocket::outcome::Outcome;
+use rocket::request::{self, FromRequest, Request};
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+use rocket::State;
+
+/// Amount of entries listed when a request doesn't provide a limit.
+const DEFAULT_LIMIT: usize = 20;
+
+/// A cached image and how long it has gone without being accessed.
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+struct StaleEntry {
+    pathname: String,
+    idle_secs: u64,
+}
+
+/// Amount of expired entries that were purged from the cache.
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+struct Purged {
+    purged: usize,
+}
+
+/// Request guard that ensures an `Authorization: Bearer <token>` header matches the
+/// configured `admin_token`. Admin routes are disabled when no token is configured.
+struct AdminToken;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AdminToken {
+    type Error = &'static str;
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let token = req
+            .rocket()
+            .state::<Config>()
+            .and_then(|config| config.admin_token.as_ref());
+
+        let header = req
+            .headers()
+            .get_one("Authorization")
+            .and_then(|header| header.strip_prefix("Bearer "));
+
+        match (token, header) {
+            (Some(token), Some(header)) if tokens_match(token, header) => {
+                Outcome::Success(AdminToken)
+            }
+            (None, _) => Outcome::Failure((Status::Forbidden, "Admin routes are disabled.")),
+            _ => Outcome::Failure((Status::Unauthorized, "The admin token is invalid.")),
+        }
+    }
+}
+
+#[get("/admin/cache/stalest?<limit>")]
+async fn list_stalest(
+    limit: Option<usize>,
+    _token: AdminToken,
+    state: &State<Cache>,
+) -> Json<Vec<StaleEntry>> {
+    let cache = state.lock().await;
+
+    Json(
+        cache
+            .stalest()
+            .into_iter()
+            .take(limit.unwrap_or(DEFAULT_LIMIT))
+            .map(|(pathname, last_accessed)| StaleEntry {
+                pathname: pathname.clone(),
+                idle_secs: last_accessed.elapsed().as_secs(),
+            })
+            .collect(),
+    )
+}
+
+#[post("/admin/cache/purge")]
+async fn purge_expired(_token: AdminToken, state: &State<Cache>) -> Json<Purged> {
+    let purged = state.lock().await.purge_expired();
+
+    info_!("Purged {} expired images from cache.", purged);
+
+    Json(Purged { purged })
+}
+
+pub fn main() -> AdHoc {
+    AdHoc::on_ignite("admin", |rocket| async {
+        rocket.mount("/", routes![list_stalest, purge_expired])
+    })
+}
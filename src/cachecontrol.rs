@@ -0,0 +1,66 @@
+use rocket::outcome::Outcome;
+use rocket::request::{self, FromRequest, Request};
+use std::convert::Infallible;
+
+/// Whether a request carries the `Cache-Control: no-cache` directive, in which case the
+/// requested image is regenerated rather than served from the cache or disk.
+pub struct NoCache(pub bool);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for NoCache {
+    type Error = Infallible;
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        // directives are case-insensitive and may be split across several headers
+        let no_cache = req
+            .headers()
+            .get("Cache-Control")
+            .flat_map(|value| value.split(','))
+            .any(|directive| directive.trim().eq_ignore_ascii_case("no-cache"));
+
+        Outcome::Success(NoCache(no_cache))
+    }
+}
+
+/// The entity tags of a request's `If-None-Match` header, which a client sends along with
+/// the images that it already has a copy of.
+pub struct IfNoneMatch(pub Option<String>);
+
+impl IfNoneMatch {
+    /// Determines if an entity tag is one that the client already has, where weak tags
+    /// (`W/"..."`) match just as strong tags do and `*` matches any tag.
+    ///
+    /// Arguments:
+    ///
+    /// * `etag` - &str
+    ///
+    /// Returns: `bool`
+    ///
+    /// Usage: ```if_none_match.matches(&etag);```
+    pub fn matches(&self, etag: &str) -> bool {
+        let etags = match &self.0 {
+            Some(etags) => etags,
+            None => return false,
+        };
+
+        etags.split(',').map(str::trim).any(|tag| {
+            tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == etag.trim_start_matches("W/")
+        })
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for IfNoneMatch {
+    type Error = Infallible;
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        // several headers are treated as a single list of tags
+        let etags: Vec<&str> = req.headers().get("If-None-Match").collect();
+        let if_none_match = match etags.is_empty() {
+            true => None,
+            false => Some(etags.join(",")),
+        };
+
+        Outcome::Success(IfNoneMatch(if_none_match))
+    }
+}
@@ -0,0 +1,299 @@
+use crate::config::Config;
+use crate::reqimage::VARIANTS_DIR;
+use crate::serve::{forget_image, Cache, ColorCache};
+use crate::utils::{get_string_path, has_supported_extension};
+use rocket::fairing::AdHoc;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// A directory that variants are saved into, along with the static directories that their
+/// original images are within, whose directories the variants directory mirrors.
+#[derive(Debug, Clone)]
+pub struct VariantDir {
+    pub dir: PathBuf,
+    pub roots: Vec<PathBuf>,
+}
+
+/// Retrieves the directories that variants are saved into, which is either the `cache_dir`
+/// for every static directory, or the `.variants` directory within each of them.
+///
+/// Arguments:
+///
+/// * `config` - &Config
+///
+/// Returns: `Vec<VariantDir>`
+///
+/// Usage: ```variant_dirs(config);```
+pub fn variant_dirs(config: &Config) -> Vec<VariantDir> {
+    match &config.cache_dir {
+        Some(cache_dir) => vec![VariantDir {
+            dir: cache_dir.clone(),
+            roots: config.roots(),
+        }],
+        None => config
+            .roots()
+            .into_iter()
+            .map(|root| VariantDir {
+                dir: root.join(VARIANTS_DIR),
+                roots: vec![root],
+            })
+            .collect(),
+    }
+}
+
+/// Lists every image within a directory and its subdirectories.
+///
+/// Arguments:
+///
+/// * `dir` - &Path
+/// * `images` - &mut Vec<PathBuf>
+///
+/// Usage: ```list_images(&variant_dir.dir, &mut variants);```
+fn list_images(dir: &Path, images: &mut Vec<PathBuf>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+
+    for path in entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+    {
+        if path.is_dir() {
+            list_images(&path, images);
+        } else if has_supported_extension(&path) {
+            images.push(path);
+        }
+    }
+}
+
+/// Retrieves the stems of the images within a directory => `photo.png` => `photo`
+///
+/// Arguments:
+///
+/// * `dir` - &Path
+///
+/// Returns: `Vec<String>`
+///
+/// Usage: ```image_stems(&root.join(dirs));```
+fn image_stems(dir: &Path) -> Vec<String> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+
+    entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file() && has_supported_extension(path))
+        .filter_map(|path| path.file_stem().and_then(OsStr::to_str).map(String::from))
+        .collect()
+}
+
+/// Removes every variant whose original image no longer exists. A variant is named after the
+/// stem of its original image (`photo_200w.png`, or `photo.webp` once it's converted) within
+/// the same directories, so it's kept as long as an image in those directories has a stem
+/// that it's named after, even if that image isn't the one it was generated from.
+///
+/// Arguments:
+///
+/// * `variant_dirs` - &[VariantDir]
+///
+/// Returns: `Vec<String>` - the pathnames of the variants that were removed
+///
+/// Usage: ```remove_orphans(&variant_dirs(config));```
+pub fn remove_orphans(variant_dirs: &[VariantDir]) -> Vec<String> {
+    let mut removed = Vec::new();
+    // every variant within a directory is checked against the same original images
+    let mut stems: HashMap<PathBuf, Vec<String>> = HashMap::new();
+
+    for VariantDir { dir, roots } in variant_dirs {
+        let mut variants = Vec::new();
+        list_images(dir, &mut variants);
+
+        for variant in variants {
+            let dirs = match variant.strip_prefix(dir).ok().and_then(Path::parent) {
+                Some(dirs) => dirs.to_path_buf(),
+                None => continue,
+            };
+            let name = match variant.file_stem().and_then(OsStr::to_str) {
+                Some(name) => name,
+                None => continue,
+            };
+
+            let has_original = roots.iter().any(|root| {
+                let originals = root.join(&dirs);
+                stems
+                    .entry(originals)
+                    .or_insert_with_key(|originals| image_stems(originals))
+                    .iter()
+                    .any(|stem| {
+                        name.strip_prefix(stem.as_str())
+                            .is_some_and(|rest| rest.is_empty() || rest.starts_with('_'))
+                    })
+            });
+
+            if !has_original && fs::remove_file(&variant).is_ok() {
+                removed.push(get_string_path(&variant));
+            }
+        }
+    }
+
+    removed
+}
+
+/// Removes every variant whose original image no longer exists, along with its cache entry.
+///
+/// Arguments:
+///
+/// * `variant_dirs` - Vec<VariantDir>
+/// * `state` - &Cache
+///
+/// Returns: `usize` - the amount of variants that were removed
+///
+/// Usage: ```clean_orphans(variant_dirs(config), state).await;```
+pub async fn clean_orphans(variant_dirs: Vec<VariantDir>, state: &Cache) -> usize {
+    // scanning the directories blocks, so it's kept off of the async workers
+    let removed = rocket::tokio::task::spawn_blocking(move || remove_orphans(&variant_dirs))
+        .await
+        .unwrap_or_default();
+
+    let mut cache = state.lock().await;
+    for pathname in &removed {
+        cache.remove(pathname);
+    }
+
+    removed.len()
+}
+
+/// Retrieves the original images within the cache whose files no longer exist, along with the
+/// path of each one within its static directory. A content addressed original image is cached
+/// under its pathname and the hash of its contents => <rootdir>/<path>#<hash>
+///
+/// Arguments:
+///
+/// * `keys` - &[String]
+/// * `roots` - &[PathBuf]
+/// * `cache_dir` - Option<&Path>
+///
+/// Returns: `Vec<(String, PathBuf)>`
+///
+/// Usage: ```missing_originals(&keys, &config.roots(), config.cache_dir.as_deref());```
+fn missing_originals(
+    keys: &[String],
+    roots: &[PathBuf],
+    cache_dir: Option<&Path>,
+) -> Vec<(String, PathBuf)> {
+    keys.iter()
+        .filter_map(|key| {
+            let pathname = key
+                .split_once('#')
+                .map_or(key.as_str(), |(pathname, _)| pathname);
+            let pathname = Path::new(pathname);
+            // variants are only cached once they're saved, or exist in memory alone
+            if cache_dir.is_some_and(|cache_dir| pathname.starts_with(cache_dir)) {
+                return None;
+            }
+
+            let path = roots
+                .iter()
+                .find_map(|root| pathname.strip_prefix(root).ok())
+                .filter(|path| !path.starts_with(VARIANTS_DIR))?;
+
+            match pathname.is_file() {
+                true => None,
+                false => Some((key.clone(), path.to_path_buf())),
+            }
+        })
+        .collect()
+}
+
+/// Removes every original image whose file no longer exists from the caches, along with its
+/// variants, as cached images are served without checking that their original image exists.
+///
+/// Arguments:
+///
+/// * `config` - &Config
+/// * `state` - &Cache
+/// * `colors` - &ColorCache
+///
+/// Returns: `usize` - the amount of images that were removed from the cache
+///
+/// Usage: ```clean_missing_originals(config, state, colors).await;```
+pub async fn clean_missing_originals(config: &Config, state: &Cache, colors: &ColorCache) -> usize {
+    let keys: Vec<String> = state
+        .lock()
+        .await
+        .iter()
+        .map(|(key, _)| key.clone())
+        .collect();
+    let roots = config.roots();
+    let cache_dir = config.cache_dir.clone();
+
+    // checking every original image blocks, so it's kept off of the async workers
+    let missing = rocket::tokio::task::spawn_blocking(move || {
+        missing_originals(&keys, &roots, cache_dir.as_deref())
+    })
+    .await
+    .unwrap_or_default();
+
+    let mut removed = 0;
+    for (key, path) in missing {
+        // the key may be within another static directory than the one the path resolves to
+        if state.lock().await.remove(&key).is_some() {
+            removed += 1;
+        }
+        removed += forget_image(config, &path, state, colors).await;
+    }
+
+    removed
+}
+
+/// Periodically removes orphaned variants, and the cached images of deleted original images,
+/// every `orphan_cleanup_interval_secs`, if it's configured, until the server shuts down.
+///
+/// Arguments: (none)
+///
+/// Returns: `AdHoc`
+///
+/// Usage: ```rocket::build().attach(cleanup::main());```
+pub fn main() -> AdHoc {
+    AdHoc::on_liftoff("orphaned variants", |rocket| {
+        Box::pin(async move {
+            let (config, state, colors) = match (
+                rocket.state::<Config>(),
+                rocket.state::<Cache>(),
+                rocket.state::<ColorCache>(),
+            ) {
+                (Some(config), Some(state), Some(colors)) => {
+                    (config.clone(), state.clone(), colors.clone())
+                }
+                _ => return,
+            };
+            let interval = match config.orphan_cleanup_interval_secs {
+                Some(secs) if secs > 0 => Duration::from_secs(secs),
+                _ => return,
+            };
+            let variant_dirs = variant_dirs(&config);
+            let mut shutdown = rocket.shutdown();
+
+            rocket::tokio::spawn(async move {
+                loop {
+                    rocket::tokio::select! {
+                        _ = rocket::tokio::time::sleep(interval) => (),
+                        _ = &mut shutdown => return,
+                    }
+
+                    let removed = clean_orphans(variant_dirs.clone(), &state).await;
+                    info!("Removed {} orphaned variants.", removed);
+
+                    let removed = clean_missing_originals(&config, &state, &colors).await;
+                    info!("Removed {} cached images of deleted originals.", removed);
+                }
+            });
+        })
+    })
+}
@@ -0,0 +1,132 @@
+use crate::config::Config;
+use flate2::write::{GzEncoder, ZlibEncoder};
+use flate2::Compression;
+use rocket::fairing::AdHoc;
+use rocket::http::{ContentType, Header};
+use std::io::{Cursor, Write};
+
+/// Smallest body, in bytes, that's worth compressing.
+const MIN_COMPRESSIBLE_BYTES: usize = 256;
+
+/// Encodings that a response can be compressed with, in order of preference.
+const ENCODINGS: [&str; 2] = ["gzip", "deflate"];
+
+/// Determines if a content type is text that compresses well, rather than an image or other
+/// binary that's already compressed.
+///
+/// Arguments:
+///
+/// * `content_type` - &ContentType
+///
+/// Returns: `bool`
+///
+/// Usage: ```is_compressible(&content_type);```
+fn is_compressible(content_type: &ContentType) -> bool {
+    content_type.top() == "text"
+        || content_type.is_json()
+        || content_type.is_javascript()
+        || content_type.is_xml()
+        || content_type.is_svg()
+}
+
+/// Retrieves the preferred encoding that's advertised by an `Accept-Encoding` header.
+///
+/// Arguments:
+///
+/// * `accept_encoding` - &str
+///
+/// Returns: `Option<&str>`
+///
+/// Usage: ```get_encoding(accept_encoding);```
+fn get_encoding(accept_encoding: &str) -> Option<&'static str> {
+    let accepted: Vec<&str> = accept_encoding
+        .split(',')
+        .map(|encoding| encoding.split(';').next().unwrap_or_default().trim())
+        .collect();
+
+    ENCODINGS
+        .iter()
+        .find(|encoding| accepted.contains(encoding))
+        .copied()
+}
+
+/// Compresses the contents of a response with an encoding from `ENCODINGS`.
+///
+/// Arguments:
+///
+/// * `encoding` - &str
+/// * `contents` - &[u8]
+///
+/// Returns: `std::io::Result<Vec<u8>>`
+///
+/// Usage: ```compress(encoding, &contents);```
+fn compress(encoding: &str, contents: &[u8]) -> std::io::Result<Vec<u8>> {
+    match encoding {
+        "gzip" => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(contents)?;
+            encoder.finish()
+        }
+        _ => {
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(contents)?;
+            encoder.finish()
+        }
+    }
+}
+
+pub fn main() -> AdHoc {
+    AdHoc::on_response("compression", |req, res| {
+        Box::pin(async move {
+            let enabled = req
+                .rocket()
+                .state::<Config>()
+                .is_some_and(|config| config.compression);
+
+            let compressible = res
+                .content_type()
+                .is_some_and(|content_type| is_compressible(&content_type));
+
+            if !enabled || !compressible || res.headers().contains("Content-Encoding") {
+                return;
+            }
+
+            // the response differs depending on the encodings that a client accepts, even
+            // when this client doesn't accept any of them
+            res.adjoin_header(Header::new("Vary", "Accept-Encoding"));
+
+            let encoding = match req
+                .headers()
+                .get_one("Accept-Encoding")
+                .and_then(get_encoding)
+            {
+                Some(encoding) => encoding,
+                None => return,
+            };
+
+            let contents = match res.body_mut().to_bytes().await {
+                Ok(contents) => contents,
+                Err(reason) => {
+                    warn_!("Unable to read the response to compress it: {}", reason);
+                    return;
+                }
+            };
+
+            let contents = match contents.len() >= MIN_COMPRESSIBLE_BYTES {
+                true => match compress(encoding, &contents) {
+                    Ok(compressed) => {
+                        res.set_header(Header::new("Content-Encoding", encoding));
+                        compressed
+                    }
+                    Err(reason) => {
+                        warn_!("Unable to compress the response: {}", reason);
+                        contents
+                    }
+                },
+                false => contents,
+            };
+
+            res.set_sized_body(contents.len(), Cursor::new(contents));
+        })
+    })
+}
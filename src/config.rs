@@ -0,0 +1,545 @@
+use crate::reqimage::{DEFAULT_FILTER, DEFAULT_UPSCALE_FILTER, FILTERS};
+use crate::utils::parse_hex_color;
+use image::imageops::FilterType;
+use rocket::figment::{self, Figment};
+use rocket::fs::relative;
+use rocket::serde::de::{self, Deserializer, Unexpected, Visitor};
+use rocket::serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::iter;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::thread;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Config {
+    /// Directory that source images are served and resized from (`ROCKET_STATIC_DIR`).
+    #[serde(default = "default_static_dir")]
+    pub static_dir: PathBuf,
+    /// Further directories that source images are served and resized from, which are searched
+    /// in order when `static_dir` doesn't contain an image (`ROCKET_STATIC_DIRS`).
+    #[serde(default)]
+    pub static_dirs: Vec<PathBuf>,
+    /// Directory that resized images are written to, so that the static directories only
+    /// contain original images, or they're written into the static directories
+    /// (`ROCKET_CACHE_DIR`).
+    #[serde(default)]
+    pub cache_dir: Option<PathBuf>,
+    /// Largest width, in pixels, that an image can be resized to (`ROCKET_MAX_WIDTH`).
+    #[serde(default = "default_max_width")]
+    pub max_width: u32,
+    /// Widths, in pixels, that images can be resized to, or any width up to `max_width` is
+    /// allowed (`ROCKET_ALLOWED_WIDTHS`).
+    #[serde(default)]
+    pub allowed_widths: Option<Vec<u32>>,
+    /// How a width that isn't within `allowed_widths` is handled (`ROCKET_WIDTH_MODE`).
+    #[serde(default)]
+    pub width_mode: WidthMode,
+    /// Width, in pixels, that images are resized to when a request provides neither a width
+    /// nor a ratio, where `?ratio=0` still requests the original image, or the original image
+    /// is served (`ROCKET_DEFAULT_WIDTH`).
+    #[serde(default)]
+    pub default_width: Option<u32>,
+    /// Requests per second that each client IP can make to resize images, or requests are
+    /// unlimited (`ROCKET_RATE_LIMIT_PER_SECOND`).
+    #[serde(default)]
+    pub rate_limit_per_second: Option<f64>,
+    /// Requests that each client IP can make at once before being limited
+    /// (`ROCKET_RATE_LIMIT_BURST`).
+    #[serde(default = "default_rate_limit_burst")]
+    pub rate_limit_burst: u32,
+    /// Proxies whose `X-Real-IP` header is trusted as the client IP that requests are limited
+    /// by, rather than the proxy's own IP (`ROCKET_TRUSTED_PROXIES`).
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpAddr>,
+    /// Quality, from 1 to 100, that jpegs are encoded with unless a `quality` is requested
+    /// (`ROCKET_JPEG_QUALITY`).
+    #[serde(default = "default_quality", deserialize_with = "deserialize_quality")]
+    pub jpeg_quality: u8,
+    /// Quality, from 1 to 100, that lossy webps are encoded with unless a `quality` is
+    /// requested (`ROCKET_WEBP_QUALITY`).
+    #[serde(default = "default_quality", deserialize_with = "deserialize_quality")]
+    pub webp_quality: u8,
+    /// Filters and qualities that a `quality_preset` is resized and encoded with by its name,
+    /// which are `fast`, `balanced` and `best` unless they're configured
+    /// (`ROCKET_QUALITY_PRESETS`).
+    #[serde(default = "default_quality_presets")]
+    pub quality_presets: BTreeMap<String, QualityPreset>,
+    /// Hex color (`rrggbb`) that transparency is flattened onto when an image is converted to
+    /// a format without an alpha channel, unless a `bg` is requested (`ROCKET_BACKGROUND`).
+    #[serde(
+        default = "default_background",
+        deserialize_with = "deserialize_hex_color"
+    )]
+    pub background: [u8; 3],
+    /// Include a hash of the original image's contents in cache keys and saved image names, so
+    /// replacing an original image invalidates its variants (`ROCKET_CONTENT_ADDRESSED`).
+    #[serde(default)]
+    pub content_addressed: bool,
+    /// Seconds that clients can cache a served image for in a `Cache-Control` header, by the
+    /// extension of the image as it's served, such as `{ png = 3600, svg = 86400 }`, where
+    /// `default` applies to any other extension, or the header isn't sent
+    /// (`ROCKET_MAX_AGE_SECS`).
+    #[serde(default)]
+    pub max_age_secs: BTreeMap<String, u64>,
+    /// Seconds that clients can cache a served image for when `content_addressed` is enabled,
+    /// which is sent as `immutable` in place of the `max_age_secs`
+    /// (`ROCKET_IMMUTABLE_MAX_AGE_SECS`).
+    #[serde(default = "default_immutable_max_age_secs")]
+    pub immutable_max_age_secs: u64,
+    /// Send a hex SHA-256 digest of every served image's body in an `X-Content-SHA256`
+    /// header, which is computed once and cached alongside the image (`ROCKET_CONTENT_DIGEST`).
+    #[serde(default)]
+    pub content_digest: bool,
+    /// File that a line of json is appended to for every resized image, or resizes aren't
+    /// logged (`ROCKET_RESIZE_LOG`).
+    #[serde(default)]
+    pub resize_log: Option<PathBuf>,
+    /// Compress text and json responses for clients that accept gzip or deflate, while images
+    /// are left as is (`ROCKET_COMPRESSION`).
+    #[serde(default)]
+    pub compression: bool,
+    /// Origins that can retrieve images with cross-origin requests, where `*` allows any origin
+    /// (`ROCKET_CORS_ALLOWED_ORIGINS`).
+    #[serde(default)]
+    pub cors_allowed_origins: Vec<String>,
+    /// Bearer token required to upload images, or uploads are disabled (`ROCKET_UPLOAD_TOKEN`).
+    #[serde(default)]
+    pub upload_token: Option<String>,
+    /// Bearer token required to inspect and manage the cache, or admin routes are disabled
+    /// (`ROCKET_ADMIN_TOKEN`).
+    #[serde(default)]
+    pub admin_token: Option<String>,
+    /// Secret that image urls are signed with, which a request has to carry the signature of
+    /// in a `sig` parameter, or urls aren't signed (`ROCKET_SIGNING_SECRET`).
+    #[serde(default)]
+    pub signing_secret: Option<String>,
+    /// Characters that a requested or uploaded image path can't contain, such as `<>:"|?*` to
+    /// keep paths valid on Windows, while control characters are never allowed
+    /// (`ROCKET_FORBIDDEN_PATH_CHARACTERS`).
+    #[serde(default)]
+    pub forbidden_path_characters: String,
+    /// Extensions of images that are served as is, rather than resized, along with vector
+    /// images, such as `gif` for already optimized animations (`ROCKET_PASSTHROUGH_FORMATS`).
+    #[serde(default)]
+    pub passthrough_formats: Vec<String>,
+    /// Largest image, in bytes, that can be uploaded (`ROCKET_MAX_UPLOAD_BYTES`).
+    #[serde(default = "default_max_upload_bytes")]
+    pub max_upload_bytes: u64,
+    /// Widths, in pixels, below which images are reduced with the faster Triangle filter
+    /// rather than the `downscale_filter`, unless a filter is requested
+    /// (`ROCKET_FAST_FILTER_WIDTH`).
+    #[serde(default)]
+    pub fast_filter_width: u32,
+    /// Resampling filter that images are reduced with, unless a filter is requested or the
+    /// width is below `fast_filter_width` (`ROCKET_DOWNSCALE_FILTER`).
+    #[serde(
+        default = "default_downscale_filter",
+        deserialize_with = "deserialize_filter"
+    )]
+    pub downscale_filter: FilterType,
+    /// Resampling filter that images are enlarged with when upscaling is allowed, unless a
+    /// filter is requested (`ROCKET_UPSCALE_FILTER`).
+    #[serde(
+        default = "default_upscale_filter",
+        deserialize_with = "deserialize_filter"
+    )]
+    pub upscale_filter: FilterType,
+    /// Seconds that an image stays in the cache after it's stored, or images only leave the
+    /// cache to make room (`ROCKET_CACHE_TTL_SECS`).
+    #[serde(default)]
+    pub cache_ttl_secs: Option<u64>,
+    /// Seconds between scans that remove the variants of original images that no longer
+    /// exist, along with their cache entries, or variants are never removed
+    /// (`ROCKET_ORPHAN_CLEANUP_INTERVAL_SECS`).
+    #[serde(default)]
+    pub orphan_cleanup_interval_secs: Option<u64>,
+    /// Combined size, in bytes, that the cached images can take up, past which the least
+    /// recently used images are evicted, or only the number of images is limited
+    /// (`ROCKET_CACHE_MAX_BYTES`).
+    #[serde(default)]
+    pub cache_max_bytes: Option<usize>,
+    /// File that the keys of the cached images are written to when the server shuts down, and
+    /// read back into the cache from when it launches, or the cache starts out empty
+    /// (`ROCKET_CACHE_INDEX`).
+    #[serde(default)]
+    pub cache_index: Option<PathBuf>,
+    /// Log every image that's served from, saved into or evicted from the cache, which is
+    /// logged for nearly every request, or only warnings and errors are logged
+    /// (`ROCKET_VERBOSE_CACHE_LOGGING`).
+    #[serde(default)]
+    pub verbose_cache_logging: bool,
+    /// Largest generated image, in bytes, that's stored in the cache, while larger images are
+    /// served without being cached (`ROCKET_MAX_CACHEABLE_BYTES`).
+    #[serde(default = "default_max_cacheable_bytes")]
+    pub max_cacheable_bytes: usize,
+    /// Images that can be resized at once, while further resizes wait for one to finish
+    /// (`ROCKET_MAX_CONCURRENT_RESIZES`, defaults to the number of cpus).
+    #[serde(default = "default_max_concurrent_resizes")]
+    pub max_concurrent_resizes: usize,
+    /// Threads that block on resizes and file reads and writes, which should exceed
+    /// `max_concurrent_resizes` so that files can still be read while every resize is
+    /// underway, or tokio's default of 512 threads is used (`ROCKET_MAX_BLOCKING_THREADS`).
+    #[serde(default)]
+    pub max_blocking_threads: Option<usize>,
+    /// Milliseconds that a request waits to lock the cache before responding with a 503,
+    /// rather than stalling behind a request that's holding it (`ROCKET_CACHE_LOCK_TIMEOUT_MS`).
+    #[serde(default = "default_cache_lock_timeout_ms")]
+    pub cache_lock_timeout_ms: u64,
+    /// Whether variants are read from and written to disk, only read from disk, or only
+    /// stored in the cache (`ROCKET_DISK_MODE`).
+    #[serde(default)]
+    pub disk_mode: DiskMode,
+    /// Resize images in memory and only store them in the cache, without writing them to disk,
+    /// as a `disk_mode` of `memory_only` does (`ROCKET_MEMORY_ONLY`).
+    #[serde(default)]
+    pub memory_only: bool,
+    /// Image within `static_dir` that's served in place of a missing image, or missing images
+    /// respond with an error (`ROCKET_FALLBACK_IMAGE`).
+    #[serde(default)]
+    pub fallback_image: Option<PathBuf>,
+    /// HTML file that's rendered for a 404, where `{reason}` is replaced with why the request
+    /// failed, or a built in page is rendered (`ROCKET_NOT_FOUND_TEMPLATE`).
+    #[serde(default)]
+    pub not_found_template: Option<PathBuf>,
+    /// HTML file that's rendered for a 500, where `{reason}` is replaced with why the request
+    /// failed, or a built in page is rendered (`ROCKET_SERVER_ERROR_TEMPLATE`).
+    #[serde(default)]
+    pub server_error_template: Option<PathBuf>,
+    /// Image within `static_dir` that's served for `/favicon.ico`, or the route responds with
+    /// a 204 (`ROCKET_FAVICON`).
+    #[serde(default)]
+    pub favicon: Option<PathBuf>,
+}
+
+impl Config {
+    /// Extracts the configuration from a figment, refusing values that are only invalid in
+    /// light of another value, which can't be checked while they're deserialized.
+    ///
+    /// Arguments:
+    ///
+    /// * `figment` - &Figment
+    ///
+    /// Returns: `Result<Config, figment::Error>`
+    ///
+    /// Usage: ```Config::from_figment(rocket.figment())?;```
+    // it errors the same way as extracting from the figment does, with figment's own error
+    #[allow(clippy::result_large_err)]
+    pub fn from_figment(figment: &Figment) -> Result<Config, figment::Error> {
+        let config: Config = figment.extract()?;
+
+        // every allowed width must be one that can be requested, as snapping to a width beyond
+        // max_width would resize past it
+        let invalid_width = config
+            .allowed_widths
+            .iter()
+            .flatten()
+            .find(|&&width| width == 0 || width > config.max_width);
+        if let Some(width) = invalid_width {
+            return Err(figment::Error::from(format!(
+                "allowed_widths contains {}, which isn't between 1 and the max_width of {}",
+                width, config.max_width
+            )));
+        }
+
+        Ok(config)
+    }
+
+    /// Retrieves the `static_dir` followed by the `static_dirs`, in the order that they're
+    /// searched for an image.
+    ///
+    /// Arguments: (none)
+    ///
+    /// Returns: `Vec<PathBuf>`
+    ///
+    /// Usage: ```config.roots();```
+    pub fn roots(&self) -> Vec<PathBuf> {
+        iter::once(&self.static_dir)
+            .chain(&self.static_dirs)
+            .cloned()
+            .collect()
+    }
+
+    /// Retrieves how variants use the disk, where `memory_only` takes precedence over the
+    /// `disk_mode`.
+    ///
+    /// Arguments: (none)
+    ///
+    /// Returns: `DiskMode`
+    ///
+    /// Usage: ```config.disk_mode();```
+    pub fn disk_mode(&self) -> DiskMode {
+        match self.memory_only {
+            true => DiskMode::MemoryOnly,
+            false => self.disk_mode,
+        }
+    }
+}
+
+/// A resampling filter and an encoder quality that images are resized and encoded with
+/// together, so that a request doesn't need to select them itself.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct QualityPreset {
+    /// Resampling filter, which is one of the names that a `filter` can be.
+    #[serde(deserialize_with = "deserialize_filter")]
+    pub filter: FilterType,
+    /// Quality, from 1 to 100, of a jpeg or lossy webp.
+    #[serde(deserialize_with = "deserialize_quality")]
+    pub quality: u8,
+}
+
+/// How variants are read from and written to disk.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum DiskMode {
+    /// Reads existing variants from disk and saves new variants to disk.
+    #[default]
+    DiskRw,
+    /// Reads existing variants from disk, while new variants are only stored in the cache.
+    DiskReadOnly,
+    /// Never reads nor writes variants, which are only stored in the cache.
+    MemoryOnly,
+}
+
+/// How a requested width that isn't within `allowed_widths` is handled.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum WidthMode {
+    /// Responds with a 400.
+    #[default]
+    Reject,
+    /// Resizes the image to the nearest allowed width instead.
+    Snap,
+}
+
+/// Falls back to the `static` folder relative to the crate root.
+///
+/// Arguments: (none)
+///
+/// Returns: `PathBuf`
+///
+/// Usage: ```default_static_dir();```
+fn default_static_dir() -> PathBuf {
+    PathBuf::from(relative!("static"))
+}
+
+/// Falls back to a maximum width of 4096 pixels.
+///
+/// Arguments: (none)
+///
+/// Returns: `u32`
+///
+/// Usage: ```default_max_width();```
+fn default_max_width() -> u32 {
+    4096
+}
+
+/// Falls back to a quality of 75, which is the image crate's default jpeg quality.
+///
+/// Arguments: (none)
+///
+/// Returns: `u8`
+///
+/// Usage: ```default_quality();```
+fn default_quality() -> u8 {
+    75
+}
+
+/// Falls back to a fast, balanced and best preset, from the quickest to the sharpest.
+///
+/// Arguments: (none)
+///
+/// Returns: `BTreeMap<String, QualityPreset>`
+///
+/// Usage: ```default_quality_presets();```
+fn default_quality_presets() -> BTreeMap<String, QualityPreset> {
+    [
+        ("fast", FilterType::Triangle, 60),
+        ("balanced", FilterType::CatmullRom, 75),
+        ("best", FilterType::Lanczos3, 90),
+    ]
+    .iter()
+    .map(|&(name, filter, quality)| (name.to_string(), QualityPreset { filter, quality }))
+    .collect()
+}
+
+/// Falls back to reducing images with CatmullRom.
+///
+/// Arguments: (none)
+///
+/// Returns: `FilterType`
+///
+/// Usage: ```default_downscale_filter();```
+fn default_downscale_filter() -> FilterType {
+    DEFAULT_FILTER
+}
+
+/// Falls back to enlarging images with Lanczos3.
+///
+/// Arguments: (none)
+///
+/// Returns: `FilterType`
+///
+/// Usage: ```default_upscale_filter();```
+fn default_upscale_filter() -> FilterType {
+    DEFAULT_UPSCALE_FILTER
+}
+
+/// Falls back to resizing as many images at once as there are cpus.
+///
+/// Arguments: (none)
+///
+/// Returns: `usize`
+///
+/// Usage: ```default_max_concurrent_resizes();```
+fn default_max_concurrent_resizes() -> usize {
+    thread::available_parallelism().map_or(1, |cpus| cpus.get())
+}
+
+/// Falls back to waiting 5 seconds to lock the cache.
+///
+/// Arguments: (none)
+///
+/// Returns: `u64`
+///
+/// Usage: ```default_cache_lock_timeout_ms();```
+fn default_cache_lock_timeout_ms() -> u64 {
+    5000
+}
+
+/// Falls back to a white background.
+///
+/// Arguments: (none)
+///
+/// Returns: `[u8; 3]`
+///
+/// Usage: ```default_background();```
+fn default_background() -> [u8; 3] {
+    [255, 255, 255]
+}
+
+/// Deserializes a `rrggbb` hex color, which the environment provides as an integer when it
+/// only contains digits.
+///
+/// Arguments:
+///
+/// * `deserializer` - D
+///
+/// Returns: `Result<[u8; 3], D::Error>`
+///
+/// Usage: ```#[serde(deserialize_with = "deserialize_hex_color")]```
+fn deserialize_hex_color<'de, D: Deserializer<'de>>(deserializer: D) -> Result<[u8; 3], D::Error> {
+    struct HexColor;
+
+    impl<'de> Visitor<'de> for HexColor {
+        type Value = [u8; 3];
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("a hex color, such as ff8800")
+        }
+
+        fn visit_str<E: de::Error>(self, color: &str) -> Result<Self::Value, E> {
+            parse_hex_color(color).ok_or_else(|| E::invalid_value(Unexpected::Str(color), &self))
+        }
+
+        fn visit_u64<E: de::Error>(self, color: u64) -> Result<Self::Value, E> {
+            self.visit_str(&format!("{:06}", color))
+        }
+
+        fn visit_i64<E: de::Error>(self, color: i64) -> Result<Self::Value, E> {
+            self.visit_str(&format!("{:06}", color))
+        }
+    }
+
+    deserializer.deserialize_any(HexColor)
+}
+
+/// Deserializes the name of a resampling filter.
+///
+/// Arguments:
+///
+/// * `deserializer` - D
+///
+/// Returns: `Result<FilterType, D::Error>`
+///
+/// Usage: ```#[serde(deserialize_with = "deserialize_filter")]```
+fn deserialize_filter<'de, D: Deserializer<'de>>(deserializer: D) -> Result<FilterType, D::Error> {
+    let filter = String::deserialize(deserializer)?;
+
+    FILTERS
+        .iter()
+        .find(|(name, _)| *name == filter)
+        .map(|(_, value)| *value)
+        .ok_or_else(|| {
+            let names: Vec<&str> = FILTERS.iter().map(|(name, _)| *name).collect();
+            de::Error::invalid_value(Unexpected::Str(&filter), &names.join(", ").as_str())
+        })
+}
+
+/// Deserializes an encoder quality, which must be from 1 to 100.
+///
+/// Arguments:
+///
+/// * `deserializer` - D
+///
+/// Returns: `Result<u8, D::Error>`
+///
+/// Usage: ```#[serde(deserialize_with = "deserialize_quality")]```
+fn deserialize_quality<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u8, D::Error> {
+    let quality = u8::deserialize(deserializer)?;
+
+    match quality {
+        1..=100 => Ok(quality),
+        _ => Err(de::Error::invalid_value(
+            Unexpected::Unsigned(quality as u64),
+            &"a quality from 1 to 100",
+        )),
+    }
+}
+
+/// Falls back to caching images of up to 5 MiB.
+///
+/// Arguments: (none)
+///
+/// Returns: `usize`
+///
+/// Usage: ```default_max_cacheable_bytes();```
+fn default_max_cacheable_bytes() -> usize {
+    5 * 1024 * 1024
+}
+
+/// Falls back to letting clients cache immutable images for a year.
+///
+/// Arguments: (none)
+///
+/// Returns: `u64`
+///
+/// Usage: ```default_immutable_max_age_secs();```
+fn default_immutable_max_age_secs() -> u64 {
+    365 * 24 * 60 * 60
+}
+
+/// Falls back to a burst of 10 requests.
+///
+/// Arguments: (none)
+///
+/// Returns: `u32`
+///
+/// Usage: ```default_rate_limit_burst();```
+fn default_rate_limit_burst() -> u32 {
+    10
+}
+
+/// Falls back to a maximum upload size of 1 MiB, which is Rocket's default `file` limit.
+///
+/// Arguments: (none)
+///
+/// Returns: `u64`
+///
+/// Usage: ```default_max_upload_bytes();```
+fn default_max_upload_bytes() -> u64 {
+    1024 * 1024
+}
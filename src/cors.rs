@@ -0,0 +1,88 @@
+use crate::config::Config;
+use rocket::fairing::AdHoc;
+use rocket::http::{Header, Method, Status};
+use std::path::PathBuf;
+
+/// Methods that cross-origin requests can use to retrieve images.
+const ALLOWED_METHODS: &str = "GET, HEAD, OPTIONS";
+
+/// Seconds that a client can cache the response of a preflight request.
+const MAX_AGE: &str = "86400";
+
+#[options("/image/<_path..>")]
+fn preflight(_path: PathBuf) -> Status {
+    Status::NoContent
+}
+
+/// Determines which `Access-Control-Allow-Origin` a request's origin receives, if any.
+///
+/// Arguments:
+///
+/// * `allowed_origins` - &[String]
+/// * `origin` - &str
+///
+/// Returns: `Option<&str>`
+///
+/// Usage: ```get_allowed_origin(&config.cors_allowed_origins, origin);```
+fn get_allowed_origin<'a>(allowed_origins: &[String], origin: &'a str) -> Option<&'a str> {
+    if allowed_origins.iter().any(|allowed| allowed == "*") {
+        return Some("*");
+    }
+
+    allowed_origins
+        .iter()
+        .any(|allowed| allowed == origin)
+        .then_some(origin)
+}
+
+pub fn main() -> AdHoc {
+    AdHoc::on_ignite("cors", |rocket| async {
+        rocket
+            .mount("/", routes![preflight])
+            .attach(AdHoc::on_response("cors headers", |req, res| {
+                Box::pin(async move {
+                    if !req.uri().path().starts_with("/image/") {
+                        return;
+                    }
+
+                    let allowed_origins = match req.rocket().state::<Config>() {
+                        Some(config) => &config.cors_allowed_origins,
+                        None => return,
+                    };
+
+                    let allowed_origin = req
+                        .headers()
+                        .get_one("Origin")
+                        .and_then(|origin| get_allowed_origin(allowed_origins, origin));
+
+                    let allowed_origin = match allowed_origin {
+                        Some(allowed_origin) => allowed_origin.to_string(),
+                        None => return,
+                    };
+
+                    // a response for a specific origin can't be reused for other origins
+                    if allowed_origin != "*" {
+                        res.adjoin_header(Header::new("Vary", "Origin"));
+                    }
+                    res.set_header(Header::new("Access-Control-Allow-Origin", allowed_origin));
+
+                    if req.method() == Method::Options {
+                        res.set_header(Header::new(
+                            "Access-Control-Allow-Methods",
+                            ALLOWED_METHODS,
+                        ));
+                        res.set_header(Header::new("Access-Control-Max-Age", MAX_AGE));
+
+                        if let Some(headers) =
+                            req.headers().get_one("Access-Control-Request-Headers")
+                        {
+                            res.set_header(Header::new(
+                                "Access-Control-Allow-Headers",
+                                headers.to_string(),
+                            ));
+                        }
+                    }
+                })
+            }))
+    })
+}
@@ -0,0 +1,34 @@
+use crate::config::Config;
+use rocket::fairing::AdHoc;
+use rocket::fs::NamedFile;
+use rocket::response::status::NoContent;
+use rocket::State;
+
+/// Either the configured favicon or an empty response.
+#[derive(Responder)]
+enum Favicon {
+    Icon(NamedFile),
+    Empty(NoContent),
+}
+
+#[get("/favicon.ico")]
+async fn favicon(config: &State<Config>) -> Favicon {
+    let path = match &config.favicon {
+        Some(favicon) => config.static_dir.join(favicon),
+        None => return Favicon::Empty(NoContent),
+    };
+
+    match NamedFile::open(&path).await {
+        Ok(file) => Favicon::Icon(file),
+        Err(_) => {
+            warn_!("The configured favicon {} doesn't exist.", path.display());
+            Favicon::Empty(NoContent)
+        }
+    }
+}
+
+pub fn main() -> AdHoc {
+    AdHoc::on_ignite("favicon", |rocket| async {
+        rocket.mount("/", routes![favicon])
+    })
+}
@@ -26,20 +26,78 @@
 //!
 use std::collections::HashMap;
 use std::hash::Hash;
+use std::iter::FromIterator;
+use std::time::{Duration, Instant};
 
 struct CacheEntry<K, V> {
   key: K,
   value: Option<V>,
   next: Option<usize>,
   prev: Option<usize>,
+  last_accessed: Instant,
+  inserted: Instant,
 }
 
+///
+/// A snapshot of how much of the cache is in use.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+  pub len: usize,
+  pub capacity: usize,
+  pub remaining: usize,
+  pub hits: u64,
+  pub misses: u64,
+}
+
+///
+/// A callback that's invoked with every item removed to make room in the cache.
+///
+pub type EvictCallback<K, V> = Box<dyn FnMut(&K, &V) + Send>;
+
+///
+/// A function that measures how many bytes an item takes up in the cache.
+///
+pub type Weigher<V> = Box<dyn Fn(&V) -> usize + Send>;
+
 pub struct LRUCache<K, V> {
   table: HashMap<K, usize>,
   entries: Vec<CacheEntry<K, V>>,
   first: Option<usize>,
   last: Option<usize>,
   capacity: usize,
+  free: Vec<usize>,
+  on_evict: Option<EvictCallback<K, V>>,
+  ttl: Option<Duration>,
+  max_bytes: Option<usize>,
+  bytes: usize,
+  weigher: Option<Weigher<V>>,
+  hits: u64,
+  misses: u64,
+}
+
+///
+/// An item of the cache that's either present or absent, which is retrieved with `entry`.
+///
+pub enum Entry<'a, K, V> {
+  Occupied(OccupiedEntry<'a, K, V>),
+  Vacant(VacantEntry<'a, K, V>),
+}
+
+///
+/// An item that's present in the cache, and was promoted to the top of it.
+///
+pub struct OccupiedEntry<'a, K, V> {
+  cache: &'a mut LRUCache<K, V>,
+  idx: usize,
+}
+
+///
+/// An item that's absent from the cache, which can be inserted at the top of it.
+///
+pub struct VacantEntry<'a, K, V> {
+  cache: &'a mut LRUCache<K, V>,
+  key: K,
 }
 
 impl<K: Clone + Hash + Eq, V> LRUCache<K, V> {
@@ -53,7 +111,173 @@ impl<K: Clone + Hash + Eq, V> LRUCache<K, V> {
       first: None,
       last: None,
       capacity,
+      free: Vec::new(),
+      on_evict: None,
+      ttl: None,
+      max_bytes: None,
+      bytes: 0,
+      weigher: None,
+      hits: 0,
+      misses: 0,
+    }
+  }
+
+  ///
+  /// Creates a new cache that can hold the specified number of elements.
+  ///
+  /// This is an alias of `new`.
+  ///
+  /// # Example
+  /// ```
+  /// use lrucache::LRUCache;
+  ///
+  /// let mut cache = LRUCache::with_capacity(1);
+  /// cache.insert("foo", 1);
+  /// assert!(cache.is_full());
+  /// ```
+  pub fn with_capacity(capacity: usize) -> Self {
+    Self::new(capacity)
+  }
+
+  ///
+  /// Creates a new cache that can hold the specified number of elements, as long as
+  /// their combined size (measured by `weigher`) doesn't exceed `max_bytes`.
+  ///
+  /// The oldest items are removed until both limits are satisfied, although the most
+  /// recently inserted item is always kept, even when it alone exceeds `max_bytes`. Values
+  /// aren't weighed again after they're changed through `get_mut`.
+  ///
+  /// # Example
+  /// ```
+  /// use lrucache::LRUCache;
+  ///
+  /// let mut cache = LRUCache::with_limits(3, 10, |value: &Vec<u8>| value.len());
+  ///
+  /// // "foo" is removed because the cache is out of entries
+  /// cache.insert("foo", vec![0; 1]);
+  /// cache.insert("bar", vec![0; 1]);
+  /// cache.insert("baz", vec![0; 1]);
+  /// cache.insert("qux", vec![0; 1]);
+  /// assert!(!cache.contains_key(&"foo"));
+  /// assert_eq!(cache.bytes(), 3);
+  ///
+  /// // "bar" and "baz" are removed because the cache is out of bytes
+  /// cache.insert("quux", vec![0; 9]);
+  /// assert_eq!(cache.len(), 2);
+  /// assert!(cache.contains_key(&"qux"));
+  /// assert_eq!(cache.bytes(), 10);
+  ///
+  /// // "qux" is removed because the cache is out of both entries and bytes
+  /// cache.resize(2);
+  /// cache.insert("corge", vec![0; 1]);
+  /// assert_eq!(cache.len(), 2);
+  /// assert!(!cache.contains_key(&"qux"));
+  /// assert_eq!(cache.bytes(), 10);
+  ///
+  /// // growing an item removes older items to make room for it
+  /// cache.insert("corge", vec![0; 4]);
+  /// assert_eq!(cache.len(), 1);
+  /// assert_eq!(cache.bytes(), 4);
+  /// ```
+  pub fn with_limits(
+    max_entries: usize,
+    max_bytes: usize,
+    weigher: impl Fn(&V) -> usize + Send + 'static,
+  ) -> Self {
+    let mut cache = Self::new(max_entries);
+    cache.max_bytes = Some(max_bytes);
+    cache.weigher = Some(Box::new(weigher));
+    cache
+  }
+
+  ///
+  /// Registers a callback that's invoked with the key and value of every item that's
+  /// removed because the cache ran out of room. Replaces any previous callback.
+  ///
+  /// Items that are removed explicitly (`remove`, `retain`) or once they've expired
+  /// (`purge_expired`, or when they're retrieved past their time to live) don't invoke it.
+  ///
+  /// # Example
+  /// ```
+  /// use lrucache::LRUCache;
+  /// use std::sync::{Arc, Mutex};
+  ///
+  /// let evicted = Arc::new(Mutex::new(Vec::new()));
+  /// let mut cache = LRUCache::with_capacity(2);
+  /// {
+  ///   let evicted = Arc::clone(&evicted);
+  ///   cache.set_on_evict(move |key: &&str, value: &i32| {
+  ///     evicted.lock().unwrap().push((*key, *value));
+  ///   });
+  /// }
+  ///
+  /// cache.insert("foo", 1);
+  /// cache.insert("bar", 2);
+  /// cache.remove(&"bar");
+  /// cache.insert("bar", 2);
+  /// cache.insert("baz", 3);
+  /// cache.resize(1);
+  /// assert_eq!(*evicted.lock().unwrap(), vec![("foo", 1), ("bar", 2)]);
+  /// ```
+  pub fn set_on_evict(&mut self, callback: impl FnMut(&K, &V) + Send + 'static) {
+    self.on_evict = Some(Box::new(callback));
+  }
+
+  ///
+  /// Sets how long an item lives after it's inserted, or removes the limit when `None`.
+  ///
+  /// Expired items are removed when they're retrieved (`get`, `get_mut`), which counts as a
+  /// miss, or all at once by `purge_expired`.
+  ///
+  /// # Example
+  /// ```
+  /// use lrucache::LRUCache;
+  /// use std::time::Duration;
+  ///
+  /// let mut cache = LRUCache::with_capacity(2);
+  /// cache.set_ttl(Some(Duration::from_millis(10)));
+  /// cache.insert("foo", 1);
+  /// assert_eq!(cache.get(&"foo"), Some(&1));
+  ///
+  /// std::thread::sleep(Duration::from_millis(20));
+  /// assert_eq!(cache.get(&"foo"), None);
+  /// assert!(cache.is_empty());
+  /// ```
+  pub fn set_ttl(&mut self, ttl: Option<Duration>) {
+    self.ttl = ttl;
+  }
+
+  ///
+  /// Removes every item that has outlived the cache's time to live and returns how many
+  /// were removed. Nothing expires when no time to live is set.
+  ///
+  /// # Example
+  /// ```
+  /// use lrucache::LRUCache;
+  /// use std::time::Duration;
+  ///
+  /// let mut cache = LRUCache::with_capacity(3);
+  /// cache.set_ttl(Some(Duration::from_millis(10)));
+  /// cache.insert("foo", 1);
+  /// cache.insert("bar", 2);
+  ///
+  /// std::thread::sleep(Duration::from_millis(20));
+  /// cache.insert("baz", 3);
+  /// assert_eq!(cache.purge_expired(), 2);
+  /// assert_eq!(cache.len(), 1);
+  /// assert!(cache.contains_key(&"baz"));
+  /// ```
+  pub fn purge_expired(&mut self) -> usize {
+    let mut purged = 0;
+    let mut cursor = self.first;
+    while let Some(idx) = cursor {
+      cursor = self.entries[idx].next;
+      if self.is_expired(idx) {
+        self.remove_entry(idx);
+        purged += 1;
+      }
     }
+    purged
   }
 
   ///
@@ -73,36 +297,83 @@ impl<K: Clone + Hash + Eq, V> LRUCache<K, V> {
   /// assert!(cache.contains_key(&"baz"));
   /// assert!(cache.contains_key(&"bar"));
   /// assert!(!cache.contains_key(&"foo"));
+  ///
+  /// // slots of removed items are reused, so the cache never outgrows its capacity
+  /// for i in 0..1000 {
+  ///     cache.insert("qux", i);
+  ///     cache.remove(&"qux");
+  ///     cache.insert("bar", i);
+  /// }
+  /// assert_eq!(cache.len(), 1);
   /// ```
   pub fn insert(&mut self, key: K, value: V) -> Option<V> {
     if self.table.contains_key(&key) {
       self.access(&key);
-      let entry = &mut self.entries[self.first.unwrap()];
-      let old = entry.value.take();
-      entry.value = Some(value);
+      let idx = self.first.unwrap();
+      let old = self.replace_value(idx, value);
+      self.trim_to_max_bytes();
       old
     } else {
-      self.ensure_room();
-      // Update old head
-      let idx = self.entries.len();
-      self.first.map(|e| {
-        let prev = Some(idx);
-        self.entries[e].prev = prev;
-      });
-      // This is the new head
-      self.entries.push(CacheEntry {
+      let incoming = self.weigh(&value);
+      self.ensure_room(incoming);
+      self.bytes += incoming;
+      let entry = CacheEntry {
         key: key.clone(),
         value: Some(value),
-        next: self.first,
+        next: None,
         prev: None,
-      });
-      self.first = Some(idx);
-      self.last = self.last.or(self.first);
+        last_accessed: Instant::now(),
+        inserted: Instant::now(),
+      };
+      // Reuse a slot reclaimed by a removal before growing the entries
+      let idx = match self.free.pop() {
+        Some(idx) => {
+          self.entries[idx] = entry;
+          idx
+        }
+        None => {
+          self.entries.push(entry);
+          self.entries.len() - 1
+        }
+      };
+      // This is the new head
+      self.push_front(idx);
       self.table.insert(key, idx);
       None
     }
   }
 
+  ///
+  /// Inserts a key-value pair into the cache and returns the previous value, if any.
+  ///
+  /// Unlike `insert`, overwriting the value of an existing key doesn't promote it, so it
+  /// keeps its position in the cache. New keys are inserted at the top of the cache.
+  ///
+  /// # Example
+  /// ```
+  /// use lrucache::LRUCache;
+  ///
+  /// let mut cache = LRUCache::with_capacity(2);
+  /// cache.insert("foo", 1);
+  /// cache.insert("bar", 2);
+  ///
+  /// // "foo" will not be promoted, and will then be removed first.
+  /// assert_eq!(cache.insert_no_promote("foo", 3), Some(1));
+  /// cache.insert("baz", 4);
+  /// assert!(!cache.contains_key(&"foo"));
+  /// assert_eq!(cache.peek(&"bar"), Some(&2));
+  /// ```
+  pub fn insert_no_promote(&mut self, key: K, value: V) -> Option<V> {
+    match self.table.get(&key) {
+      Some(&idx) => {
+        let old = self.replace_value(idx, value);
+        self.trim_to_max_bytes();
+        old
+      }
+      None => self.insert(key, value),
+    }
+  }
+
   ///
   /// Removes the item associated with `key` from the cache and returns its value, if any.
   ///
@@ -116,10 +387,54 @@ impl<K: Clone + Hash + Eq, V> LRUCache<K, V> {
   /// assert_eq!(cache.remove(&"foo"), Some(1));
   /// ```
   pub fn remove(&mut self, key: &K) -> Option<V> {
-    self.table.remove(&key).map(|idx| {
-      self.remove_from_list(idx);
-      self.entries[idx].value.take().unwrap()
-    })
+    let idx = *self.table.get(key)?;
+    self.remove_entry(idx)
+  }
+
+  ///
+  /// Retains only the items for which the predicate `f` returns true.
+  ///
+  /// Retained items keep their relative position in the cache.
+  ///
+  /// # Example
+  /// ```
+  /// use lrucache::LRUCache;
+  ///
+  /// let mut cache = LRUCache::with_capacity(3);
+  /// cache.insert("foo_20", vec![0; 4]);
+  /// cache.insert("bar_20", vec![0; 8]);
+  /// cache.insert("foo_50", vec![0; 16]);
+  ///
+  /// // drop every variant of "foo"
+  /// cache.retain(|key, _| !key.starts_with("foo"));
+  /// assert_eq!(cache.len(), 1);
+  /// assert!(cache.contains_key(&"bar_20"));
+  ///
+  /// // drop every item larger than 8 bytes
+  /// cache.insert("baz_20", vec![0; 2]);
+  /// cache.insert("baz_50", vec![0; 32]);
+  /// cache.retain(|_, value| value.len() <= 8);
+  /// assert_eq!(cache.len(), 2);
+  /// assert!(!cache.contains_key(&"baz_50"));
+  ///
+  /// // "bar_20" is still the oldest item and is removed first.
+  /// cache.insert("qux_20", vec![0; 1]);
+  /// cache.insert("qux_50", vec![0; 1]);
+  /// assert!(!cache.contains_key(&"bar_20"));
+  /// assert!(cache.contains_key(&"baz_20"));
+  /// ```
+  pub fn retain(&mut self, mut f: impl FnMut(&K, &V) -> bool) {
+    let mut cursor = self.first;
+    while let Some(idx) = cursor {
+      cursor = self.entries[idx].next;
+      let keep = {
+        let entry = &self.entries[idx];
+        f(&entry.key, entry.value.as_ref().unwrap())
+      };
+      if !keep {
+        self.remove_entry(idx);
+      }
+    }
   }
 
   ///
@@ -160,9 +475,7 @@ impl<K: Clone + Hash + Eq, V> LRUCache<K, V> {
   /// assert_eq!(cache.get(&"foo"), Some(&1));
   /// ```
   pub fn get(&mut self, key: &K) -> Option<&V> {
-    if self.contains_key(key) {
-      self.access(key);
-    }
+    self.record_access(key);
     self.peek(key)
   }
 
@@ -182,9 +495,7 @@ impl<K: Clone + Hash + Eq, V> LRUCache<K, V> {
   /// assert_eq!(cache.get(&"foo"), Some(&2));
   /// ```
   pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
-    if self.contains_key(key) {
-      self.access(key);
-    }
+    self.record_access(key);
     let entries = &mut self.entries;
     self
       .table
@@ -192,6 +503,183 @@ impl<K: Clone + Hash + Eq, V> LRUCache<K, V> {
       .and_then(move |i| entries[*i].value.as_mut())
   }
 
+  ///
+  /// Retrieves the entry of `key` for in-place insertion or modification, with a single
+  /// lookup. An occupied entry is promoted to the top of the cache and counted as a hit, just
+  /// as `get_mut` would, while a vacant entry is counted as a miss and is inserted at the top
+  /// of the cache.
+  ///
+  /// # Example
+  /// ```
+  /// use lrucache::LRUCache;
+  ///
+  /// let mut cache = LRUCache::with_capacity(2);
+  ///
+  /// // a vacant entry is inserted as the most recent item
+  /// *cache.entry("foo").or_insert(0) += 1;
+  /// cache.entry("bar").or_insert(10);
+  /// assert_eq!(cache.peek(&"foo"), Some(&1));
+  ///
+  /// // an occupied entry is modified in place and promoted, so "bar" is removed first
+  /// *cache.entry("foo").or_insert(0) += 1;
+  /// cache.insert("baz", 20);
+  /// assert_eq!(cache.peek(&"foo"), Some(&2));
+  /// assert!(!cache.contains_key(&"bar"));
+  ///
+  /// // inserting into a full cache removes the least recent item
+  /// cache.entry("qux").and_modify(|count| *count += 1).or_insert(30);
+  /// assert!(!cache.contains_key(&"foo"));
+  /// assert_eq!(cache.peek(&"qux"), Some(&30));
+  /// assert_eq!(cache.stats().hits, 1);
+  /// assert_eq!(cache.stats().misses, 3);
+  /// ```
+  pub fn entry(&mut self, key: K) -> Entry<'_, K, V> {
+    self.record_access(&key);
+    match self.table.get(&key) {
+      Some(&idx) => Entry::Occupied(OccupiedEntry { cache: self, idx }),
+      None => Entry::Vacant(VacantEntry { cache: self, key }),
+    }
+  }
+
+  ///
+  /// Returns the key of every item in the cache along with when it was last promoted,
+  /// ordered from the least to the most recently accessed.
+  ///
+  /// # Example
+  /// ```
+  /// use lrucache::LRUCache;
+  ///
+  /// let mut cache = LRUCache::with_capacity(3);
+  /// cache.insert("foo", 1);
+  /// cache.insert("bar", 2);
+  /// cache.insert("baz", 3);
+  /// cache.get(&"foo");
+  ///
+  /// let keys: Vec<_> = cache.stalest().into_iter().map(|(key, _)| *key).collect();
+  /// assert_eq!(keys, vec!["bar", "baz", "foo"]);
+  /// ```
+  pub fn stalest(&self) -> Vec<(&K, Instant)> {
+    let mut stalest = Vec::with_capacity(self.len());
+    let mut cursor = self.last;
+    while let Some(idx) = cursor {
+      let entry = &self.entries[idx];
+      stalest.push((&entry.key, entry.last_accessed));
+      cursor = entry.prev;
+    }
+    stalest
+  }
+
+  ///
+  /// Returns an iterator over every item in the cache, ordered from the most to the least
+  /// recently accessed. This does not promote any items.
+  ///
+  /// # Example
+  /// ```
+  /// use lrucache::LRUCache;
+  ///
+  /// let mut cache = LRUCache::with_capacity(3);
+  /// cache.insert("foo", vec![0; 4]);
+  /// cache.insert("bar", vec![0; 8]);
+  /// cache.get(&"foo");
+  ///
+  /// let keys: Vec<_> = cache.iter().map(|(key, _)| *key).collect();
+  /// assert_eq!(keys, vec!["foo", "bar"]);
+  ///
+  /// let bytes: usize = cache.iter().map(|(_, value)| value.len()).sum();
+  /// assert_eq!(bytes, 12);
+  /// ```
+  pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+    let entries = &self.entries;
+    std::iter::successors(self.first, move |&idx| entries[idx].next).map(move |idx| {
+      let entry = &entries[idx];
+      (&entry.key, entry.value.as_ref().unwrap())
+    })
+  }
+
+  ///
+  /// Returns the least recently accessed item, which is the next to be evicted. This does not
+  /// promote it.
+  ///
+  /// # Example
+  /// ```
+  /// use lrucache::LRUCache;
+  ///
+  /// let mut cache = LRUCache::with_capacity(3);
+  /// assert_eq!(cache.peek_lru(), None);
+  ///
+  /// cache.insert("foo", 1);
+  /// cache.insert("bar", 2);
+  /// cache.insert("baz", 3);
+  /// assert_eq!(cache.peek_lru(), Some((&"foo", &1)));
+  ///
+  /// cache.get(&"foo");
+  /// assert_eq!(cache.peek_lru(), Some((&"bar", &2)));
+  /// // peeking doesn't promote "bar"
+  /// assert_eq!(cache.peek_lru(), Some((&"bar", &2)));
+  /// ```
+  pub fn peek_lru(&self) -> Option<(&K, &V)> {
+    self.last.map(|idx| {
+      let entry = &self.entries[idx];
+      (&entry.key, entry.value.as_ref().unwrap())
+    })
+  }
+
+  ///
+  /// Returns the most recently accessed item. This does not promote it.
+  ///
+  /// # Example
+  /// ```
+  /// use lrucache::LRUCache;
+  ///
+  /// let mut cache = LRUCache::with_capacity(3);
+  /// assert_eq!(cache.peek_mru(), None);
+  ///
+  /// cache.insert("foo", 1);
+  /// cache.insert("bar", 2);
+  /// assert_eq!(cache.peek_mru(), Some((&"bar", &2)));
+  ///
+  /// cache.get(&"foo");
+  /// assert_eq!(cache.peek_mru(), Some((&"foo", &1)));
+  ///
+  /// cache.remove(&"foo");
+  /// assert_eq!(cache.peek_mru(), Some((&"bar", &2)));
+  /// assert_eq!(cache.peek_lru(), Some((&"bar", &2)));
+  /// ```
+  pub fn peek_mru(&self) -> Option<(&K, &V)> {
+    self.first.map(|idx| {
+      let entry = &self.entries[idx];
+      (&entry.key, entry.value.as_ref().unwrap())
+    })
+  }
+
+  ///
+  /// Returns an iterator over the items whose keys start with a prefix, along with when each
+  /// was last accessed, ordered from the most to the least recently accessed. This does not
+  /// promote any items.
+  ///
+  /// # Example
+  /// ```
+  /// use lrucache::LRUCache;
+  ///
+  /// let mut cache = LRUCache::with_capacity(3);
+  /// cache.insert("photo_10w", 1);
+  /// cache.insert("icon_10w", 2);
+  /// cache.insert("photo_20w", 3);
+  ///
+  /// let keys: Vec<_> = cache.iter_prefix("photo_").map(|(key, ..)| *key).collect();
+  /// assert_eq!(keys, vec!["photo_20w", "photo_10w"]);
+  /// ```
+  pub fn iter_prefix<'a>(&'a self, prefix: &'a str) -> impl Iterator<Item = (&'a K, &'a V, Instant)>
+  where
+    K: AsRef<str>,
+  {
+    let entries = &self.entries;
+    std::iter::successors(self.first, move |&idx| entries[idx].next)
+      .map(move |idx| &entries[idx])
+      .filter(move |entry| entry.key.as_ref().starts_with(prefix))
+      .map(|entry| (&entry.key, entry.value.as_ref().unwrap(), entry.last_accessed))
+  }
+
   ///
   /// Returns the number of elements currently in the cache.
   ///
@@ -199,6 +687,160 @@ impl<K: Clone + Hash + Eq, V> LRUCache<K, V> {
     self.table.len()
   }
 
+  ///
+  /// Returns the maximum number of elements the cache can hold.
+  ///
+  pub fn capacity(&self) -> usize {
+    self.capacity
+  }
+
+  ///
+  /// Returns the combined size of every item in the cache, which is always 0 unless the
+  /// cache was created `with_limits`.
+  ///
+  pub fn bytes(&self) -> usize {
+    self.bytes
+  }
+
+  ///
+  /// Changes the maximum number of elements the cache can hold.
+  ///
+  /// If the cache holds more elements than the new capacity, the oldest items are
+  /// removed until it fits.
+  ///
+  /// # Example
+  /// ```
+  /// use lrucache::LRUCache;
+  ///
+  /// let mut cache = LRUCache::with_capacity(4);
+  /// cache.insert("foo", 1);
+  /// cache.insert("bar", 2);
+  /// cache.insert("baz", 3);
+  /// cache.insert("qux", 4);
+  /// cache.get(&"foo");
+  ///
+  /// // "bar" and "baz" are the oldest items, so they're removed.
+  /// cache.resize(2);
+  /// assert_eq!(cache.len(), 2);
+  /// assert!(cache.contains_key(&"foo"));
+  /// assert!(cache.contains_key(&"qux"));
+  /// assert!(!cache.contains_key(&"bar"));
+  /// assert!(!cache.contains_key(&"baz"));
+  ///
+  /// // growing the cache keeps every item
+  /// cache.resize(3);
+  /// cache.insert("bar", 2);
+  /// assert_eq!(cache.len(), 3);
+  /// assert!(cache.is_full());
+  /// ```
+  pub fn resize(&mut self, new_cap: usize) {
+    self.capacity = new_cap;
+    while self.len() > new_cap {
+      self.remove_last();
+    }
+  }
+
+  ///
+  /// Returns the number of items the cache has allocated room for, which stays at the
+  /// largest number of items it has held, even once it's resized smaller, until it's
+  /// `shrink_to_fit`.
+  ///
+  pub fn allocated(&self) -> usize {
+    self.entries.capacity()
+  }
+
+  ///
+  /// Releases the room allocated beyond the items currently in the cache, by compacting
+  /// them from the most to the least recently used. The order of the items is unchanged.
+  ///
+  /// # Example
+  /// ```
+  /// use lrucache::LRUCache;
+  ///
+  /// let mut cache = LRUCache::with_capacity(100);
+  /// for i in 0..100 {
+  ///   cache.insert(i, i);
+  /// }
+  /// cache.resize(2);
+  /// assert!(cache.allocated() >= 100);
+  ///
+  /// cache.shrink_to_fit();
+  /// assert!(cache.allocated() < 100);
+  /// assert_eq!(cache.iter().collect::<Vec<_>>(), vec![(&99, &99), (&98, &98)]);
+  ///
+  /// // the compacted cache still removes its oldest items to make room
+  /// cache.insert(100, 100);
+  /// assert_eq!(cache.get(&100), Some(&100));
+  /// assert!(!cache.contains_key(&98));
+  /// ```
+  pub fn shrink_to_fit(&mut self) {
+    let order: Vec<usize> =
+      std::iter::successors(self.first, |&idx| self.entries[idx].next).collect();
+    let mut old: Vec<Option<CacheEntry<K, V>>> =
+      std::mem::take(&mut self.entries).into_iter().map(Some).collect();
+
+    let mut entries = Vec::with_capacity(order.len());
+    for (new_idx, &idx) in order.iter().enumerate() {
+      let mut entry = old[idx].take().unwrap();
+      entry.prev = new_idx.checked_sub(1);
+      entry.next = Some(new_idx + 1).filter(|&next| next < order.len());
+      if let Some(table_idx) = self.table.get_mut(&entry.key) {
+        *table_idx = new_idx;
+      }
+      entries.push(entry);
+    }
+
+    self.entries = entries;
+    self.first = Some(0).filter(|_| !order.is_empty());
+    self.last = order.len().checked_sub(1);
+    self.free = Vec::new();
+    self.table.shrink_to_fit();
+  }
+
+  ///
+  /// Returns the number of elements that can be inserted before the oldest item is removed.
+  ///
+  /// # Example
+  /// ```
+  /// use lrucache::LRUCache;
+  ///
+  /// let mut cache = LRUCache::with_capacity(3);
+  /// cache.insert("foo", 1);
+  /// assert_eq!(cache.capacity(), 3);
+  /// assert_eq!(cache.remaining(), 2);
+  /// ```
+  pub fn remaining(&self) -> usize {
+    self.capacity.saturating_sub(self.len())
+  }
+
+  ///
+  /// Returns a snapshot of the cache's current usage, along with how many retrievals
+  /// (`get`, `get_mut`) found or missed their item.
+  ///
+  /// # Example
+  /// ```
+  /// use lrucache::{CacheStats, LRUCache};
+  ///
+  /// let mut cache = LRUCache::with_capacity(2);
+  /// cache.insert("foo", 1);
+  /// cache.get(&"foo");
+  /// cache.get(&"bar");
+  /// cache.peek(&"bar");
+  /// assert_eq!(
+  ///   cache.stats(),
+  ///   CacheStats { len: 1, capacity: 2, remaining: 1, hits: 1, misses: 1 }
+  /// );
+  /// ```
+  pub fn stats(&self) -> CacheStats {
+    CacheStats {
+      len: self.len(),
+      capacity: self.capacity(),
+      remaining: self.remaining(),
+      hits: self.hits,
+      misses: self.misses,
+    }
+  }
+
   ///
   /// Returns true if the cache contains no elements.
   ///
@@ -224,13 +866,42 @@ impl<K: Clone + Hash + Eq, V> LRUCache<K, V> {
     self.table.len() == self.capacity
   }
 
+  ///
+  /// Promotes the specified key to the top of the cache if it's present, counting the
+  /// retrieval as either a hit or a miss. An expired item is removed and counted as a miss.
+  ///
+  fn record_access(&mut self, key: &K) {
+    if let Some(&idx) = self.table.get(key) {
+      if self.is_expired(idx) {
+        self.remove_entry(idx);
+      }
+    }
+
+    if self.contains_key(key) {
+      self.hits += 1;
+      self.access(key);
+    } else {
+      self.misses += 1;
+    }
+  }
+
+  ///
+  /// Returns true if the item has outlived the cache's time to live.
+  ///
+  fn is_expired(&self, idx: usize) -> bool {
+    self
+      .ttl
+      .is_some_and(|ttl| self.entries[idx].inserted.elapsed() >= ttl)
+  }
+
   ///
   /// Promotes the specified key to the top of the cache.
   ///
   fn access(&mut self, key: &K) {
     let i = *self.table.get(key).unwrap();
     self.remove_from_list(i);
-    self.first = Some(i);
+    self.push_front(i);
+    self.entries[i].last_accessed = Instant::now();
   }
 
   ///
@@ -256,46 +927,423 @@ impl<K: Clone + Hash + Eq, V> LRUCache<K, V> {
   fn remove_from_list(&mut self, i: usize) {
     let (prev, next) = {
       let entry = self.entries.get_mut(i).unwrap();
-      (entry.prev, entry.next)
+      (entry.prev.take(), entry.next.take())
     };
-    match (prev, next) {
-      // Item was in the middle of the list
-      (Some(j), Some(k)) => {
-        {
-          let first = &mut self.entries[j];
-          first.next = next;
-        }
-        let second = &mut self.entries[k];
-        second.prev = prev;
-      }
-      // Item was at the end of the list
-      (Some(j), None) => {
-        let first = &mut self.entries[j];
-        first.next = None;
-        self.last = prev;
-      }
-      // Item was at front
-      _ => (),
+    match prev {
+      // Item was in the middle or at the end of the list
+      Some(j) => self.entries[j].next = next,
+      // Item was at the front
+      None => self.first = next,
+    }
+    match next {
+      // Item was in the middle or at the front of the list
+      Some(k) => self.entries[k].prev = prev,
+      // Item was at the end
+      None => self.last = prev,
+    }
+  }
+
+  ///
+  /// Links an unlinked item in as the head of the list.
+  ///
+  fn push_front(&mut self, i: usize) {
+    // Update old head
+    if let Some(head) = self.first {
+      self.entries[head].prev = Some(i);
+    }
+    {
+      let entry = &mut self.entries[i];
+      entry.prev = None;
+      entry.next = self.first;
     }
+    self.first = Some(i);
+    self.last = self.last.or(self.first);
   }
 
-  fn ensure_room(&mut self) {
-    if self.capacity == self.len() {
+  ///
+  /// Removes the oldest items until there's room for one more item of `incoming` bytes,
+  /// without limits on the number of removals.
+  ///
+  fn ensure_room(&mut self, incoming: usize) {
+    while !self.is_empty() && (self.len() >= self.capacity || self.exceeds_max_bytes(incoming)) {
       self.remove_last();
     }
   }
 
   ///
-  /// Removes the oldest item in the cache.
+  /// Removes the oldest items, other than the most recent one, until the cache fits
+  /// within its byte limit.
+  ///
+  fn trim_to_max_bytes(&mut self) {
+    while self.len() > 1 && self.exceeds_max_bytes(0) {
+      self.remove_last();
+    }
+  }
+
+  ///
+  /// Returns true if adding `incoming` bytes would exceed the cache's byte limit.
+  ///
+  fn exceeds_max_bytes(&self, incoming: usize) -> bool {
+    self
+      .max_bytes
+      .is_some_and(|max_bytes| self.bytes + incoming > max_bytes)
+  }
+
+  ///
+  /// Measures an item with the cache's weigher, or as 0 bytes without one.
+  ///
+  fn weigh(&self, value: &V) -> usize {
+    self.weigher.as_ref().map_or(0, |weigher| weigher(value))
+  }
+
+  ///
+  /// Replaces the value of an item in place, resetting when it was inserted.
+  ///
+  fn replace_value(&mut self, idx: usize, value: V) -> Option<V> {
+    self.bytes += self.weigh(&value);
+    let old = self.entries[idx].value.replace(value);
+    if let Some(old) = &old {
+      self.bytes = self.bytes.saturating_sub(self.weigh(old));
+    }
+    self.entries[idx].inserted = Instant::now();
+    old
+  }
+
+  ///
+  /// Removes the oldest item in the cache, passing it to the eviction callback.
   ///
   fn remove_last(&mut self) {
     if let Some(idx) = self.last {
-      self.remove_from_list(idx);
-      let key = &self.entries[idx].key;
-      self.table.remove(key);
+      let value = self.remove_entry(idx);
+      if let (Some(on_evict), Some(value)) = (self.on_evict.as_mut(), value.as_ref()) {
+        on_evict(&self.entries[idx].key, value);
+      }
+    }
+  }
+
+  ///
+  /// Removes an item from the linked list and the table, reclaiming its slot for
+  /// reuse by a later insertion.
+  ///
+  fn remove_entry(&mut self, idx: usize) -> Option<V> {
+    self.remove_from_list(idx);
+    self.table.remove(&self.entries[idx].key);
+    self.free.push(idx);
+    let value = self.entries[idx].value.take();
+    if let Some(value) = &value {
+      self.bytes = self.bytes.saturating_sub(self.weigh(value));
+    }
+    value
+  }
+}
+
+impl<K: Clone + Hash + Eq, V> Extend<(K, V)> for LRUCache<K, V> {
+  ///
+  /// Inserts every item in order, as `insert` would, so that once the cache is full, later
+  /// items evict earlier ones.
+  ///
+  /// # Example
+  /// ```
+  /// use lrucache::LRUCache;
+  ///
+  /// let mut cache = LRUCache::with_capacity(3);
+  /// cache.insert("foo", 1);
+  /// cache.extend(vec![("bar", 2), ("baz", 3), ("qux", 4), ("bar", 5)]);
+  ///
+  /// assert_eq!(cache.len(), 3);
+  /// assert!(!cache.contains_key(&"foo"));
+  ///
+  /// let items: Vec<_> = cache.iter().map(|(key, value)| (*key, *value)).collect();
+  /// assert_eq!(items, vec![("bar", 5), ("qux", 4), ("baz", 3)]);
+  /// ```
+  fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, iter: I) {
+    for (key, value) in iter {
+      self.insert(key, value);
+    }
+  }
+}
+
+impl<K: Clone + Hash + Eq, V> FromIterator<(K, V)> for LRUCache<K, V> {
+  ///
+  /// Creates a cache that can hold every item, inserted in order, so that the last item is the
+  /// most recently accessed. A cache with a smaller capacity is built with `with_capacity`
+  /// and `extend` instead.
+  ///
+  /// # Example
+  /// ```
+  /// use lrucache::LRUCache;
+  ///
+  /// let mut cache: LRUCache<_, _> = vec![("foo", 1), ("bar", 2), ("foo", 3)]
+  ///   .into_iter()
+  ///   .collect();
+  ///
+  /// assert_eq!(cache.capacity(), 3);
+  /// assert_eq!(cache.len(), 2);
+  ///
+  /// let items: Vec<_> = cache.iter().map(|(key, value)| (*key, *value)).collect();
+  /// assert_eq!(items, vec![("foo", 3), ("bar", 2)]);
+  ///
+  /// cache.insert("baz", 4);
+  /// cache.insert("qux", 5);
+  /// assert!(!cache.contains_key(&"bar"));
+  /// ```
+  fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+    let items: Vec<(K, V)> = iter.into_iter().collect();
+    let mut cache = LRUCache::new(items.len());
+    cache.extend(items);
+    cache
+  }
+}
+
+impl<'a, K: Clone + Hash + Eq, V> Entry<'a, K, V> {
+  ///
+  /// Returns the key of the entry.
+  ///
+  pub fn key(&self) -> &K {
+    match self {
+      Entry::Occupied(entry) => entry.key(),
+      Entry::Vacant(entry) => entry.key(),
     }
-    if self.last.is_none() {
-      self.first = None;
+  }
+
+  ///
+  /// Inserts `default` if the entry is vacant, and returns a mutable reference to its value.
+  ///
+  pub fn or_insert(self, default: V) -> &'a mut V {
+    self.or_insert_with(|| default)
+  }
+
+  ///
+  /// Inserts the value returned by `default` if the entry is vacant, and returns a mutable
+  /// reference to its value.
+  ///
+  /// # Example
+  /// ```
+  /// use lrucache::LRUCache;
+  ///
+  /// let mut cache: LRUCache<&str, Vec<u32>> = LRUCache::with_capacity(2);
+  /// cache.entry("foo").or_insert_with(Vec::new).push(1);
+  /// cache.entry("foo").or_insert_with(Vec::new).push(2);
+  /// assert_eq!(cache.peek(&"foo"), Some(&vec![1, 2]));
+  /// ```
+  pub fn or_insert_with(self, default: impl FnOnce() -> V) -> &'a mut V {
+    match self {
+      Entry::Occupied(entry) => entry.into_mut(),
+      Entry::Vacant(entry) => entry.insert(default()),
     }
   }
+
+  ///
+  /// Modifies the value of an occupied entry in place, leaving a vacant entry as is.
+  ///
+  /// # Example
+  /// ```
+  /// use lrucache::LRUCache;
+  ///
+  /// let mut cache = LRUCache::with_capacity(2);
+  /// cache.entry("foo").and_modify(|count| *count += 1).or_insert(1);
+  /// cache.entry("foo").and_modify(|count| *count += 1).or_insert(1);
+  /// assert_eq!(cache.peek(&"foo"), Some(&2));
+  /// ```
+  pub fn and_modify(self, f: impl FnOnce(&mut V)) -> Self {
+    match self {
+      Entry::Occupied(mut entry) => {
+        f(entry.get_mut());
+        Entry::Occupied(entry)
+      }
+      Entry::Vacant(entry) => Entry::Vacant(entry),
+    }
+  }
+}
+
+impl<'a, K: Clone + Hash + Eq, V> OccupiedEntry<'a, K, V> {
+  ///
+  /// Returns the key of the entry.
+  ///
+  pub fn key(&self) -> &K {
+    &self.cache.entries[self.idx].key
+  }
+
+  ///
+  /// Returns a reference to the value of the entry.
+  ///
+  pub fn get(&self) -> &V {
+    self.cache.entries[self.idx].value.as_ref().unwrap()
+  }
+
+  ///
+  /// Returns a mutable reference to the value of the entry.
+  ///
+  pub fn get_mut(&mut self) -> &mut V {
+    self.cache.entries[self.idx].value.as_mut().unwrap()
+  }
+
+  ///
+  /// Converts the entry into a mutable reference to its value, which lives as long as the
+  /// borrow of the cache.
+  ///
+  pub fn into_mut(self) -> &'a mut V {
+    self.cache.entries[self.idx].value.as_mut().unwrap()
+  }
+
+  ///
+  /// Replaces the value of the entry and returns the previous value, resetting when it was
+  /// inserted and removing older items to make room for it just as `insert` would.
+  ///
+  pub fn insert(&mut self, value: V) -> V {
+    let old = self.cache.replace_value(self.idx, value);
+    // the entry is the most recent item, so it's never the one that's removed
+    self.cache.trim_to_max_bytes();
+    old.unwrap()
+  }
+
+  ///
+  /// Removes the entry from the cache and returns its value.
+  ///
+  pub fn remove(self) -> V {
+    self.cache.remove_entry(self.idx).unwrap()
+  }
+}
+
+impl<'a, K: Clone + Hash + Eq, V> VacantEntry<'a, K, V> {
+  ///
+  /// Returns the key that the entry would be inserted with.
+  ///
+  pub fn key(&self) -> &K {
+    &self.key
+  }
+
+  ///
+  /// Inserts the value of the entry at the top of the cache, removing the least recent items
+  /// to make room for it, and returns a mutable reference to it.
+  ///
+  pub fn insert(self, value: V) -> &'a mut V {
+    self.cache.insert(self.key.clone(), value);
+    let idx = self.cache.table[&self.key];
+    self.cache.entries[idx].value.as_mut().unwrap()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{Entry, LRUCache};
+  use std::sync::{Arc, Mutex};
+  use std::thread;
+  use std::time::Duration;
+
+  #[test]
+  fn reuses_the_slots_of_removed_items() {
+    let mut cache = LRUCache::new(3);
+    cache.insert(0, 0);
+    cache.insert(1, 1);
+    cache.insert(2, 2);
+
+    // every removed item frees a slot for the next item
+    for i in 3..1000 {
+      cache.remove(&(i - 3));
+      cache.insert(i, i);
+      assert_eq!(cache.entries.len(), 3);
+    }
+
+    // as does every evicted item
+    for i in 1000..2000 {
+      cache.insert(i, i);
+      assert_eq!(cache.entries.len(), 3);
+    }
+    assert_eq!(cache.len(), 3);
+    assert!(cache.free.is_empty());
+  }
+
+  #[test]
+  fn replacing_an_entry_removes_older_items_to_make_room_for_it() {
+    let mut cache = LRUCache::with_limits(3, 10, |value: &Vec<u8>| value.len());
+    cache.insert("foo", vec![0; 4]);
+    cache.insert("bar", vec![0; 4]);
+    cache.insert("baz", vec![0; 2]);
+
+    if let Entry::Occupied(mut entry) = cache.entry("bar") {
+      assert_eq!(entry.insert(vec![0; 6]).len(), 4);
+    }
+    assert_eq!(cache.bytes(), 8);
+    assert!(!cache.contains_key(&"foo"));
+    assert!(cache.contains_key(&"baz"));
+    assert_eq!(cache.get(&"bar").map(Vec::len), Some(6));
+  }
+
+  #[test]
+  fn expired_items_are_removed_without_invoking_the_eviction_callback() {
+    let evicted = Arc::new(Mutex::new(Vec::new()));
+    let mut cache = LRUCache::new(3);
+    {
+      let evicted = Arc::clone(&evicted);
+      cache.set_on_evict(move |key: &&str, _: &i32| evicted.lock().unwrap().push(*key));
+    }
+    cache.set_ttl(Some(Duration::from_millis(10)));
+    cache.insert("foo", 1);
+    cache.insert("bar", 2);
+
+    thread::sleep(Duration::from_millis(20));
+    assert_eq!(cache.purge_expired(), 2);
+    assert!(cache.is_empty());
+    assert!(evicted.lock().unwrap().is_empty());
+
+    // unlike the items that are removed because the cache ran out of room
+    cache.insert("baz", 3);
+    cache.resize(0);
+    assert_eq!(*evicted.lock().unwrap(), vec!["baz"]);
+  }
+
+  #[test]
+  fn retain_drops_items_by_key_and_by_value() {
+    let mut cache = LRUCache::new(3);
+    cache.insert("foo_20", vec![0; 4]);
+    cache.insert("bar_20", vec![0; 8]);
+    cache.insert("foo_50", vec![0; 16]);
+
+    // drop every variant of "foo"
+    cache.retain(|key, _| !key.starts_with("foo"));
+    assert_eq!(cache.len(), 1);
+    assert!(cache.contains_key(&"bar_20"));
+    assert!(!cache.contains_key(&"foo_20"));
+    assert!(!cache.contains_key(&"foo_50"));
+
+    // drop every item larger than 8 bytes
+    cache.insert("baz_20", vec![0; 2]);
+    cache.insert("baz_50", vec![0; 32]);
+    cache.retain(|_, value| value.len() <= 8);
+    assert_eq!(cache.len(), 2);
+    assert!(cache.contains_key(&"bar_20"));
+    assert!(cache.contains_key(&"baz_20"));
+    assert!(!cache.contains_key(&"baz_50"));
+  }
+
+  #[test]
+  fn retain_keeps_the_order_of_the_retained_items() {
+    let mut cache = LRUCache::new(4);
+    cache.insert("a", 1);
+    cache.insert("b", 2);
+    cache.insert("c", 3);
+    cache.insert("d", 4);
+    cache.get(&"a");
+
+    // "a" is the newest item and "b" the oldest, so both ends of the list are removed
+    cache.retain(|key, _| *key != "a" && *key != "b");
+    assert_eq!(cache.len(), 2);
+
+    // "c" is now the oldest item and is removed first, followed by "d"
+    cache.insert("e", 5);
+    cache.insert("f", 6);
+    cache.insert("g", 7);
+    assert!(!cache.contains_key(&"c"));
+    assert!(cache.contains_key(&"d"));
+    cache.insert("h", 8);
+    assert!(!cache.contains_key(&"d"));
+    assert_eq!(cache.len(), 4);
+
+    // a cache that retains nothing can be filled again
+    cache.retain(|_, _| false);
+    assert_eq!(cache.len(), 0);
+    cache.insert("i", 9);
+    assert_eq!(cache.get(&"i"), Some(&9));
+  }
 }
@@ -5,15 +5,46 @@ extern crate image;
 
 extern crate tokio;
 
+use rocket::figment::Figment;
+use rocket::tokio::runtime::{self, Runtime};
+use rocket::{Build, Rocket};
+use std::io;
+
 // use rocket::serde::{Deserialize, Serialize};
 
-mod lrucache;
+// route codegen re-exports an unused uri! macro for every handler
+#[allow(unused_imports)]
+mod admin;
+mod cachecontrol;
+mod cleanup;
+mod compression;
+mod config;
+#[allow(unused_imports)]
+mod cors;
+#[allow(unused_imports)]
+mod favicon;
+mod precompressed;
+// uri display codegen borrows every field of a query
+#[allow(clippy::needless_borrows_for_generic_args)]
+mod query;
+mod ratelimit;
 mod reqimage;
+#[macro_use]
+mod requestid;
+mod segments;
+#[allow(unused_imports)]
 mod serve;
+mod signature;
+mod snapshot;
+#[allow(unused_imports)]
+mod stats;
+mod templates;
+#[allow(unused_imports)]
+mod upload;
 mod utils;
 
-// #[cfg(test)]
-// mod tests;
+#[cfg(test)]
+mod tests;
 
 // #[derive(Serialize, Deserialize)]
 // #[serde(crate = "rocket::serde")]
@@ -36,10 +67,90 @@ fn not_found() -> utils::InvalidRequest {
     utils::send_404_response("Resource was not found.".to_string())
 }
 
-#[launch]
-fn rocket() -> _ {
-    rocket::build()
+#[catch(500)]
+fn internal_error() -> utils::InvalidRequest {
+    utils::send_500_response("Something went wrong.".to_string())
+}
+
+/// Builds the server from a configuration, which tests provide their own of.
+///
+/// Arguments:
+///
+/// * `figment` - Figment
+///
+/// Returns: `Rocket<Build>`
+///
+/// Usage: ```app(rocket::Config::figment());```
+fn app(figment: Figment) -> Rocket<Build> {
+    rocket::custom(figment)
         // .mount("/", routes![index, hello])
+        .attach(requestid::main())
         .attach(serve::main())
-        .register("/", catchers![not_found])
+        .attach(serve::log_stats_on_shutdown())
+        .attach(snapshot::main())
+        .attach(cleanup::main())
+        .attach(compression::main())
+        .attach(cors::main())
+        .attach(favicon::main())
+        .attach(ratelimit::main())
+        .attach(upload::main())
+        .attach(admin::main())
+        .attach(stats::main())
+        .attach(templates::main())
+        .register("/", catchers![not_found, internal_error])
+}
+
+/// Builds the async runtime that the server runs on, as `#[launch]` would, along with the
+/// configured `max_blocking_threads`. The blocking pool runs every resize, as well as every
+/// file read and write, so it caps how many of them run at once, while `max_concurrent_resizes`
+/// only caps the resizes.
+///
+/// Arguments:
+///
+/// * `figment` - &Figment
+///
+/// Returns: `io::Result<Runtime>`
+///
+/// Usage: ```build_runtime(&rocket::Config::figment())?;```
+fn build_runtime(figment: &Figment) -> io::Result<Runtime> {
+    let mut builder = runtime::Builder::new_multi_thread();
+    builder
+        .worker_threads(rocket::Config::from(figment.clone()).workers)
+        // rocket's graceful shutdown depends on the "rocket-worker" prefix
+        .thread_name("rocket-worker-thread")
+        .enable_all();
+
+    // an invalid configuration is reported once the server ignites
+    let max_blocking_threads = config::Config::from_figment(figment)
+        .ok()
+        .and_then(|config| config.max_blocking_threads);
+    if let Some(threads) = max_blocking_threads {
+        builder.max_blocking_threads(threads.max(1));
+    }
+
+    builder.build()
+}
+
+fn main() {
+    let figment = rocket::Config::figment();
+    let runtime = build_runtime(&figment).expect("Unable to build the async runtime.");
+
+    // as with `#[launch]`, an unhandled launch error reports itself when it's dropped
+    let _res = runtime.block_on(async {
+        let rocket = app(figment).ignite().await?;
+        let index = rocket
+            .state::<config::Config>()
+            .and_then(|config| config.cache_index.clone());
+        let state = rocket.state::<serve::Cache>().cloned();
+
+        rocket.launch().await?;
+
+        // every request is done with the cache by now, yet the runtime is still around to
+        // write its index
+        if let (Some(index), Some(state)) = (index, state) {
+            snapshot::save_index(&index, &state).await;
+        }
+
+        Ok::<(), rocket::Error>(())
+    });
 }
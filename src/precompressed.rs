@@ -0,0 +1,154 @@
+use rocket::fs::{FileServer, NamedFile};
+use rocket::http::uri::{fmt::Path as UriPath, Segments};
+use rocket::http::{ContentType, Header, Method};
+use rocket::route::{Handler, Outcome};
+use rocket::{Data, Request, Route};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+/// Extension of a file's brotli-precompressed sibling, e.g. `app.js` => `app.js.br`.
+const BROTLI_EXTENSION: &str = "br";
+
+/// A brotli-precompressed file, which is sent with the content type of the file it was
+/// compressed from.
+#[derive(Responder)]
+struct Precompressed {
+    inner: (ContentType, NamedFile),
+    encoding: Header<'static>,
+    vary: Header<'static>,
+}
+
+/// Serves static files like a `FileServer`, but prefers a file's `.br` sibling when the
+/// client accepts brotli.
+#[derive(Clone)]
+pub struct PrecompressedServer {
+    root: PathBuf,
+    files: FileServer,
+    rank: isize,
+}
+
+impl PrecompressedServer {
+    /// Serves the files within a directory, at the given rank.
+    ///
+    /// Arguments:
+    ///
+    /// * `root` - &Path
+    /// * `rank` - isize
+    ///
+    /// Returns: `PrecompressedServer`
+    ///
+    /// Usage: ```PrecompressedServer::new(&config.static_dir, 10);```
+    pub fn new(root: &Path, rank: isize) -> Self {
+        PrecompressedServer {
+            root: root.to_path_buf(),
+            files: FileServer::from(root).rank(rank),
+            rank,
+        }
+    }
+}
+
+/// Determines if an `Accept-Encoding` header advertises brotli, and hasn't refused it with
+/// `q=0`.
+///
+/// Arguments:
+///
+/// * `accept_encoding` - &str
+///
+/// Returns: `bool`
+///
+/// Usage: ```accepts_brotli(accept_encoding);```
+fn accepts_brotli(accept_encoding: &str) -> bool {
+    accept_encoding.split(',').any(|encoding| {
+        let mut params = encoding.split(';').map(str::trim);
+        let name = params.next().unwrap_or_default();
+        let refused = params.any(|param| {
+            param
+                .strip_prefix("q=")
+                .and_then(|q| q.parse::<f32>().ok())
+                .is_some_and(|q| q == 0.0)
+        });
+
+        name.eq_ignore_ascii_case(BROTLI_EXTENSION) && !refused
+    })
+}
+
+/// Retrieves the pathname of a file's brotli-precompressed sibling => `app.js.br`
+///
+/// Arguments:
+///
+/// * `path` - &Path
+///
+/// Returns: `PathBuf`
+///
+/// Usage: ```brotli_sibling(&path);```
+fn brotli_sibling(path: &Path) -> PathBuf {
+    let mut pathname = OsString::from(path);
+    pathname.push(".");
+    pathname.push(BROTLI_EXTENSION);
+    PathBuf::from(pathname)
+}
+
+#[rocket::async_trait]
+impl Handler for PrecompressedServer {
+    async fn handle<'r>(&self, req: &'r Request<'_>, data: Data<'r>) -> Outcome<'r> {
+        // dotfiles are refused, just as the wrapped file server refuses them
+        let path = req
+            .segments::<Segments<'_, UriPath>>(0..)
+            .ok()
+            .and_then(|segments| segments.to_path_buf(false).ok())
+            .map(|path| self.root.join(path))
+            .filter(|path| path.is_file());
+
+        let (path, sibling) = match path {
+            Some(path) => {
+                let sibling = brotli_sibling(&path);
+                match sibling.is_file() {
+                    true => (path, sibling),
+                    false => return self.files.handle(req, data).await,
+                }
+            }
+            None => return self.files.handle(req, data).await,
+        };
+
+        let accepted = req
+            .headers()
+            .get_one("Accept-Encoding")
+            .is_some_and(accepts_brotli);
+
+        if !accepted {
+            // the response differs depending on whether a client accepts brotli
+            return match self.files.handle(req, data).await {
+                Outcome::Success(mut res) => {
+                    res.adjoin_header(Header::new("Vary", "Accept-Encoding"));
+                    Outcome::Success(res)
+                }
+                outcome => outcome,
+            };
+        }
+
+        let content_type = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .and_then(ContentType::from_extension)
+            .unwrap_or(ContentType::Binary);
+
+        let precompressed = NamedFile::open(sibling)
+            .await
+            .ok()
+            .map(|file| Precompressed {
+                inner: (content_type, file),
+                encoding: Header::new("Content-Encoding", BROTLI_EXTENSION),
+                vary: Header::new("Vary", "Accept-Encoding"),
+            });
+
+        Outcome::from_or_forward(req, data, precompressed)
+    }
+}
+
+impl From<PrecompressedServer> for Vec<Route> {
+    fn from(server: PrecompressedServer) -> Self {
+        let mut route = Route::ranked(server.rank, Method::Get, "/<path..>", server.clone());
+        route.name = Some(format!("PrecompressedServer: {}", server.root.display()).into());
+        vec![route]
+    }
+}
@@ -0,0 +1,142 @@
+use rocket::form::{self, FromFormField, ValueField};
+use rocket::http::impl_from_uri_param_identity;
+use rocket::http::uri::fmt::{Formatter, Query, UriDisplay};
+use rocket::request::FromParam;
+use std::fmt;
+use std::str::FromStr;
+
+/// Query of a request to resize an image, for example: `?width=200&filter=nearest`.
+#[derive(FromForm, UriDisplayQuery)]
+pub struct ImageQuery<'r> {
+    pub ratio: Option<&'r str>,
+    pub width: Option<Parsed<Width>>,
+    pub dpr: Option<&'r str>,
+    pub filter: Option<&'r str>,
+    pub allow_upscale: Option<bool>,
+    pub progressive: Option<bool>,
+    pub tint: Option<&'r str>,
+    pub grayscale: Option<bool>,
+    pub format: Option<&'r str>,
+    pub bg: Option<&'r str>,
+    pub quality: Option<&'r str>,
+    pub webp_lossless: Option<bool>,
+    pub quality_preset: Option<&'r str>,
+    pub ephemeral: Option<bool>,
+    pub original: Option<bool>,
+}
+
+/// A width, in pixels, that an image is resized to, which is parsed once from a request.
+/// Whether it's within `max_width` and the `allowed_widths` depends on the configuration, so
+/// it's checked when the request is validated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Width(pub u32);
+
+impl FromStr for Width {
+    type Err = String;
+
+    fn from_str(width: &str) -> Result<Self, Self::Err> {
+        match width.trim().parse::<u32>() {
+            Ok(width) if width > 0 => Ok(Width(width)),
+            _ => Err("The provided width is invalid! It must be a positive number.".to_string()),
+        }
+    }
+}
+
+impl UriDisplay<Query> for Width {
+    fn fmt(&self, f: &mut Formatter<'_, Query>) -> fmt::Result {
+        f.write_value(self.0)
+    }
+}
+
+impl fmt::Display for Width {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// A query value that's parsed once from a request, which keeps why an invalid value couldn't
+/// be parsed so that it's rejected with a 400, where an invalid `Option` would be discarded as
+/// if it weren't provided.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Parsed<T>(pub Result<T, String>);
+
+impl<'v, T: FromStr<Err = String> + Send> FromFormField<'v> for Parsed<T> {
+    fn from_value(field: ValueField<'v>) -> form::Result<'v, Self> {
+        Ok(Parsed(field.value.parse()))
+    }
+}
+
+impl<T: UriDisplay<Query>> UriDisplay<Query> for Parsed<T> {
+    fn fmt(&self, f: &mut Formatter<'_, Query>) -> fmt::Result {
+        match &self.0 {
+            Ok(value) => value.fmt(f),
+            Err(_) => Ok(()),
+        }
+    }
+}
+
+impl_from_uri_param_identity!([Query] (T: UriDisplay<Query>) Parsed<T>);
+
+/// Resize parameters that lead the path of a pretty url, which are joined by `_` and each
+/// made of a letter and a number delimited by `:`, for example: `/image/w:400_q:80/photo.jpg`.
+/// The delimiter keeps directories that are named like a parameter (`w2023`) from being read
+/// as one.
+#[derive(Debug, PartialEq, Eq)]
+pub struct PrettyParams<'r>(Vec<(char, &'r str)>);
+
+impl<'r> FromParam<'r> for PrettyParams<'r> {
+    type Error = &'r str;
+
+    fn from_param(param: &'r str) -> Result<Self, Self::Error> {
+        let params = param
+            .split('_')
+            .map(|param| match param.split_once(':') {
+                Some((name @ ("w" | "h" | "q"), value))
+                    if !value.is_empty() && value.bytes().all(|b| b.is_ascii_digit()) =>
+                {
+                    Ok((name.chars().next().unwrap(), value))
+                }
+                _ => Err(param),
+            })
+            .collect::<Result<Vec<(char, &str)>, &str>>()?;
+
+        Ok(PrettyParams(params))
+    }
+}
+
+impl<'r> PrettyParams<'r> {
+    /// Merges the parameters into the query of a request, as if they were provided by it:
+    /// `w` is the `width` and `q` is the `quality`.
+    ///
+    /// Arguments:
+    ///
+    /// * `query` - ImageQuery
+    ///
+    /// Returns: `Result<ImageQuery, String>`
+    ///
+    /// Usage: ```params.merge(query)?;```
+    pub fn merge(self, mut query: ImageQuery<'r>) -> Result<ImageQuery<'r>, String> {
+        let mut merged = Vec::new();
+
+        for (name, value) in self.0 {
+            if merged.contains(&name) {
+                return Err(format!("The {} parameter may only be provided once.", name));
+            }
+            merged.push(name);
+
+            match name {
+                'w' if query.width.is_none() => query.width = Some(Parsed(value.parse())),
+                'q' if query.quality.is_none() => query.quality = Some(value),
+                'h' => return Err("Resizing to a height isn't supported.".to_string()),
+                _ => {
+                    return Err(format!(
+                        "The {} parameter may only be provided by either the path or the query.",
+                        name
+                    ))
+                }
+            }
+        }
+
+        Ok(query)
+    }
+}
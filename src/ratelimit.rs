@@ -0,0 +1,113 @@
+use crate::config::Config;
+use crate::utils::{send_429_response, InvalidRequest};
+use futures_locks::Mutex;
+use rocket::fairing::AdHoc;
+use rocket::outcome::Outcome;
+use rocket::request::{self, FromRequest, Request};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::Instant;
+
+/// Amount of clients that are tracked before clients with a full bucket are forgotten.
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+type Buckets = Mutex<HashMap<IpAddr, Bucket>>;
+
+/// The tokens that a client has left to spend on requests, which refill over time.
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Limits each client IP to `rate_limit_per_second` requests, with bursts of up to
+/// `rate_limit_burst` requests. Requests are unlimited when no rate is configured. The client
+/// IP is the address of the connection, unless it's one of the `trusted_proxies`.
+///
+/// Request guards run before a route's path and query are matched, so this guard never fails
+/// and the limit is checked by the handler instead. Otherwise, requests that are forwarded
+/// to other routes would be limited as well.
+pub struct RateLimit<'r> {
+    ip: Option<IpAddr>,
+    config: Option<&'r Config>,
+    buckets: Option<&'r Buckets>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RateLimit<'r> {
+    type Error = &'static str;
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let config = req.rocket().state::<Config>();
+
+        // any client can send an `X-Real-IP` header, so it's only trusted from a proxy
+        let remote = req.remote().map(|remote| remote.ip());
+        let ip = match (remote, config) {
+            (Some(remote), Some(config)) if config.trusted_proxies.contains(&remote) => {
+                req.real_ip().or(Some(remote))
+            }
+            _ => remote,
+        };
+
+        Outcome::Success(RateLimit {
+            ip,
+            config,
+            buckets: req.rocket().state::<Buckets>(),
+        })
+    }
+}
+
+impl RateLimit<'_> {
+    /// Spends a token from the bucket of the request's client IP.
+    ///
+    /// Arguments: (none)
+    ///
+    /// Returns: `Result<(), InvalidRequest>`
+    ///
+    /// Usage: ```limit.check().await?;```
+    pub async fn check(&self) -> Result<(), InvalidRequest> {
+        let (rate, burst, buckets, ip) = match (self.config, self.buckets, self.ip) {
+            (Some(config), Some(buckets), Some(ip)) => match config.rate_limit_per_second {
+                Some(rate) if rate > 0.0 => {
+                    (rate, config.rate_limit_burst.max(1) as f64, buckets, ip)
+                }
+                _ => return Ok(()),
+            },
+            _ => return Ok(()),
+        };
+
+        let mut buckets = buckets.lock().await;
+        let now = Instant::now();
+
+        // forget clients that have since refilled their bucket
+        if buckets.len() >= MAX_TRACKED_CLIENTS {
+            buckets.retain(|_, bucket| {
+                bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate < burst
+            });
+        }
+
+        let bucket = buckets.entry(ip).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+
+        // refill the bucket for the time that has passed since the client's last request
+        bucket.tokens =
+            (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate).min(burst);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+
+        let retry_after = ((1.0 - bucket.tokens) / rate).ceil() as u64;
+
+        Err(send_429_response(retry_after.max(1)))
+    }
+}
+
+pub fn main() -> AdHoc {
+    AdHoc::on_ignite("rate limit", |rocket| async {
+        rocket.manage(Mutex::new(HashMap::<IpAddr, Bucket>::new()))
+    })
+}
@@ -1,72 +1,574 @@
-use crate::utils::{get_file_path, get_root_dir, get_string_path};
-use image::imageops::FilterType;
-use image::GenericImageView;
+use crate::query::Width;
+use crate::utils::{
+    find_root, get_file_path, get_string_path, image_content_type, image_extension,
+};
+use image::codecs::gif::{GifDecoder, GifEncoder, Repeat};
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::{self, FilterType};
+use image::io::Reader;
+use image::{
+    AnimationDecoder, DynamicImage, Frame, GenericImageView, ImageError, ImageFormat, Rgb,
+    RgbImage, RgbaImage,
+};
 use rocket::http::ContentType;
+use rocket::serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::convert::TryFrom;
 use std::ffi::OsStr;
+use std::fs;
+use std::io::{self, BufReader, Cursor};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use tokio::fs::File;
 use tokio::io::AsyncReadExt;
+use tokio::task;
 
-#[derive(Debug)]
+/// Hidden directory within a static directory that variants are saved into, which neither
+/// the file server nor an image path can reach, so a variant is never mistaken for an original
+/// image.
+pub const VARIANTS_DIR: &str = ".variants";
+
+/// Width of a low quality image placeholder.
+const LQIP_WIDTH: u32 = 20;
+
+/// Blur applied to a low quality image placeholder.
+const LQIP_BLUR: f32 = 2.0;
+
+/// Jpeg quality of a low quality image placeholder.
+const LQIP_QUALITY: u8 = 30;
+
+/// Quality of a lossy jpeg or webp when neither a request nor the configuration provide one,
+/// which matches the image crate's default jpeg quality.
+const DEFAULT_QUALITY: u8 = 75;
+
+/// How far each pixel is blended toward a requested tint, from 0 (none) to 1 (solid).
+const TINT_STRENGTH: f32 = 0.5;
+
+/// Size that an image is reduced to before averaging its color.
+const COLOR_SAMPLE_SIZE: u32 = 64;
+
+/// Size that an image is reduced to before computing its blurhash.
+const BLURHASH_SAMPLE_SIZE: u32 = 32;
+
+/// Horizontal and vertical components of a blurhash, where more components capture more
+/// detail in a longer string.
+const BLURHASH_COMPONENTS: (u32, u32) = (4, 3);
+
+/// Bytes of an original image's SHA-256 digest that content addressed variants are named
+/// after, which are hex encoded into 16 characters.
+const CONTENT_HASH_BYTES: usize = 8;
+
+/// Resampling filter used to reduce an image when a request doesn't provide one.
+pub const DEFAULT_FILTER: FilterType = FilterType::CatmullRom;
+
+/// Resampling filter used to enlarge an image when a request doesn't provide one, which keeps
+/// edges sharper than the default filter does.
+pub const DEFAULT_UPSCALE_FILTER: FilterType = FilterType::Lanczos3;
+
+/// Faster resampling filter used for widths below the `fast_filter_width`, when a request
+/// doesn't provide one.
+const FAST_FILTER: FilterType = FilterType::Triangle;
+
+/// Resampling filters that can be requested by name.
+pub const FILTERS: [(&str, FilterType); 5] = [
+    ("nearest", FilterType::Nearest),
+    ("triangle", FilterType::Triangle),
+    ("catmullrom", FilterType::CatmullRom),
+    ("gaussian", FilterType::Gaussian),
+    ("lanczos3", FilterType::Lanczos3),
+];
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    pub hex: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Metadata {
+    pub width: u32,
+    pub height: u32,
+    pub format: String,
+    pub bytes: u64,
+}
+
+#[derive(Debug, Clone)]
 pub struct RequestedImage {
     pub content_type: Option<ContentType>,
     pub path: PathBuf,
+    pub root: PathBuf,
     pub new_pathname: String,
     pub new_pathname_buf: PathBuf,
     pub ratio: u8,
+    pub width: Option<u32>,
+    pub filter: Option<FilterType>,
+    pub fast_filter_width: u32,
+    pub downscale_filter: FilterType,
+    pub upscale_filter: FilterType,
+    pub progressive: bool,
+    pub tint: Option<[u8; 3]>,
+    pub grayscale: bool,
+    pub content_hash: Option<String>,
+    pub background: Option<[u8; 3]>,
+    pub quality: Option<u8>,
+    pub lossless: bool,
+    pub jpeg_quality: u8,
+    pub webp_quality: u8,
 }
 
-impl<'p, 'r> RequestedImage {
+impl RequestedImage {
     /// Initialize a new requested image that:
-    /// * strips out any provided ratios within the stem -> filename_ratio -> filename
-    /// * creates buffers from the stripped pathname and a potential new path (filename_ratio.ext)
+    /// * creates buffers from the pathname and a potential new path (.variants/filename_ratio.ext)
     /// * retrieves content type from requested image
     ///
     /// Arguments:
     ///
-    /// * `path` - PathBuf
+    /// * `roots` - &[PathBuf]
+    /// * `path` - &Path
     /// * `ratio` - Option<u8>
     ///
-    /// Usage: ```RequestedImage::new(&path, ratio);```
-    pub fn new(path: &'p PathBuf, ratio: u8) -> Self {
-        // if present, strip any included "_<ratio>" from the filename
-        let filename: String = get_string_path(path.to_path_buf())
-            .chars()
-            .filter(|c| !c.is_digit(10))
-            .filter(|c| *c != '_')
-            .collect();
-
-        // retrieve file path to "static" folder => <rootdir><static><filename>.<ext>
-        let filepath = get_file_path(filename);
-
-        // or assign pathname with ratio: <rootdir><filename>_<ratio>.<ext>
-        let pathname = match ratio == 0 {
-            true => get_string_path(&filepath),
-            false => {
+    /// Usage: ```RequestedImage::new(&config.roots(), &path, ratio);```
+    pub fn new(roots: &[PathBuf], path: &Path, ratio: u8) -> Self {
+        let suffix = match ratio == 0 {
+            true => None,
+            false => Some(ratio.to_string()),
+        };
+
+        RequestedImage::with_suffix(roots, path, suffix, ratio, None)
+    }
+
+    /// Initialize a new requested image that is resized to an exact width, creating a
+    /// potential new path of filename_<width>w.ext
+    ///
+    /// Arguments:
+    ///
+    /// * `roots` - &[PathBuf]
+    /// * `path` - &Path
+    /// * `width` - Width
+    ///
+    /// Usage: ```RequestedImage::with_width(&config.roots(), &path, width);```
+    pub fn with_width(roots: &[PathBuf], path: &Path, width: Width) -> Self {
+        let Width(width) = width;
+
+        RequestedImage::with_suffix(roots, path, Some(format!("{}w", width)), 0, Some(width))
+    }
+
+    /// Initialize a new requested low quality image placeholder (filename_lqip.ext), which
+    /// is only ever stored in memory
+    ///
+    /// Arguments:
+    ///
+    /// * `roots` - &[PathBuf]
+    /// * `path` - &Path
+    ///
+    /// Usage: ```RequestedImage::lqip(&config.roots(), &path);```
+    pub fn lqip(roots: &[PathBuf], path: &Path) -> Self {
+        RequestedImage::with_suffix(roots, path, Some("lqip".to_string()), 0, Some(LQIP_WIDTH))
+    }
+
+    /// Shared initializer that assigns the variant's pathname from the provided suffix
+    ///
+    /// Arguments:
+    ///
+    /// * `roots` - &[PathBuf]
+    /// * `path` - &Path
+    /// * `suffix` - Option<String>
+    /// * `ratio` - u8
+    /// * `width` - Option<u32>
+    ///
+    /// Usage: ```RequestedImage::with_suffix(roots, path, suffix, ratio, width);```
+    fn with_suffix(
+        roots: &[PathBuf],
+        path: &Path,
+        suffix: Option<String>,
+        ratio: u8,
+        width: Option<u32>,
+    ) -> Self {
+        // the filename is taken as is, since variants are never saved beside the original images
+        // retrieve file path to the first root folder containing it => <rootdir><filename>.<ext>
+        let root = find_root(roots, path);
+        let filepath = get_file_path(root, path);
+
+        // or assign pathname with suffix: <rootdir>/.variants/<dirs>/<filename>_<suffix>.<ext>
+        let pathname = match suffix {
+            None => get_string_path(&filepath),
+            Some(suffix) => {
                 // retrieve image file stem => <filename>
                 let stem = &filepath
                     .file_stem()
                     .and_then(OsStr::to_str)
-                    .expect(&format!("Image is missing stem"));
+                    .expect("Image is missing stem");
 
-                // retrieve image file stem => <ext>
-                let ext = &filepath
-                    .extension()
-                    .and_then(OsStr::to_str)
-                    .expect(&format!("Image is missing extension"));
-                format!("{}/{}_{}.{}", get_root_dir(), stem, ratio, ext)
+                // retrieve image file extension, normalized for the variant => <ext>
+                let ext = image_extension(&filepath).expect("Image is missing extension");
+                get_string_path(
+                    variants_dir(root, &filepath).join(format!("{}_{}.{}", stem, suffix, ext)),
+                )
             }
         };
 
         RequestedImage {
-            content_type: path
-                .extension()
-                .and_then(OsStr::to_str)
-                .and_then(ContentType::from_extension),
-            path: get_file_path(&filepath),
+            content_type: image_content_type(path),
+            path: filepath,
+            root: root.to_path_buf(),
             new_pathname: pathname.to_string(),
             new_pathname_buf: Path::new(&pathname).to_path_buf(),
             ratio,
+            width,
+            filter: None,
+            fast_filter_width: 0,
+            downscale_filter: DEFAULT_FILTER,
+            upscale_filter: DEFAULT_UPSCALE_FILTER,
+            progressive: false,
+            tint: None,
+            grayscale: false,
+            content_hash: None,
+            background: None,
+            quality: None,
+            lossless: false,
+            jpeg_quality: DEFAULT_QUALITY,
+            webp_quality: DEFAULT_QUALITY,
+        }
+    }
+
+    /// Assigns the resampling filter of the requested image, which is appended to the new
+    /// pathname (filename_<suffix>_<filter>.ext) when provided. Otherwise, the filter is
+    /// selected from the new image's width: the faster filter below `fast_filter_width` and
+    /// the default filter at or above it.
+    ///
+    /// Arguments:
+    ///
+    /// * `filter` - Option<FilterType>
+    /// * `fast_filter_width` - u32
+    ///
+    /// Usage: ```RequestedImage::with_width(&config.roots(), &path, width).with_filter(filter, config.fast_filter_width);```
+    pub fn with_filter(mut self, filter: Option<FilterType>, fast_filter_width: u32) -> Self {
+        self.filter = filter;
+        self.fast_filter_width = fast_filter_width;
+
+        // the original image is never resampled, so its pathname stays the same
+        let filter = match filter {
+            Some(filter) if !self.is_original() => filter,
+            _ => return self,
+        };
+
+        let name = FILTERS
+            .iter()
+            .find(|(_, value)| *value == filter)
+            .map(|(name, _)| *name)
+            .expect("Filter is missing a name");
+
+        self.push_suffix(name);
+
+        self
+    }
+
+    /// Assigns whether the requested jpeg image is encoded as a progressive jpeg, which is
+    /// appended to the new pathname (filename_<suffix>_progressive.ext)
+    ///
+    /// Arguments:
+    ///
+    /// * `progressive` - bool
+    ///
+    /// Usage: ```RequestedImage::with_width(&config.roots(), &path, width).with_progressive(true);```
+    pub fn with_progressive(mut self, progressive: bool) -> Self {
+        self.progressive = progressive;
+
+        if progressive {
+            self.push_suffix("progressive");
+        }
+
+        self
+    }
+
+    /// Assigns a color that the requested image is blended toward, and whether it's converted
+    /// to grayscale beforehand, which are appended to the new pathname
+    /// (filename_<suffix>_gray_tint<rrggbb>.ext)
+    ///
+    /// Arguments:
+    ///
+    /// * `tint` - Option<[u8; 3]>
+    /// * `grayscale` - bool
+    ///
+    /// Usage: ```RequestedImage::with_width(&config.roots(), &path, width).with_tint(tint, false);```
+    pub fn with_tint(mut self, tint: Option<[u8; 3]>, grayscale: bool) -> Self {
+        self.tint = tint;
+        self.grayscale = grayscale;
+
+        if grayscale {
+            self.push_suffix("gray");
+        }
+
+        if let Some([r, g, b]) = tint {
+            self.push_suffix(&format!("tint{:02x}{:02x}{:02x}", r, g, b));
+        }
+
+        self
+    }
+
+    /// Assigns a format that the requested image is converted to, which replaces the
+    /// extension of the new pathname. A jpeg also appends the background that any transparency
+    /// is flattened onto (filename_<suffix>_bg<rrggbb>.jpg), while a webp preserves it. An
+    /// image that's already in the format is left as is.
+    ///
+    /// Arguments:
+    ///
+    /// * `format` - Option<ImageFormat> - only `ImageFormat::Jpeg` and `ImageFormat::WebP` are supported
+    /// * `background` - [u8; 3]
+    ///
+    /// Usage: ```RequestedImage::with_width(&config.roots(), &path, width).with_format(Some(ImageFormat::Jpeg), [255, 255, 255]);```
+    pub fn with_format(mut self, format: Option<ImageFormat>, background: [u8; 3]) -> Self {
+        let (content_type, ext) = match format {
+            Some(ImageFormat::Jpeg) => (ContentType::JPEG, "jpg"),
+            Some(ImageFormat::WebP) => (ContentType::WEBP, "webp"),
+            _ => return self,
+        };
+
+        if self.content_type == Some(content_type.clone()) {
+            return self;
+        }
+
+        self.make_variant();
+        self.content_type = Some(content_type);
+        self.new_pathname_buf.set_extension(ext);
+
+        if format == Some(ImageFormat::Jpeg) {
+            self.background = Some(background);
+
+            let [r, g, b] = background;
+            self.push_suffix(&format!("bg{:02x}{:02x}{:02x}", r, g, b));
+        } else {
+            // the new pathname is otherwise updated along with its suffix
+            self.new_pathname = get_string_path(&self.new_pathname_buf);
+        }
+
+        self
+    }
+
+    /// Assigns the quality that a jpeg or lossy webp is encoded with, or whether a webp is
+    /// encoded losslessly, which are appended to the new pathname (filename_<suffix>_q<quality>.ext
+    /// or filename_<suffix>_lossless.webp)
+    ///
+    /// Arguments:
+    ///
+    /// * `quality` - Option<u8> - from 1 to 100
+    /// * `lossless` - bool - ignores the quality
+    ///
+    /// Usage: ```RequestedImage::with_width(&config.roots(), &path, width).with_quality(Some(50), false);```
+    pub fn with_quality(mut self, quality: Option<u8>, lossless: bool) -> Self {
+        self.lossless = lossless;
+        self.quality = quality.filter(|_| !lossless);
+
+        if lossless {
+            self.push_suffix("lossless");
+        } else if let Some(quality) = self.quality {
+            self.push_suffix(&format!("q{}", quality));
+        }
+
+        self
+    }
+
+    /// Assigns the qualities that jpegs and lossy webps are encoded with when no quality was
+    /// requested, which aren't appended to the new pathname
+    ///
+    /// Arguments:
+    ///
+    /// * `jpeg_quality` - u8 - from 1 to 100
+    /// * `webp_quality` - u8 - from 1 to 100
+    ///
+    /// Usage: ```RequestedImage::with_width(&config.roots(), &path, width).with_default_quality(config.jpeg_quality, config.webp_quality);```
+    pub fn with_default_quality(mut self, jpeg_quality: u8, webp_quality: u8) -> Self {
+        self.jpeg_quality = jpeg_quality;
+        self.webp_quality = webp_quality;
+
+        self
+    }
+
+    /// Assigns the resampling filters that an image is reduced and enlarged with when no filter
+    /// was requested, which aren't appended to the new pathname
+    ///
+    /// Arguments:
+    ///
+    /// * `downscale_filter` - FilterType
+    /// * `upscale_filter` - FilterType
+    ///
+    /// Usage: ```RequestedImage::with_width(&config.roots(), &path, width).with_default_filters(config.downscale_filter, config.upscale_filter);```
+    pub fn with_default_filters(
+        mut self,
+        downscale_filter: FilterType,
+        upscale_filter: FilterType,
+    ) -> Self {
+        self.downscale_filter = downscale_filter;
+        self.upscale_filter = upscale_filter;
+
+        self
+    }
+
+    /// Assigns a hash of the original image's contents when content addressing is enabled,
+    /// which is appended to the new pathname (filename_<suffix>_<hash>.ext) so that replacing
+    /// the original image produces a new variant. The original image is read and hashed on a
+    /// blocking thread, as it's read in full
+    ///
+    /// Arguments:
+    ///
+    /// * `content_addressed` - bool
+    ///
+    /// Usage: ```RequestedImage::with_width(&config.roots(), &path, width).with_content_hash(config.content_addressed).await;```
+    pub async fn with_content_hash(mut self, content_addressed: bool) -> Self {
+        if !content_addressed {
+            return self;
+        }
+
+        // a missing original image is handled when it's fetched
+        let hash = match self
+            .spawn_blocking(RequestedImage::content_hash_blocking)
+            .await
+        {
+            Ok(hash) => hash,
+            Err(_) => return self,
+        };
+
+        // the original image is read from its own path, so only its cache key includes the hash
+        if !self.is_original() {
+            self.push_suffix(&hash);
+        }
+        self.content_hash = Some(hash);
+
+        self
+    }
+
+    /// Hashes the contents of the original image, blocking the current thread while it's read
+    ///
+    /// Arguments: (none)
+    ///
+    /// Usage: ```self.spawn_blocking(RequestedImage::content_hash_blocking).await;```
+    fn content_hash_blocking(&self) -> Result<String, String> {
+        let contents =
+            fs::read(&self.path).map_err(|reason| format!("Unable to open image: {}", reason))?;
+
+        // a truncated SHA-256 digest stays the same across builds, unlike std's hashers, so
+        // the variants named after it outlive an upgrade
+        Ok(Sha256::digest(&contents)[..CONTENT_HASH_BYTES]
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect())
+    }
+
+    /// Moves the new pathname of a resized image out of the static directory's variants and
+    /// into the cache directory (<cachedir>/filename_<suffix>.ext), so that resized images are
+    /// never written into the static directories
+    ///
+    /// Arguments:
+    ///
+    /// * `cache_dir` - Option<&Path>
+    ///
+    /// Usage: ```RequestedImage::with_width(&config.roots(), &path, width).with_cache_dir(config.cache_dir.as_deref());```
+    pub fn with_cache_dir(mut self, cache_dir: Option<&Path>) -> Self {
+        // the original image is always read from its static directory
+        let cache_dir = match cache_dir {
+            Some(cache_dir) if !self.is_original() => cache_dir,
+            _ => return self,
+        };
+
+        let variants_dir = self.root.join(VARIANTS_DIR);
+        if let Ok(relative) = self.new_pathname_buf.strip_prefix(variants_dir) {
+            let pathname = cache_dir.join(relative);
+
+            self.new_pathname = get_string_path(&pathname);
+            self.new_pathname_buf = pathname;
+        }
+
+        self
+    }
+
+    /// Retrieves the beginning of the pathname that every variant of the original image shares,
+    /// within either the static directory's variants or the cache directory
+    /// => <rootdir>/.variants/<dirs>/filename_
+    ///
+    /// Arguments:
+    ///
+    /// * `cache_dir` - Option<&Path>
+    ///
+    /// Usage: ```RequestedImage::new(&config.roots(), &path, 0).variant_prefix(config.cache_dir.as_deref());```
+    pub fn variant_prefix(&self, cache_dir: Option<&Path>) -> String {
+        let stem = self
+            .path
+            .file_stem()
+            .and_then(OsStr::to_str)
+            .unwrap_or_default();
+
+        let variants_dir = variants_dir(&self.root, &self.path);
+        let dir = match cache_dir {
+            Some(cache_dir) => match variants_dir.strip_prefix(self.root.join(VARIANTS_DIR)) {
+                Ok(relative) => cache_dir.join(relative),
+                Err(_) => cache_dir.to_path_buf(),
+            },
+            None => variants_dir,
+        };
+
+        get_string_path(dir.join(format!("{}_", stem)))
+    }
+
+    /// Retrieves the key of the requested image within a cache, which is its new pathname
+    /// followed by the content hash for an original image => filename.ext#<hash>
+    ///
+    /// Arguments: (none)
+    ///
+    /// Usage: ```req_image.cache_key();```
+    pub fn cache_key(&self) -> String {
+        match &self.content_hash {
+            Some(hash) if self.is_original() => format!("{}#{}", self.new_pathname, hash),
+            _ => self.new_pathname.clone(),
+        }
+    }
+
+    /// Appends a suffix to the stem of the new pathname => filename_<suffix>.ext
+    ///
+    /// Arguments:
+    ///
+    /// * `suffix` - &str
+    ///
+    /// Usage: ```self.push_suffix(suffix);```
+    fn push_suffix(&mut self, suffix: &str) {
+        self.make_variant();
+
+        let stem = self
+            .new_pathname_buf
+            .file_stem()
+            .and_then(OsStr::to_str)
+            .expect("Image is missing stem");
+
+        let ext = image_extension(&self.new_pathname_buf).expect("Image is missing extension");
+
+        let pathname = self
+            .new_pathname_buf
+            .with_file_name(format!("{}_{}.{}", stem, suffix, ext));
+
+        self.new_pathname = get_string_path(&pathname);
+        self.new_pathname_buf = pathname;
+    }
+
+    /// Moves the new pathname of an original image into the variants directory, before it's
+    /// given a suffix or extension => <rootdir>/.variants/<dirs>/filename.ext, where the extension
+    /// is normalized just as it is for every other variant
+    ///
+    /// Arguments: (none)
+    ///
+    /// Usage: ```self.make_variant();```
+    fn make_variant(&mut self) {
+        if !self.is_original() {
+            return;
+        }
+
+        let stem = self.path.file_stem().and_then(OsStr::to_str);
+        if let (Some(stem), Some(ext)) = (stem, image_extension(&self.path)) {
+            self.new_pathname_buf =
+                variants_dir(&self.root, &self.path).join(format!("{}.{}", stem, ext));
+            self.new_pathname = get_string_path(&self.new_pathname_buf);
         }
     }
 
@@ -79,28 +581,537 @@ impl<'p, 'r> RequestedImage {
         self.new_pathname_buf.is_file()
     }
 
-    /// Saves a new image to disk with the provided resized ratio of the requested image
+    /// Determines if the original image is empty, which an interrupted upload leaves behind,
+    /// so that it's rejected rather than decoded or served as is
+    ///
+    /// Arguments: (none)
+    ///
+    /// Usage: ```req_image.is_empty();```
+    pub fn is_empty(&self) -> bool {
+        fs::metadata(&self.path).is_ok_and(|meta| meta.len() == 0)
+    }
+
+    /// Determines if the requested image is the original image, which doesn't need resizing
+    ///
+    /// Arguments: (none)
+    ///
+    /// Usage: ```req_image.is_original();```
+    pub fn is_original(&self) -> bool {
+        self.new_pathname_buf == self.path
+    }
+
+    /// Determines if the original image was modified after the requested image was saved
+    ///
+    /// Arguments: (none)
+    ///
+    /// Usage: ```req_image.is_stale();```
+    pub fn is_stale(&self) -> bool {
+        // the original image can't be stale with itself
+        if self.is_original() {
+            return false;
+        }
+
+        let modified = |path: &Path| fs::metadata(path).and_then(|meta| meta.modified()).ok();
+
+        match (modified(&self.path), modified(&self.new_pathname_buf)) {
+            (Some(original), Some(requested)) => original > requested,
+            _ => false,
+        }
+    }
+
+    /// Decodes the original image, whose format is guessed from its contents so that an
+    /// extension the image crate doesn't know of (e.g. `.jfif`) can still be opened
+    ///
+    /// Arguments: (none)
+    ///
+    /// Usage: ```req_image.open()?;```
+    fn open(&self) -> Result<DynamicImage, String> {
+        let reader = Reader::open(&self.path)
+            .and_then(Reader::with_guessed_format)
+            .map_err(|reason| describe_decode_error(ImageError::IoError(reason)))?;
+
+        // the image crate can only decode lossy webps without transparency
+        if reader.format() == Some(ImageFormat::WebP) {
+            let contents = fs::read(&self.path)
+                .map_err(|reason| describe_decode_error(ImageError::IoError(reason)))?;
+
+            return decode_webp(&contents);
+        }
+
+        reader.decode().map_err(describe_decode_error)
+    }
+
+    /// Reads the width of the original image without decoding it
+    ///
+    /// Arguments: (none)
+    ///
+    /// Usage: ```req_image.original_width();```
+    pub fn original_width(&self) -> Option<u32> {
+        Reader::open(&self.path)
+            .and_then(Reader::with_guessed_format)
+            .ok()
+            .and_then(|reader| read_dimensions(&self.path, reader).ok())
+            .map(|(width, ..)| width)
+    }
+
+    /// Calculates the width of the new image from the width of the original image
+    ///
+    /// Arguments:
+    ///
+    /// * `original_width` - u32
+    ///
+    /// Usage: ```req_image.target_width(width);```
+    fn target_width(&self, original_width: u32) -> u32 {
+        match self.width {
+            Some(width) => width,
+            // the original image is only re-encoded
+            None if self.ratio == 0 => original_width,
+            None => original_width * self.ratio as u32 / 100,
+        }
+    }
+
+    /// Computes the dimensions that the requested image is resized to from the original
+    /// image's header, without decoding or resizing it, which preserve its aspect ratio just
+    /// as resizing it does
+    ///
+    /// Arguments: (none)
+    ///
+    /// Returns: `Result<(u32, u32), String>`
+    ///
+    /// Usage: ```req_image.target_dimensions()?;```
+    pub fn target_dimensions(&self) -> Result<(u32, u32), String> {
+        let reader = Reader::open(&self.path)
+            .and_then(Reader::with_guessed_format)
+            .map_err(|reason| format!("Unable to open image: {}", reason))?;
+
+        let (width, height) = read_dimensions(&self.path, reader)
+            .map_err(|reason| format!("Unable to read image dimensions: {}", reason))?;
+
+        // the image crate rounds the height down, but never below a pixel
+        let new_image_width = self.target_width(width);
+        let new_image_height =
+            (height as u64 * new_image_width as u64 / width.max(1) as u64).max(1);
+
+        Ok((new_image_width, new_image_height as u32))
+    }
+
+    /// Selects the resampling filter for resizing an image to a new width, unless one was
+    /// requested: the upscale filter when it's enlarged, otherwise the faster filter below
+    /// `fast_filter_width` and the downscale filter at or above it
+    ///
+    /// Arguments:
+    ///
+    /// * `width` - u32 - the original image's width
+    /// * `new_image_width` - u32
+    ///
+    /// Returns: `FilterType`
+    ///
+    /// Usage: ```req_image.resample_filter(width, new_image_width);```
+    pub fn resample_filter(&self, width: u32, new_image_width: u32) -> FilterType {
+        match self.filter {
+            Some(filter) => filter,
+            None if new_image_width > width => self.upscale_filter,
+            None if new_image_width < self.fast_filter_width => FAST_FILTER,
+            None => self.downscale_filter,
+        }
+    }
+
+    /// Saves a new image to disk with the provided resized ratio or width of the requested
+    /// image, on a blocking thread so the async runtime isn't held up by the resize
+    ///
+    /// Arguments: (none)
+    ///
+    /// Usage: ```req_image.save().await;```
+    pub async fn save(&self) -> Result<(), String> {
+        self.spawn_blocking(RequestedImage::save_blocking).await
+    }
+
+    /// Resizes the requested image to the provided ratio or width and encodes it in memory,
+    /// without touching the disk, on a blocking thread so the async runtime isn't held up by
+    /// the resize
+    ///
+    /// Arguments: (none)
+    ///
+    /// Usage: ```req_image.encode().await;```
+    pub async fn encode(&self) -> Result<Vec<u8>, String> {
+        self.spawn_blocking(RequestedImage::encode_blocking).await
+    }
+
+    /// Runs CPU-bound work on a copy of the requested image within tokio's blocking thread
+    /// pool, rather than on the async worker that's handling the request. Work that's dropped
+    /// while it waits for a blocking thread is skipped, while work that's already running can't
+    /// be interrupted, so it's left to finish
+    ///
+    /// Arguments:
+    ///
+    /// * `work` - fn(&RequestedImage) -> Result<T, String>
+    ///
+    /// Usage: ```self.spawn_blocking(RequestedImage::save_blocking).await;```
+    async fn spawn_blocking<T: Send + 'static>(
+        &self,
+        work: fn(&RequestedImage) -> Result<T, String>,
+    ) -> Result<T, String> {
+        let req_image = self.clone();
+        // held for as long as the work is awaited, so the work can tell if it's been dropped
+        let waiting = Arc::new(());
+        let awaited = Arc::downgrade(&waiting);
+
+        let result = task::spawn_blocking(move || match awaited.upgrade() {
+            Some(_) => work(&req_image),
+            None => Err("The resize was dropped before it started.".to_string()),
+        })
+        .await;
+        drop(waiting);
+
+        result.map_err(|reason| format!("Unable to resize image: {}", reason))?
+    }
+
+    /// Saves a new image to disk, blocking the current thread while it's resized
     ///
     /// Arguments: (none)
     ///
-    /// Usage: ```req_image.save();```
-    pub fn save(&self) -> Result<(), String> {
+    /// Usage: ```req_image.save_blocking();```
+    fn save_blocking(&self) -> Result<(), String> {
+        let contents = self.encode_blocking()?;
+
+        // the cache directory may not exist yet
+        if let Some(parent) = self.new_pathname_buf.parent() {
+            fs::create_dir_all(parent)
+                .map_err(|reason| format!("Unable to create image directory: {}", reason))?;
+        }
+
+        fs::write(&self.new_pathname, contents)
+            .map_err(|reason| format!("Unable to save image: {}", reason))
+    }
+
+    /// Resizes the requested image and encodes it in memory, blocking the current thread while
+    /// it's resized
+    ///
+    /// Arguments: (none)
+    ///
+    /// Usage: ```req_image.encode_blocking();```
+    fn encode_blocking(&self) -> Result<Vec<u8>, String> {
+        // animated gifs need to be resized frame by frame
+        if self.content_type == Some(ContentType::GIF) {
+            let frames = self.read_gif_frames()?;
+
+            if frames.len() > 1 {
+                return self.encode_gif_frames(frames);
+            }
+        }
+
         // open original image
-        let original_image = image::open(&self.path).expect("Failed to open image.");
+        let original_image = self.open()?;
 
         // pull out width from read image
         let (width, ..) = original_image.dimensions();
 
-        // calculate new image width based on ratio
-        let new_image_width = (width * self.ratio as u32 / 100) as u32;
+        // calculate new image width based on ratio or width
+        let new_image_width = self.target_width(width);
+
+        // determine the output format from the new image's extension
+        let format = ImageFormat::from_path(&self.new_pathname)
+            .map_err(|reason| format!("Unable to determine image format: {}", reason))?;
+
+        // resize (preserving its aspect ratio) and encode it as the requested ratio or width
+        let new_image = self.apply_effects(original_image.resize(
+            new_image_width,
+            u32::MAX,
+            self.resample_filter(width, new_image_width),
+        ));
+
+        // jpegs have no alpha channel, so transparency is flattened onto the background
+        let new_image = match self.background {
+            Some(background) => flatten_image(new_image, background),
+            None => new_image,
+        };
+
+        let quality = self.quality.unwrap_or(match format {
+            ImageFormat::WebP => self.webp_quality,
+            _ => self.jpeg_quality,
+        });
+        match format {
+            ImageFormat::WebP => return encode_webp(&new_image, self.lossless, quality),
+            ImageFormat::Jpeg if self.progressive => {
+                return encode_progressive_jpeg(&new_image, quality)
+            }
+            _ => (),
+        }
+
+        let mut contents = Vec::new();
+        match format {
+            ImageFormat::Jpeg => JpegEncoder::new_with_quality(&mut contents, quality)
+                .encode_image(&new_image)
+                .map_err(|reason| format!("Failed to resize image: {}", reason))?,
+            _ => new_image
+                .write_to(&mut contents, format)
+                .map_err(|reason| format!("Failed to resize image: {}", reason))?,
+        }
+
+        Ok(contents)
+    }
+
+    /// Applies the requested grayscale and tint to a resized image, preserving whether it has
+    /// an alpha channel
+    ///
+    /// Arguments:
+    ///
+    /// * `image` - DynamicImage
+    ///
+    /// Usage: ```self.apply_effects(image);```
+    fn apply_effects(&self, image: DynamicImage) -> DynamicImage {
+        if self.tint.is_none() && !self.grayscale {
+            return image;
+        }
 
-        // resize and save it as the requested ratio
-        original_image
-            .resize(new_image_width, new_image_width, FilterType::CatmullRom)
-            .save(self.new_pathname.to_string())
-            .expect("Failed to resize image.");
+        let mut buffer = image.to_rgba8();
+        self.apply_pixel_effects(&mut buffer);
 
-        Ok(())
+        match image.color().has_alpha() {
+            true => DynamicImage::ImageRgba8(buffer),
+            false => DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(buffer).to_rgb8()),
+        }
+    }
+
+    /// Converts every pixel to grayscale and blends it toward the tint, if requested
+    ///
+    /// Arguments:
+    ///
+    /// * `buffer` - &mut RgbaImage
+    ///
+    /// Usage: ```self.apply_pixel_effects(&mut buffer);```
+    fn apply_pixel_effects(&self, buffer: &mut RgbaImage) {
+        if self.tint.is_none() && !self.grayscale {
+            return;
+        }
+
+        for pixel in buffer.pixels_mut() {
+            if self.grayscale {
+                let [r, g, b, _] = pixel.0;
+                let luma = (0.299 * r as f32 + 0.587 * g as f32 + 0.114 * b as f32) as u8;
+                pixel.0[..3].fill(luma);
+            }
+
+            if let Some(tint) = self.tint {
+                for (channel, tint) in pixel.0.iter_mut().zip(tint.iter()) {
+                    *channel = (*channel as f32 * (1.0 - TINT_STRENGTH)
+                        + *tint as f32 * TINT_STRENGTH) as u8;
+                }
+            }
+        }
+    }
+
+    /// Resizes the requested image to a tiny, blurred and heavily compressed jpeg, on a
+    /// blocking thread so the async runtime isn't held up by the resize
+    ///
+    /// Arguments: (none)
+    ///
+    /// Usage: ```req_image.encode_lqip().await;```
+    pub async fn encode_lqip(&self) -> Result<Vec<u8>, String> {
+        self.spawn_blocking(RequestedImage::encode_lqip_blocking)
+            .await
+    }
+
+    /// Resizes the requested image to a tiny, blurred and heavily compressed jpeg, blocking the
+    /// current thread while it's resized
+    ///
+    /// Arguments: (none)
+    ///
+    /// Usage: ```req_image.encode_lqip_blocking();```
+    fn encode_lqip_blocking(&self) -> Result<Vec<u8>, String> {
+        // open original image
+        let original_image = self.open()?;
+
+        let placeholder = original_image
+            .resize(LQIP_WIDTH, u32::MAX, FilterType::Triangle)
+            .blur(LQIP_BLUR)
+            .to_rgb8();
+
+        let mut contents = Vec::new();
+        JpegEncoder::new_with_quality(&mut contents, LQIP_QUALITY)
+            .encode_image(&placeholder)
+            .map_err(|reason| format!("Failed to encode placeholder: {}", reason))?;
+
+        Ok(contents)
+    }
+
+    /// Calculates the average color of the original image, on a blocking thread so the async
+    /// runtime isn't held up while it's decoded
+    ///
+    /// Arguments: (none)
+    ///
+    /// Usage: ```req_image.average_color().await?;```
+    pub async fn average_color(&self) -> Result<Color, String> {
+        self.spawn_blocking(RequestedImage::average_color_blocking)
+            .await
+    }
+
+    /// Calculates the average color of the original image, blocking the current thread while
+    /// it's decoded
+    ///
+    /// Arguments: (none)
+    ///
+    /// Usage: ```req_image.average_color_blocking()?;```
+    fn average_color_blocking(&self) -> Result<Color, String> {
+        // open original image
+        let original_image = self.open()?;
+
+        // sample a reduced image rather than every pixel of the original
+        let sample = original_image
+            .thumbnail(COLOR_SAMPLE_SIZE, COLOR_SAMPLE_SIZE)
+            .to_rgb8();
+
+        let mut totals = [0u64; 3];
+        for pixel in sample.pixels() {
+            for (total, channel) in totals.iter_mut().zip(pixel.0.iter()) {
+                *total += *channel as u64;
+            }
+        }
+
+        let count = (sample.width() as u64 * sample.height() as u64).max(1);
+        let [r, g, b] = totals.map(|total| (total / count) as u8);
+
+        Ok(Color {
+            r,
+            g,
+            b,
+            hex: format!("#{:02x}{:02x}{:02x}", r, g, b),
+        })
+    }
+
+    /// Computes a blurhash placeholder of the original image from a reduced copy of it, on a
+    /// blocking thread so the async runtime isn't held up while it's decoded
+    ///
+    /// Arguments: (none)
+    ///
+    /// Usage: ```req_image.blurhash().await;```
+    pub async fn blurhash(&self) -> Result<String, String> {
+        self.spawn_blocking(RequestedImage::blurhash_blocking).await
+    }
+
+    /// Computes a blurhash placeholder of the original image from a reduced copy of it,
+    /// blocking the current thread while it's decoded
+    ///
+    /// Arguments: (none)
+    ///
+    /// Usage: ```req_image.blurhash_blocking();```
+    fn blurhash_blocking(&self) -> Result<String, String> {
+        let original_image = self.open()?;
+
+        // a blurhash only captures a few components, so every pixel isn't needed
+        let sample = original_image
+            .thumbnail(BLURHASH_SAMPLE_SIZE, BLURHASH_SAMPLE_SIZE)
+            .to_rgba8();
+
+        let (components_x, components_y) = BLURHASH_COMPONENTS;
+        blurhash::encode(
+            components_x,
+            components_y,
+            sample.width(),
+            sample.height(),
+            sample.as_raw(),
+        )
+        .map_err(|reason| format!("Unable to compute blurhash: {}", reason))
+    }
+
+    /// Reads the dimensions and format of the original image from its header, without
+    /// decoding it, on a blocking thread so the async runtime isn't held up while it's read
+    ///
+    /// Arguments: (none)
+    ///
+    /// Usage: ```req_image.metadata().await;```
+    pub async fn metadata(&self) -> Result<Metadata, String> {
+        self.spawn_blocking(RequestedImage::metadata_blocking).await
+    }
+
+    /// Reads the dimensions and format of the original image from its header, without
+    /// decoding it, blocking the current thread while it's read
+    ///
+    /// Arguments: (none)
+    ///
+    /// Usage: ```req_image.metadata_blocking();```
+    fn metadata_blocking(&self) -> Result<Metadata, String> {
+        let bytes = fs::metadata(&self.path)
+            .map_err(|reason| format!("Unable to open image: {}", reason))?
+            .len();
+
+        let reader = Reader::open(&self.path)
+            .and_then(Reader::with_guessed_format)
+            .map_err(|reason| format!("Unable to open image: {}", reason))?;
+
+        let format = reader
+            .format()
+            .map(|format| format!("{:?}", format).to_lowercase())
+            .ok_or_else(|| "Unable to determine image format.".to_string())?;
+
+        let (width, height) = read_dimensions(&self.path, reader)
+            .map_err(|reason| format!("Unable to read image dimensions: {}", reason))?;
+
+        Ok(Metadata {
+            width,
+            height,
+            format,
+            bytes,
+        })
+    }
+
+    /// Decodes every frame of the requested gif
+    ///
+    /// Arguments: (none)
+    ///
+    /// Usage: ```req_image.read_gif_frames();```
+    fn read_gif_frames(&self) -> Result<Vec<Frame>, String> {
+        let file = fs::File::open(&self.path)
+            .map_err(|reason| format!("Unable to open image: {}", reason))?;
+
+        GifDecoder::new(BufReader::new(file))
+            .and_then(|decoder| decoder.into_frames().collect_frames())
+            .map_err(describe_decode_error)
+    }
+
+    /// Encodes a new animated gif with every frame resized to the provided ratio or width,
+    /// preserving each frame's delay
+    ///
+    /// Arguments:
+    ///
+    /// * `frames` - Vec<Frame>
+    ///
+    /// Usage: ```req_image.encode_gif_frames(frames);```
+    fn encode_gif_frames(&self, frames: Vec<Frame>) -> Result<Vec<u8>, String> {
+        // frames are decoded onto the full canvas, so the first frame has the gif's width
+        let width = frames[0].buffer().width();
+        let new_image_width = self.target_width(width);
+        let scale = |value: u32| (value as u64 * new_image_width as u64 / width as u64) as u32;
+        let filter = self.resample_filter(width, new_image_width);
+
+        let resized_frames = frames.into_iter().map(|frame| {
+            let (width, height) = frame.buffer().dimensions();
+            let mut buffer = imageops::resize(
+                frame.buffer(),
+                scale(width).max(1),
+                scale(height).max(1),
+                filter,
+            );
+            self.apply_pixel_effects(&mut buffer);
+
+            Frame::from_parts(
+                buffer,
+                scale(frame.left()),
+                scale(frame.top()),
+                frame.delay(),
+            )
+        });
+
+        let mut contents = Vec::new();
+        {
+            let mut encoder = GifEncoder::new(&mut contents);
+            encoder
+                .set_repeat(Repeat::Infinite)
+                .and_then(|()| encoder.encode_frames(resized_frames))
+                .map_err(|reason| format!("Failed to resize gif: {}", reason))?;
+        }
+
+        Ok(contents)
     }
 
     /// Asynchronously reads the requested image and returns its contents as `Vec<u8>`
@@ -109,7 +1120,6 @@ impl<'p, 'r> RequestedImage {
     ///
     /// Usage: ```req_image.read();```
     pub async fn read(&self) -> Result<Vec<u8>, String> {
-        // TODO - Make sure requested image size doesn'p extend beyond actual image dimensions
         // open requested image
         let mut existing_file = match File::open(&self.new_pathname).await {
             Ok(file) => file,
@@ -131,3 +1141,240 @@ impl<'p, 'r> RequestedImage {
         Ok(contents)
     }
 }
+
+/// Retrieves the directory that the variants of an image are saved into, which mirrors the
+/// image's directories within its static directory, so images that share a name in different
+/// directories never share variants => <rootdir>/.variants/gallery/2023
+///
+/// Arguments:
+///
+/// * `root` - &Path
+/// * `filepath` - &Path - the original image within `root`
+///
+/// Returns: `PathBuf`
+///
+/// Usage: ```variants_dir(root, &filepath);```
+fn variants_dir(root: &Path, filepath: &Path) -> PathBuf {
+    let dirs = filepath
+        .strip_prefix(root)
+        .ok()
+        .and_then(Path::parent)
+        .unwrap_or_else(|| Path::new(""));
+
+    root.join(VARIANTS_DIR).join(dirs)
+}
+
+/// Describes why an image couldn't be decoded, distinguishing an image whose format isn't
+/// supported from an image whose data is corrupt or truncated, and from an image that
+/// couldn't be read at all
+///
+/// Arguments:
+///
+/// * `reason` - ImageError
+///
+/// Returns: `String`
+///
+/// Usage: ```Reader::open(&path)?.decode().map_err(describe_decode_error);```
+fn describe_decode_error(reason: ImageError) -> String {
+    match reason {
+        ImageError::Unsupported(reason) => {
+            format!("The image's format is unsupported: {}", reason)
+        }
+        ImageError::Decoding(reason) => {
+            format!("The image's data is corrupt or truncated: {}", reason)
+        }
+        // decoders read past the end of a truncated image's data
+        ImageError::IoError(reason) if reason.kind() == io::ErrorKind::UnexpectedEof => {
+            format!("The image's data is corrupt or truncated: {}", reason)
+        }
+        ImageError::IoError(reason) => format!("Unable to read the image: {}", reason),
+        ImageError::Limits(reason) => format!("The image is too large to decode: {}", reason),
+        reason => format!("Unable to open image: {}", reason),
+    }
+}
+
+/// Reads the dimensions of an image without decoding it, where a webp's are read by libwebp,
+/// as the image crate can't read a lossless or transparent webp's
+///
+/// Arguments:
+///
+/// * `path` - &Path
+/// * `reader` - Reader<BufReader<fs::File>> - whose format was guessed from the image
+///
+/// Returns: `Result<(u32, u32), String>`
+///
+/// Usage: ```read_dimensions(&self.path, reader)?;```
+fn read_dimensions(path: &Path, reader: Reader<BufReader<fs::File>>) -> Result<(u32, u32), String> {
+    if reader.format() == Some(ImageFormat::WebP) {
+        return fs::read(path)
+            .map_err(|reason| reason.to_string())
+            .and_then(|contents| {
+                webp_dimensions(&contents).ok_or_else(|| "the webp's header is invalid".to_string())
+            });
+    }
+
+    reader
+        .into_dimensions()
+        .map_err(|reason| reason.to_string())
+}
+
+/// Reads the dimensions of an encoded image from its header, without decoding it, or `None`
+/// for contents that aren't a raster image (such as an svg)
+///
+/// Arguments:
+///
+/// * `contents` - &[u8]
+///
+/// Returns: `Option<(u32, u32)>`
+///
+/// Usage: ```encoded_dimensions(&contents);```
+pub fn encoded_dimensions(contents: &[u8]) -> Option<(u32, u32)> {
+    let reader = Reader::new(Cursor::new(contents))
+        .with_guessed_format()
+        .ok()?;
+
+    match reader.format()? {
+        ImageFormat::WebP => webp_dimensions(contents),
+        _ => reader.into_dimensions().ok(),
+    }
+}
+
+/// Reads the dimensions of a webp from its header with libwebp
+///
+/// Arguments:
+///
+/// * `contents` - &[u8]
+///
+/// Returns: `Option<(u32, u32)>`
+///
+/// Usage: ```webp_dimensions(&contents);```
+fn webp_dimensions(contents: &[u8]) -> Option<(u32, u32)> {
+    webp::BitstreamFeatures::new(contents).map(|features| (features.width(), features.height()))
+}
+
+/// Decodes a lossy or lossless webp with libwebp, which (unlike the image crate) can decode
+/// one with an alpha channel
+///
+/// Arguments:
+///
+/// * `contents` - &[u8]
+///
+/// Returns: `Result<DynamicImage, String>`
+///
+/// Usage: ```decode_webp(&contents)?;```
+fn decode_webp(contents: &[u8]) -> Result<DynamicImage, String> {
+    let features = webp::BitstreamFeatures::new(contents).ok_or_else(|| {
+        "The image's data is corrupt or truncated: the webp's header is invalid".to_string()
+    })?;
+    if features.has_animation() {
+        return Err(
+            "The image's format is unsupported: animated webps can't be decoded".to_string(),
+        );
+    }
+
+    let webp = webp::Decoder::new(contents).decode().ok_or_else(|| {
+        "The image's data is corrupt or truncated: the webp couldn't be decoded".to_string()
+    })?;
+    let (width, height) = (webp.width(), webp.height());
+
+    let image = match webp.is_alpha() {
+        true => RgbaImage::from_raw(width, height, webp.to_vec()).map(DynamicImage::ImageRgba8),
+        false => RgbImage::from_raw(width, height, webp.to_vec()).map(DynamicImage::ImageRgb8),
+    };
+
+    image.ok_or_else(|| {
+        "The image's data is corrupt or truncated: the webp is too short".to_string()
+    })
+}
+
+/// Encodes an image as a progressive jpeg, which the image crate's jpeg encoder can't write
+///
+/// Arguments:
+///
+/// * `image` - &DynamicImage
+/// * `quality` - u8
+///
+/// Usage: ```encode_progressive_jpeg(&image, quality);```
+fn encode_progressive_jpeg(image: &DynamicImage, quality: u8) -> Result<Vec<u8>, String> {
+    let buffer = image.to_rgb8();
+    let (width, height) = (
+        u16::try_from(buffer.width()),
+        u16::try_from(buffer.height()),
+    );
+    let (width, height) = match (width, height) {
+        (Ok(width), Ok(height)) => (width, height),
+        _ => return Err("The image is too large to encode as a progressive jpeg.".to_string()),
+    };
+
+    let mut contents = Vec::new();
+    let mut encoder = jpeg_encoder::Encoder::new(&mut contents, quality);
+    encoder.set_progressive(true);
+    encoder
+        .encode(buffer.as_raw(), width, height, jpeg_encoder::ColorType::Rgb)
+        .map_err(|reason| format!("Failed to encode progressive jpeg: {}", reason))?;
+
+    Ok(contents)
+}
+
+/// Encodes an image as a lossy webp of a quality, or a lossless webp, which the image crate
+/// can't write
+///
+/// Arguments:
+///
+/// * `image` - &DynamicImage
+/// * `lossless` - bool
+/// * `quality` - u8 - ignored when lossless
+///
+/// Usage: ```encode_webp(&image, false, quality);```
+fn encode_webp(image: &DynamicImage, lossless: bool, quality: u8) -> Result<Vec<u8>, String> {
+    let (width, height) = image.dimensions();
+
+    // an image without an alpha channel is encoded without one
+    let contents = match image.color().has_alpha() {
+        true => {
+            let buffer = image.to_rgba8();
+            webp::Encoder::from_rgba(buffer.as_raw(), width, height)
+                .encode_simple(lossless, quality as f32)
+        }
+        false => {
+            let buffer = image.to_rgb8();
+            webp::Encoder::from_rgb(buffer.as_raw(), width, height)
+                .encode_simple(lossless, quality as f32)
+        }
+    };
+
+    contents
+        .map(|contents| contents.to_vec())
+        .map_err(|reason| format!("Failed to encode webp: {:?}", reason))
+}
+
+/// Composites an image with an alpha channel onto an opaque background color
+///
+/// Arguments:
+///
+/// * `image` - DynamicImage
+/// * `background` - [u8; 3]
+///
+/// Usage: ```flatten_image(image, background);```
+fn flatten_image(image: DynamicImage, background: [u8; 3]) -> DynamicImage {
+    if !image.color().has_alpha() {
+        return image;
+    }
+
+    let buffer = image.to_rgba8();
+    let flattened = RgbImage::from_fn(buffer.width(), buffer.height(), |x, y| {
+        let [r, g, b, a] = buffer.get_pixel(x, y).0;
+        let alpha = a as f32 / 255.0;
+        let blend = |channel: u8, background: u8| {
+            (channel as f32 * alpha + background as f32 * (1.0 - alpha)).round() as u8
+        };
+
+        Rgb([
+            blend(r, background[0]),
+            blend(g, background[1]),
+            blend(b, background[2]),
+        ])
+    });
+
+    DynamicImage::ImageRgb8(flattened)
+}
@@ -0,0 +1,150 @@
+use rocket::fairing::AdHoc;
+use rocket::http::Header;
+use rocket::outcome::Outcome;
+use rocket::request::{self, FromRequest, Request};
+use std::convert::Infallible;
+use std::future::Future;
+use uuid::Uuid;
+
+/// Header that a request's correlation ID is read from and echoed back in.
+const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+/// Longest correlation ID that's propagated from a request, rather than replaced.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+rocket::tokio::task_local! {
+    /// Correlation ID of the request that the current task is handling.
+    static CURRENT: String;
+}
+
+/// Logs an indented info line, prefixed with the correlation ID of the request that's being
+/// handled, if any.
+macro_rules! request_info {
+    ($($arg:tt)*) => {
+        info_!("{}{}", $crate::requestid::log_prefix(), format_args!($($arg)*))
+    };
+}
+
+/// Logs an indented warning line, prefixed with the correlation ID of the request that's
+/// being handled, if any.
+macro_rules! request_warn {
+    ($($arg:tt)*) => {
+        warn_!("{}{}", $crate::requestid::log_prefix(), format_args!($($arg)*))
+    };
+}
+
+/// The correlation ID of a request, which is propagated from its `X-Request-Id` header or
+/// generated when the header is absent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    /// Propagates a correlation ID from a header, so long as it's short and only contains
+    /// characters that are safe to log, or generates a new one.
+    ///
+    /// Arguments:
+    ///
+    /// * `header` - Option<&str>
+    ///
+    /// Returns: `RequestId`
+    ///
+    /// Usage: ```RequestId::from_header(req.headers().get_one("X-Request-Id"));```
+    pub fn from_header(header: Option<&str>) -> Self {
+        match header {
+            Some(id) if is_valid_request_id(id) => RequestId(id.to_string()),
+            _ => RequestId(Uuid::new_v4().to_string()),
+        }
+    }
+
+    /// Runs a future with this correlation ID, which prefixes the lines that it logs with
+    /// `request_info!` and `request_warn!`.
+    ///
+    /// Arguments:
+    ///
+    /// * `future` - impl Future
+    ///
+    /// Returns: `F::Output`
+    ///
+    /// Usage: ```request_id.scope(serve_requested_image(...)).await;```
+    pub async fn scope<F: Future>(self, future: F) -> F::Output {
+        CURRENT.scope(self.0, future).await
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RequestId {
+    type Error = Infallible;
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        Outcome::Success(get_request_id(req).clone())
+    }
+}
+
+/// Retrieves the correlation ID of a request, which is assigned once and then cached for the
+/// rest of the request.
+///
+/// Arguments:
+///
+/// * `req` - &Request
+///
+/// Returns: `&RequestId`
+///
+/// Usage: ```get_request_id(req);```
+fn get_request_id<'r>(req: &'r Request<'_>) -> &'r RequestId {
+    req.local_cache(|| RequestId::from_header(req.headers().get_one(REQUEST_ID_HEADER)))
+}
+
+/// Determines if a correlation ID can be propagated as is.
+///
+/// Arguments:
+///
+/// * `id` - &str
+///
+/// Returns: `bool`
+///
+/// Usage: ```is_valid_request_id(id);```
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LEN
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+/// Retrieves the prefix of a line logged while handling a request => `[<id>] `
+///
+/// Arguments: (none)
+///
+/// Returns: `String`
+///
+/// Usage: ```log_prefix();```
+pub fn log_prefix() -> String {
+    CURRENT
+        .try_with(|id| format!("[{}] ", id))
+        .unwrap_or_default()
+}
+
+/// Assigns every request a correlation ID, which is logged as the request arrives and echoed
+/// back in the `X-Request-Id` header of its response.
+///
+/// Arguments: (none)
+///
+/// Returns: `AdHoc`
+///
+/// Usage: ```rocket::build().attach(requestid::main());```
+pub fn main() -> AdHoc {
+    AdHoc::on_ignite("request id", |rocket| async {
+        rocket
+            .attach(AdHoc::on_request("request id", |req, _| {
+                Box::pin(async move {
+                    info_!("Request ID: {}", get_request_id(req).0);
+                })
+            }))
+            .attach(AdHoc::on_response("request id header", |req, res| {
+                Box::pin(async move {
+                    let RequestId(id) = get_request_id(req);
+                    res.set_header(Header::new(REQUEST_ID_HEADER, id.clone()));
+                })
+            }))
+    })
+}
@@ -0,0 +1,128 @@
+use rocket::http::uri::fmt::{FromUriParam, Path as UriPath};
+use rocket::http::uri::Segments;
+use rocket::request::FromSegments;
+use std::ffi::OsStr;
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+
+/// A trailing path segment that routes a request to an action on an image, for example:
+/// `/image/<path..>/srcset`.
+pub trait Action {
+    const SEGMENT: &'static str;
+}
+
+/// Responds with a `srcset` string for the image.
+pub struct Srcset;
+
+impl Action for Srcset {
+    const SEGMENT: &'static str = "srcset";
+}
+
+/// Responds with the average color of the image.
+pub struct DominantColor;
+
+impl Action for DominantColor {
+    const SEGMENT: &'static str = "color";
+}
+
+/// Responds with the dimensions, format and size of the image.
+pub struct Meta;
+
+impl Action for Meta {
+    const SEGMENT: &'static str = "meta";
+}
+
+/// Responds with a blurhash placeholder of the image.
+pub struct Blurhash;
+
+impl Action for Blurhash {
+    const SEGMENT: &'static str = "blurhash";
+}
+
+/// Responds with whether a request to resize the image is valid, without resizing it.
+pub struct Validate;
+
+impl Action for Validate {
+    const SEGMENT: &'static str = "validate";
+}
+
+/// Responds with every cached variant of the image.
+pub struct Variants;
+
+impl Action for Variants {
+    const SEGMENT: &'static str = "variants";
+}
+
+/// Trailing segments that are reserved for actions.
+const ACTIONS: [&str; 6] = [
+    Srcset::SEGMENT,
+    DominantColor::SEGMENT,
+    Meta::SEGMENT,
+    Blurhash::SEGMENT,
+    Validate::SEGMENT,
+    Variants::SEGMENT,
+];
+
+/// Retrieves the last segment of a path if it's reserved for an action.
+///
+/// Arguments:
+///
+/// * `path` - &Path
+///
+/// Returns: `Option<&str>`
+///
+/// Usage: ```get_action(&path);```
+fn get_action(path: &Path) -> Option<&str> {
+    path.file_name()
+        .and_then(OsStr::to_str)
+        .filter(|segment| ACTIONS.contains(segment))
+}
+
+/// An image path that doesn't end with an action's segment, which forwards action requests
+/// to their own routes.
+#[derive(Debug)]
+pub struct ImagePath(pub PathBuf);
+
+impl<'r> FromSegments<'r> for ImagePath {
+    type Error = &'static str;
+
+    fn from_segments(segments: Segments<'r, UriPath>) -> Result<Self, Self::Error> {
+        let path = PathBuf::from_segments(segments).map_err(|_| "The file path is invalid.")?;
+
+        match get_action(&path) {
+            Some(_) => Err("The file path is reserved for an action."),
+            None => Ok(ImagePath(path)),
+        }
+    }
+}
+
+impl<'a> FromUriParam<UriPath, &'a Path> for ImagePath {
+    type Target = &'a Path;
+
+    fn from_uri_param(path: &'a Path) -> Self::Target {
+        path
+    }
+}
+
+/// An image path that ends with the segment of action `A`, which is stripped from the path.
+#[derive(Debug)]
+pub struct ActionPath<A> {
+    pub path: PathBuf,
+    action: PhantomData<A>,
+}
+
+impl<'r, A: Action> FromSegments<'r> for ActionPath<A> {
+    type Error = &'static str;
+
+    fn from_segments(segments: Segments<'r, UriPath>) -> Result<Self, Self::Error> {
+        let path = PathBuf::from_segments(segments).map_err(|_| "The file path is invalid.")?;
+
+        match get_action(&path) {
+            Some(segment) if segment == A::SEGMENT => Ok(ActionPath {
+                path: path.parent().map(Path::to_path_buf).unwrap_or_default(),
+                action: PhantomData,
+            }),
+            _ => Err("The file path doesn't match the action."),
+        }
+    }
+}
@@ -1,98 +1,1930 @@
 // #![allow(dead_code, unused_variables)]
 
-use crate::lrucache::LRUCache;
-use crate::reqimage::RequestedImage;
-use crate::utils::{send_400_response, send_404_response, InvalidRequest};
-use futures_locks::Mutex;
+use crate::cachecontrol::{IfNoneMatch, NoCache};
+use crate::config::{Config, DiskMode, QualityPreset, WidthMode};
+use crate::precompressed::PrecompressedServer;
+use crate::query::{ImageQuery, Parsed, PrettyParams, Width};
+use crate::ratelimit::RateLimit;
+use crate::reqimage::{encoded_dimensions, Color, Metadata, RequestedImage, FILTERS};
+use crate::requestid::RequestId;
+use crate::segments::{
+    ActionPath, Blurhash, DominantColor, ImagePath, Meta, Srcset, Validate, Variants,
+};
+use crate::signature::{signed_url, SignedUrl};
+use crate::stats::Counts;
+use crate::utils::{
+    get_string_path, has_forbidden_characters, has_supported_extension, image_content_type,
+    image_extension, is_passthrough, parse_hex_color, send_304_response, send_400_response,
+    send_404_response, send_503_response, send_fallback_response, InvalidRequest,
+    SUPPORTED_EXTENSIONS,
+};
+use futures_locks::{Mutex, MutexGuard};
+use image::imageops::FilterType;
+use image::io::Reader;
+use image::ImageFormat;
+use lrucache::LRUCache;
 use rocket::fairing::AdHoc;
-use rocket::fs::{relative, FileServer};
+use rocket::http::{ContentType, Header};
 use rocket::response::content::Custom;
-use rocket::State;
-use std::path::PathBuf;
+use rocket::response::{self, Responder};
+use rocket::serde::json::{self, Json};
+use rocket::serde::Serialize;
+use rocket::tokio::sync::Semaphore;
+use rocket::{Request, Route, State};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsStr;
+use std::fs;
+use std::future::Future;
+use std::hash::Hash;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::fs::OpenOptions;
+use tokio::io::AsyncWriteExt;
 
-type Cache = Mutex<LRUCache<String, Vec<u8>>>;
+/// Logs an indented info line about the cache, prefixed with the request's correlation ID,
+/// only when `verbose_cache_logging` is enabled, as these lines are logged for nearly every
+/// request.
+macro_rules! cache_info {
+    ($config:expr, $($arg:tt)*) => {
+        if $config.verbose_cache_logging {
+            request_info!($($arg)*)
+        }
+    };
+}
+
+/// Rank of the default file server, which the `static_dirs` file servers are ranked after.
+const FILE_SERVER_RANK: isize = 10;
+
+pub type Cache = Mutex<LRUCache<String, CachedImage>>;
+
+/// The contents of a cached image, along with what its responses are sent with, so that a
+/// cache hit doesn't recompute them. The entity tag and digest are filled once they're
+/// known, which they aren't for an image restored from a `cache_index`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CachedImage {
+    pub bytes: Vec<u8>,
+    pub content_type: ContentType,
+    pub etag: Option<String>,
+    pub digest: Option<String>,
+    pub dimensions: Option<(u32, u32)>,
+}
+
+impl CachedImage {
+    /// Caches the contents of an image along with its dimensions, which are read from its
+    /// header, computing their digest only when it will be sent.
+    ///
+    /// Arguments:
+    ///
+    /// * `bytes` - Vec<u8>
+    /// * `content_type` - ContentType
+    /// * `content_digest` - bool
+    ///
+    /// Returns: `CachedImage`
+    ///
+    /// Usage: ```CachedImage::new(contents, ContentType::PNG, config.content_digest);```
+    pub fn new(bytes: Vec<u8>, content_type: ContentType, content_digest: bool) -> Self {
+        let digest = match content_digest {
+            true => Some(get_digest(&bytes)),
+            false => None,
+        };
+
+        CachedImage {
+            dimensions: encoded_dimensions(&bytes),
+            bytes,
+            content_type,
+            etag: None,
+            digest,
+        }
+    }
+
+    /// Assigns the entity tag that the image is served with.
+    ///
+    /// Arguments:
+    ///
+    /// * `etag` - Option<String>
+    ///
+    /// Returns: `CachedImage`
+    ///
+    /// Usage: ```CachedImage::new(contents, content_type, false).with_etag(get_etag(&req_image));```
+    pub fn with_etag(mut self, etag: Option<String>) -> Self {
+        self.etag = etag;
+        self
+    }
+}
+
+pub type InFlight = Mutex<HashMap<String, Mutex<()>>>;
+
+pub type ResizeLimit = Semaphore;
+
+pub type ColorCache = Mutex<LRUCache<String, Color>>;
+
+type MetaCache = Mutex<LRUCache<(PathBuf, SystemTime), Metadata>>;
+
+type BlurhashCache = Mutex<LRUCache<(PathBuf, SystemTime), String>>;
 
 type ResVec = Custom<Vec<u8>>;
 
-#[get("/image/<path..>?<ratio>")]
+/// How a requested image is resized.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Size {
+    /// A ratio of the original image's width, where 0 is the original image.
+    Ratio(u8),
+    /// An exact width, which is clamped to the original image's width unless upscaling is
+    /// allowed.
+    Width(Width),
+}
+
+/// The parameters of a request to serve an image, once they're validated.
+#[derive(Debug, PartialEq)]
+pub struct ValidatedRequest {
+    pub path: PathBuf,
+    pub passthrough: bool,
+    pub size: Size,
+    pub allow_upscale: bool,
+    pub filter: Option<FilterType>,
+    pub progressive: bool,
+    pub tint: Option<[u8; 3]>,
+    pub grayscale: bool,
+    pub format: Option<ImageFormat>,
+    pub background: [u8; 3],
+    pub quality: Option<u8>,
+    pub lossless: bool,
+    pub ephemeral: bool,
+}
+
+/// Whether a request to resize an image is valid, along with the dimensions that it would be
+/// resized to, which are omitted for an image that's served as is without them (an svg).
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct Validation {
+    valid: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    height: Option<u32>,
+}
+
+/// A blurhash placeholder of an image.
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct BlurhashPlaceholder {
+    blurhash: String,
+}
+
+/// The version of the server, along with the commit it was built from and when it was built.
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct BuildInfo {
+    version: &'static str,
+    git_sha: &'static str,
+    built_at: u64,
+}
+
+/// A cached variant of an image, along with how long it has gone without being accessed.
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct CachedVariant {
+    pathname: String,
+    width: Option<u32>,
+    format: Option<String>,
+    bytes: usize,
+    idle_secs: u64,
+}
+
+/// A line of the resize log.
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct ResizeRecord<'a> {
+    path: String,
+    pathname: &'a str,
+    ratio: u8,
+    width: Option<u32>,
+    bytes: usize,
+    duration_ms: f64,
+    timestamp: u64,
+}
+
+/// A resized image along with the headers that describe it.
+struct ImageResponse {
+    inner: ResVec,
+    headers: Vec<Header<'static>>,
+}
+
+impl<'r> Responder<'r, 'static> for ImageResponse {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        let mut response = self.inner.respond_to(req)?;
+        for header in self.headers {
+            response.set_header(header);
+        }
+
+        Ok(response)
+    }
+}
+
+/// Largest device pixel ratio that a width can be multiplied by.
+const MAX_DPR: f32 = 4.0;
+
+/// Maximum amount of widths that can be generated by a single batch request.
+const MAX_BATCH_WIDTHS: usize = 10;
+
+/// Reason that an empty original image is rejected with.
+const EMPTY_IMAGE: &str = "The image is empty! It may not have finished uploading.";
+
+// HEAD requests are also answered by this route, which strips the body from the response
+#[allow(clippy::too_many_arguments)]
+#[get("/image/<path..>?<query..>", rank = 3)]
 async fn serve_image(
-    path: PathBuf,
-    ratio: Option<&str>,
+    path: ImagePath,
+    query: ImageQuery<'_>,
+    limit: RateLimit<'_>,
+    signature: SignedUrl<'_>,
+    no_cache: NoCache,
+    if_none_match: IfNoneMatch,
+    request_id: RequestId,
+    config: &State<Config>,
     state: &State<Cache>,
-) -> Result<ResVec, InvalidRequest> {
-    // ensure that path is a directory
-    if path.extension().is_none() || path.as_os_str().is_empty() {
-        return Err(send_404_response("The file path is invalid.".to_string()));
+    in_flight: &State<InFlight>,
+    resize_limit: &State<ResizeLimit>,
+    counts: &State<Counts>,
+) -> Result<ImageResponse, InvalidRequest> {
+    limit.check().await?;
+    signature.verify()?;
+
+    let NoCache(no_cache) = no_cache;
+    let ImagePath(path) = path;
+
+    counts.lock().await.record(&get_string_path(&path));
+
+    // lines logged while serving the image are prefixed with the request's id
+    request_id
+        .scope(serve_requested_image(
+            &path,
+            &query,
+            no_cache,
+            &if_none_match,
+            config,
+            state,
+            in_flight,
+            resize_limit,
+        ))
+        .await
+}
+
+// `/image/w:400_q:80/photo.jpg` is served exactly as `/image/photo.jpg?width=400&quality=80`
+// is, while a leading segment that isn't made of resize parameters is forwarded as a directory
+#[allow(clippy::too_many_arguments)]
+#[get("/image/<params>/<path..>?<query..>", rank = 0)]
+async fn serve_pretty_image(
+    params: PrettyParams<'_>,
+    path: ImagePath,
+    query: ImageQuery<'_>,
+    limit: RateLimit<'_>,
+    signature: SignedUrl<'_>,
+    no_cache: NoCache,
+    if_none_match: IfNoneMatch,
+    request_id: RequestId,
+    config: &State<Config>,
+    state: &State<Cache>,
+    in_flight: &State<InFlight>,
+    resize_limit: &State<ResizeLimit>,
+    counts: &State<Counts>,
+) -> Result<ImageResponse, InvalidRequest> {
+    let query = params.merge(query).map_err(send_400_response)?;
+
+    serve_image(
+        path,
+        query,
+        limit,
+        signature,
+        no_cache,
+        if_none_match,
+        request_id,
+        config,
+        state,
+        in_flight,
+        resize_limit,
+        counts,
+    )
+    .await
+}
+
+/// Serves the image of a request, resizing and caching it as needed.
+///
+/// Arguments:
+///
+/// * `path` - &Path
+/// * `query` - &ImageQuery
+/// * `no_cache` - bool - whether the image is regenerated, bypassing the cache and disk
+/// * `if_none_match` - &IfNoneMatch
+/// * `config` - &Config
+/// * `state` - &Cache
+/// * `in_flight` - &InFlight
+/// * `resize_limit` - &ResizeLimit
+///
+/// Returns: `Result<ImageResponse, InvalidRequest>`
+///
+/// Usage: ```serve_requested_image(&path, &query, no_cache, &if_none_match, config, state, in_flight, resize_limit).await```
+#[allow(clippy::too_many_arguments)]
+async fn serve_requested_image(
+    path: &Path,
+    query: &ImageQuery<'_>,
+    no_cache: bool,
+    if_none_match: &IfNoneMatch,
+    config: &Config,
+    state: &Cache,
+    in_flight: &InFlight,
+    resize_limit: &ResizeLimit,
+) -> Result<ImageResponse, InvalidRequest> {
+    let validated = validate_request(config, path, query)?;
+    let req_image = requested_image(config, &validated, resize_limit).await;
+
+    // respond with the fallback image, resized the same way, in place of a missing image
+    if let Some(fallback_image) = &config.fallback_image {
+        if !req_image.path.is_file() {
+            let validated = validate_request(config, fallback_image, query)?;
+            let req_image = requested_image(config, &validated, resize_limit).await;
+
+            let contents =
+                fetch_image(&req_image, no_cache, config, state, in_flight, resize_limit).await?;
+
+            return Err(send_fallback_response(
+                contents.content_type,
+                contents.bytes,
+            ));
+        }
     }
 
-    // converts supplied "ratio" to a valid u8 integer
-    let ratio = ratio
-        .map(str::parse::<u8>)
-        .map(Result::ok)
-        .flatten()
-        .unwrap_or(0);
+    // the client's copy of an unchanged image is validated without generating the image,
+    // unless it's being regenerated
+    if !no_cache {
+        if let Some(etag) = get_etag(&req_image).filter(|etag| if_none_match.matches(etag)) {
+            return Err(send_304_response(etag));
+        }
+    }
 
-    // ensure the provided ratio is standardized
-    if ![0, 20, 35, 50, 75, 90].contains(&ratio) {
-        return Err(send_400_response(
-            "The provided ratio is invalid! It must be one of the following: 0, 20, 35, 50, 75 or 90.".to_string(),
-        ));
+    // a one-off image is neither read from nor written to the disk or the cache
+    let contents = match validated.ephemeral {
+        true => generate_ephemeral_image(&req_image, config, resize_limit).await?,
+        false => fetch_image(&req_image, no_cache, config, state, in_flight, resize_limit).await?,
+    };
+
+    let mut headers = Vec::new();
+    if let Some(etag) = contents.etag {
+        headers.push(Header::new("ETag", etag));
+    }
+    if let Some(digest) = contents.digest {
+        headers.push(Header::new("X-Content-SHA256", digest));
+    }
+    // the dimensions of the image as it's served, after it's resized
+    if let Some((width, height)) = contents.dimensions {
+        headers.push(Header::new("X-Image-Width", width.to_string()));
+        headers.push(Header::new("X-Image-Height", height.to_string()));
     }
 
-    // initialize requested image
-    let req_image = RequestedImage::new(&path, ratio);
+    if let Some(cache_control) = cache_control(config, &req_image) {
+        headers.push(Header::new("Cache-Control", cache_control));
+    }
+
+    // the width can differ from the requested width once it's snapped, or the original image
+    // is served in its place
+    let width = match validated.size {
+        Size::Width(_) => req_image.width.or_else(|| req_image.original_width()),
+        Size::Ratio(_) => None,
+    };
+    if let Some(width) = width {
+        headers.push(Header::new("X-Effective-Width", width.to_string()));
+    }
+
+    Ok(ImageResponse {
+        inner: Custom(contents.content_type, contents.bytes),
+        headers,
+    })
+}
+
+#[get("/image/<path..>?<query..>", rank = 7)]
+async fn serve_image_validate(
+    path: ActionPath<Validate>,
+    query: ImageQuery<'_>,
+    config: &State<Config>,
+    resize_limit: &State<ResizeLimit>,
+) -> Result<Json<Validation>, InvalidRequest> {
+    let validated = validate_request(config, &path.path, &query)?;
+    let req_image = requested_image(config, &validated, resize_limit).await;
+
+    // return if requested image doesn't exist, or it's empty
+    find_original(&req_image)?;
+
+    // the dimensions are read from the original image's header, without resizing it
+    let (width, height) = req_image.target_dimensions().ok().unzip();
+
+    Ok(Json(Validation {
+        valid: true,
+        width,
+        height,
+    }))
+}
+
+#[allow(clippy::too_many_arguments)]
+#[get("/image/<path..>?<widths>", rank = 1)]
+async fn serve_image_widths(
+    path: ImagePath,
+    widths: &str,
+    limit: RateLimit<'_>,
+    signature: SignedUrl<'_>,
+    config: &State<Config>,
+    state: &State<Cache>,
+    in_flight: &State<InFlight>,
+    resize_limit: &State<ResizeLimit>,
+) -> Result<Json<BTreeMap<u32, String>>, InvalidRequest> {
+    limit.check().await?;
+    // the urls of a batch are signed for the caller, so the batch itself has to be signed
+    signature.verify()?;
+
+    let urls = generate_widths(&path.0, widths, config, state, in_flight, resize_limit).await?;
+
+    Ok(Json(urls))
+}
+
+#[allow(clippy::too_many_arguments)]
+#[get("/image/<path..>?<widths>", rank = 4)]
+async fn serve_image_srcset(
+    path: ActionPath<Srcset>,
+    widths: &str,
+    limit: RateLimit<'_>,
+    signature: SignedUrl<'_>,
+    config: &State<Config>,
+    state: &State<Cache>,
+    in_flight: &State<InFlight>,
+    resize_limit: &State<ResizeLimit>,
+) -> Result<String, InvalidRequest> {
+    limit.check().await?;
+    signature.verify()?;
+
+    let urls = generate_widths(&path.path, widths, config, state, in_flight, resize_limit).await?;
+
+    // <url> <width>w, <url> <width>w, ...
+    Ok(urls
+        .iter()
+        .map(|(width, url)| format!("{} {}w", url, width))
+        .collect::<Vec<String>>()
+        .join(", "))
+}
+
+#[get("/image/<path..>?lqip=true", rank = 2)]
+async fn serve_image_lqip(
+    path: ImagePath,
+    signature: SignedUrl<'_>,
+    config: &State<Config>,
+    state: &State<Cache>,
+    resize_limit: &State<ResizeLimit>,
+) -> Result<String, InvalidRequest> {
+    signature.verify()?;
+
+    let ImagePath(path) = path;
+    validate_path(&path, config)?;
+
+    let req_image = RequestedImage::lqip(&config.roots(), &path);
+    let req_image = with_content_hash(req_image, config, resize_limit).await;
+
+    let contents = get_or_compute(state, req_image.cache_key(), || async {
+        // return if requested image doesn't exist, or it's empty
+        find_original(&req_image)?;
+
+        let contents = with_resize_permit(resize_limit, req_image.encode_lqip())
+            .await
+            .map_err(send_400_response)?;
+
+        cache_info!(config, "Saved requested placeholder into cache.");
+
+        Ok(CachedImage::new(contents, ContentType::JPEG, false))
+    })
+    .await?;
+
+    Ok(format!(
+        "data:image/jpeg;base64,{}",
+        base64::encode(contents.bytes)
+    ))
+}
+
+#[get("/image/<path..>", rank = 5)]
+async fn serve_image_color(
+    path: ActionPath<DominantColor>,
+    signature: SignedUrl<'_>,
+    config: &State<Config>,
+    state: &State<ColorCache>,
+    resize_limit: &State<ResizeLimit>,
+) -> Result<Json<Color>, InvalidRequest> {
+    signature.verify()?;
+    validate_path(&path.path, config)?;
+
+    let req_image = RequestedImage::new(&config.roots(), &path.path, 0);
+    let req_image = with_content_hash(req_image, config, resize_limit).await;
+
+    let color = get_or_compute(state, req_image.cache_key(), || async {
+        // return if requested image doesn't exist, or it's empty
+        find_original(&req_image)?;
+
+        with_resize_permit(resize_limit, req_image.average_color())
+            .await
+            .map_err(send_400_response)
+    })
+    .await?;
+
+    Ok(Json(color))
+}
+
+#[get("/image/<path..>", rank = 6)]
+async fn serve_image_meta(
+    path: ActionPath<Meta>,
+    signature: SignedUrl<'_>,
+    config: &State<Config>,
+    state: &State<MetaCache>,
+    resize_limit: &State<ResizeLimit>,
+) -> Result<Json<Metadata>, InvalidRequest> {
+    signature.verify()?;
+    validate_path(&path.path, config)?;
+
+    let req_image = RequestedImage::new(&config.roots(), &path.path, 0);
+
+    // return if requested image doesn't exist
+    let modified = match fs::metadata(&req_image.path).and_then(|meta| meta.modified()) {
+        Ok(modified) if req_image.path.is_file() => modified,
+        _ => return Err(send_404_response("Resource was not found.".to_string())),
+    };
+    if req_image.is_empty() {
+        return Err(send_400_response(EMPTY_IMAGE.to_string()));
+    }
+
+    // a replaced image is cached under a new key, while the old key ages out of the cache
+    let key = (req_image.path.clone(), modified);
+
+    if let Some(metadata) = state.lock().await.get(&key) {
+        return Ok(Json(metadata.clone()));
+    }
+
+    // the cache isn't held while the header is read, so other images aren't held up by it
+    let metadata = with_resize_permit(resize_limit, req_image.metadata())
+        .await
+        .map_err(send_400_response)?;
+    state.lock().await.insert(key, metadata.clone());
+
+    Ok(Json(metadata))
+}
+
+#[get("/image/<path..>", rank = 8)]
+async fn serve_image_blurhash(
+    path: ActionPath<Blurhash>,
+    signature: SignedUrl<'_>,
+    config: &State<Config>,
+    state: &State<BlurhashCache>,
+    resize_limit: &State<ResizeLimit>,
+) -> Result<Json<BlurhashPlaceholder>, InvalidRequest> {
+    signature.verify()?;
+    validate_path(&path.path, config)?;
+
+    let req_image = RequestedImage::new(&config.roots(), &path.path, 0);
+
+    // return if requested image doesn't exist
+    let modified = match fs::metadata(&req_image.path).and_then(|meta| meta.modified()) {
+        Ok(modified) if req_image.path.is_file() => modified,
+        _ => return Err(send_404_response("Resource was not found.".to_string())),
+    };
+    if req_image.is_empty() {
+        return Err(send_400_response(EMPTY_IMAGE.to_string()));
+    }
+
+    // a replaced image is hashed under a new key, while the old key ages out of the cache
+    let key = (req_image.path.clone(), modified);
+
+    let blurhash = get_or_compute(state, key, || async {
+        with_resize_permit(resize_limit, req_image.blurhash())
+            .await
+            .map_err(send_400_response)
+    })
+    .await?;
+
+    Ok(Json(BlurhashPlaceholder { blurhash }))
+}
+
+#[get("/image/<path..>", rank = 9)]
+async fn serve_image_variants(
+    path: ActionPath<Variants>,
+    config: &State<Config>,
+    state: &State<Cache>,
+) -> Result<Json<Vec<CachedVariant>>, InvalidRequest> {
+    validate_path(&path.path, config)?;
+
+    let req_image = RequestedImage::new(&config.roots(), &path.path, 0);
+
+    // return if requested image doesn't exist
+    if !req_image.path.is_file() {
+        return Err(send_404_response("Resource was not found.".to_string()));
+    }
+
+    // variants are named after their original image, so the variants of another image whose
+    // name begins with `<filename>_` are listed as well
+    let prefix = req_image.variant_prefix(config.cache_dir.as_deref());
+    let cache = state.lock().await;
+    let variants = cache
+        .iter_prefix(&prefix)
+        .map(|(pathname, image, last_accessed)| {
+            // only the header of the variant is read for its width and format
+            let reader = Reader::new(Cursor::new(&image.bytes))
+                .with_guessed_format()
+                .ok();
+            let format = reader
+                .as_ref()
+                .and_then(Reader::format)
+                .map(|format| format!("{:?}", format).to_lowercase());
+
+            CachedVariant {
+                pathname: pathname.clone(),
+                width: reader
+                    .and_then(|reader| reader.into_dimensions().ok())
+                    .map(|(width, ..)| width),
+                format,
+                bytes: image.bytes.len(),
+                idle_secs: last_accessed.elapsed().as_secs(),
+            }
+        })
+        .collect();
+
+    Ok(Json(variants))
+}
+
+/// Generates and caches every width within a comma-separated list of widths.
+///
+/// Arguments:
+///
+/// * `path` - &Path
+/// * `widths` - &str
+/// * `config` - &Config
+/// * `state` - &Cache
+/// * `in_flight` - &InFlight
+/// * `resize_limit` - &ResizeLimit
+///
+/// Returns: `Result<BTreeMap<u32, String>, InvalidRequest>` - a map of each width to its url
+///
+/// Usage: ```generate_widths(&path, widths, config, state, in_flight, resize_limit).await?;```
+async fn generate_widths(
+    path: &Path,
+    widths: &str,
+    config: &Config,
+    state: &Cache,
+    in_flight: &InFlight,
+    resize_limit: &ResizeLimit,
+) -> Result<BTreeMap<u32, String>, InvalidRequest> {
+    validate_path(path, config)?;
+
+    // converts supplied comma-separated "widths" to a set of valid widths
+    let widths = widths
+        .split(',')
+        .map(|width| parse_width(&Parsed(width.parse()), 1.0, config))
+        .collect::<Result<Vec<Width>, InvalidRequest>>()?;
+
+    if widths.len() > MAX_BATCH_WIDTHS {
+        return Err(send_400_response(format!(
+            "Too many widths were provided! A maximum of {} widths may be requested at once.",
+            MAX_BATCH_WIDTHS
+        )));
+    }
+
+    let mut urls = BTreeMap::new();
+    for width in widths {
+        let query = || ImageQuery {
+            ratio: None,
+            width: Some(Parsed(Ok(width))),
+            dpr: None,
+            filter: None,
+            allow_upscale: None,
+            progressive: None,
+            tint: None,
+            grayscale: None,
+            format: None,
+            bg: None,
+            quality: None,
+            webp_lossless: None,
+            quality_preset: None,
+            ephemeral: None,
+            original: None,
+        };
+
+        // generate and cache every requested width, exactly as its url would
+        let validated = validate_request(config, path, &query())?;
+        let req_image = requested_image(config, &validated, resize_limit).await;
+        fetch_image(&req_image, false, config, state, in_flight, resize_limit).await?;
+
+        // the urls are signed when signed urls are required, so that they can be requested
+        let url = uri!(serve_image(Path::new(path), query())).to_string();
+        let url = match &config.signing_secret {
+            Some(secret) => signed_url(secret, &url),
+            None => url,
+        };
+        urls.insert(width.0, url);
+    }
+
+    Ok(urls)
+}
+
+/// Validates the path and query of a request to serve an image, without touching the disk.
+/// Vector images and the `passthrough_formats` are served as is, so their query is ignored.
+///
+/// Arguments:
+///
+/// * `config` - &Config
+/// * `path` - &Path
+/// * `query` - &ImageQuery
+///
+/// Returns: `Result<ValidatedRequest, InvalidRequest>`
+///
+/// Usage: ```validate_request(config, &path, &query)?;```
+pub fn validate_request(
+    config: &Config,
+    path: &Path,
+    query: &ImageQuery,
+) -> Result<ValidatedRequest, InvalidRequest> {
+    let mut validated = ValidatedRequest {
+        path: path.to_path_buf(),
+        passthrough: is_passthrough(path, &config.passthrough_formats),
+        size: Size::Ratio(0),
+        allow_upscale: false,
+        filter: None,
+        progressive: false,
+        tint: None,
+        grayscale: false,
+        format: None,
+        background: config.background,
+        quality: None,
+        lossless: false,
+        ephemeral: query.ephemeral.unwrap_or(false),
+    };
+
+    if validated.passthrough {
+        validate_characters(path, config)?;
+
+        // a format that isn't resized still needs to be sent with its content type
+        if image_content_type(path).is_none() {
+            return Err(send_400_response(
+                "The image content type is invalid.".to_string(),
+            ));
+        }
+
+        return Ok(validated);
+    }
+
+    validate_path(path, config)?;
+
+    // the original image is served as is, like a format that's never resized, regardless of
+    // the rest of the query or the `default_width`
+    if query.original.unwrap_or(false) {
+        validated.passthrough = true;
+
+        return Ok(validated);
+    }
+
+    let ImageQuery {
+        ratio,
+        ref width,
+        dpr,
+        filter,
+        allow_upscale,
+        progressive,
+        tint,
+        grayscale,
+        format,
+        bg,
+        quality,
+        webp_lossless,
+        quality_preset,
+        ..
+    } = *query;
+
+    // an explicitly requested filter or quality takes precedence over the preset's
+    let preset = match quality_preset {
+        Some(preset) => Some(parse_quality_preset(preset, config)?),
+        None => None,
+    };
+
+    validated.filter = match filter {
+        Some(filter) => Some(parse_filter(filter)?),
+        None => preset.map(|preset| preset.filter),
+    };
+
+    // a request for neither a width nor a ratio is resized to the default width
+    let default_width = match (width, ratio, config.default_width) {
+        (None, None, Some(default_width)) => Some(Parsed(Ok(Width(default_width)))),
+        _ => None,
+    };
+
+    // resize the image to either a width or a ratio
+    validated.size = match width.as_ref().or(default_width.as_ref()) {
+        Some(width) => {
+            if ratio.is_some() {
+                return Err(send_400_response(
+                    "Only one of ratio or width may be provided.".to_string(),
+                ));
+            }
+
+            let dpr = match dpr {
+                Some(dpr) => parse_dpr(dpr)?,
+                None => 1.0,
+            };
+
+            Size::Width(parse_width(width, dpr, config)?)
+        }
+        None if dpr.is_some() => {
+            return Err(send_400_response(
+                "A dpr is only supported when resizing to a width.".to_string(),
+            ));
+        }
+        None => {
+            // converts supplied "ratio" to a valid u8 integer
+            let ratio = ratio
+                .and_then(|ratio| ratio.parse::<u8>().ok())
+                .unwrap_or(0);
+
+            // ensure the provided ratio is standardized
+            if ![0, 20, 35, 50, 75, 90].contains(&ratio) {
+                return Err(send_400_response(
+                    "The provided ratio is invalid! It must be one of the following: 0, 20, 35, 50, 75 or 90.".to_string(),
+                ));
+            }
+
+            Size::Ratio(ratio)
+        }
+    };
+    validated.allow_upscale = allow_upscale.unwrap_or(false);
 
     // ensure the requested image has a valid content type
-    if req_image.content_type.is_none() {
+    let content_type = image_content_type(path);
+    if content_type.is_none() {
         return Err(send_400_response(
             "The image content type is invalid.".to_string(),
         ));
     }
 
+    if let Some(format) = format {
+        validated.format = Some(parse_format(format)?);
+    }
+    match bg {
+        // only jpegs lack an alpha channel to preserve transparency with
+        Some(_) if validated.format != Some(ImageFormat::Jpeg) => {
+            return Err(send_400_response(
+                "A background is only supported when converting to a jpeg.".to_string(),
+            ))
+        }
+        Some(bg) => validated.background = parse_color("bg", bg)?,
+        None => (),
+    }
+
+    let is_jpeg = match validated.format {
+        Some(format) => format == ImageFormat::Jpeg,
+        None => content_type == Some(ContentType::JPEG),
+    };
+    let is_webp = validated.format == Some(ImageFormat::WebP);
+
+    validated.lossless = webp_lossless.unwrap_or(false);
+    if validated.lossless && !is_webp {
+        return Err(send_400_response(
+            "Lossless encoding is only supported when converting to a webp.".to_string(),
+        ));
+    }
+
+    match quality {
+        Some(_) if !is_jpeg && !is_webp => {
+            return Err(send_400_response(
+                "A quality is only supported for jpeg and webp images.".to_string(),
+            ))
+        }
+        // a lossless webp doesn't lose any quality to begin with
+        Some(quality) => {
+            let quality = parse_quality(quality)?;
+            validated.quality = Some(quality).filter(|_| !validated.lossless);
+        }
+        // a preset's quality only applies to the formats that it can
+        None if is_jpeg || is_webp => {
+            validated.quality = preset
+                .map(|preset| preset.quality)
+                .filter(|_| !validated.lossless);
+        }
+        None => (),
+    }
+
+    validated.progressive = progressive.unwrap_or(false);
+    if validated.progressive && !is_jpeg {
+        return Err(send_400_response(
+            "Progressive encoding is only supported for jpeg images.".to_string(),
+        ));
+    }
+
+    if let Some(tint) = tint {
+        validated.tint = Some(parse_color("tint", tint)?);
+    }
+    validated.grayscale = grayscale.unwrap_or(false);
+
+    Ok(validated)
+}
+
+/// Initializes the requested image of a validated request.
+///
+/// Arguments:
+///
+/// * `config` - &Config
+/// * `validated` - &ValidatedRequest
+/// * `resize_limit` - &ResizeLimit
+///
+/// Returns: `RequestedImage`
+///
+/// Usage: ```requested_image(config, &validated, resize_limit).await;```
+async fn requested_image(
+    config: &Config,
+    validated: &ValidatedRequest,
+    resize_limit: &ResizeLimit,
+) -> RequestedImage {
+    let path = validated.path.as_path();
+
+    if validated.passthrough {
+        let req_image = RequestedImage::new(&config.roots(), path, 0);
+        return with_content_hash(req_image, config, resize_limit).await;
+    }
+
+    let req_image = match validated.size {
+        Size::Width(width) => sized_image(config, path, width, validated.allow_upscale),
+        Size::Ratio(ratio) => RequestedImage::new(&config.roots(), path, ratio),
+    }
+    .with_filter(validated.filter, config.fast_filter_width)
+    .with_format(validated.format, validated.background)
+    .with_quality(validated.quality, validated.lossless)
+    .with_default_quality(config.jpeg_quality, config.webp_quality)
+    .with_default_filters(config.downscale_filter, config.upscale_filter)
+    .with_progressive(validated.progressive)
+    .with_tint(validated.tint, validated.grayscale)
+    .with_cache_dir(config.cache_dir.as_deref());
+
+    with_content_hash(req_image, config, resize_limit).await
+}
+
+/// Hashes the contents of the requested image's original image when content addressing is
+/// enabled. The original image is read in full to hash it, so it waits for a permit from the
+/// resize limit just as a resize does.
+///
+/// Arguments:
+///
+/// * `req_image` - RequestedImage
+/// * `config` - &Config
+/// * `resize_limit` - &ResizeLimit
+///
+/// Returns: `RequestedImage`
+///
+/// Usage: ```with_content_hash(RequestedImage::lqip(&config.roots(), &path), config, resize_limit).await;```
+async fn with_content_hash(
+    req_image: RequestedImage,
+    config: &Config,
+    resize_limit: &ResizeLimit,
+) -> RequestedImage {
+    if !config.content_addressed {
+        return req_image;
+    }
+
+    with_resize_permit(resize_limit, req_image.with_content_hash(true)).await
+}
+
+/// Initializes a requested image resized to a width, or the original image when the width
+/// isn't below the original image's width (unless upscaling beyond it is allowed), as
+/// resizing it would only lose quality.
+///
+/// Arguments:
+///
+/// * `config` - &Config
+/// * `path` - &Path
+/// * `width` - Width
+/// * `allow_upscale` - bool - whether the image may be resized beyond its original width
+///
+/// Returns: `RequestedImage`
+///
+/// Usage: ```sized_image(config, &path, width, false);```
+fn sized_image(config: &Config, path: &Path, width: Width, allow_upscale: bool) -> RequestedImage {
+    let req_image = RequestedImage::with_width(&config.roots(), path, width);
+
+    // upscaling beyond the original image only adds bytes, not quality
+    match req_image.original_width() {
+        Some(original_width)
+            if width.0 == original_width || (width.0 > original_width && !allow_upscale) =>
+        {
+            request_info!(
+                "Served the original image in place of a width of {}, which isn't below its width of {}.",
+                width,
+                original_width
+            );
+
+            RequestedImage::new(&config.roots(), path, 0)
+        }
+        _ => req_image,
+    }
+}
+
+/// Ensures the requested path is a file with a supported image extension, without any
+/// forbidden characters. A malformed path is a 400 response, leaving a 404 response for a
+/// well-formed path whose image is missing.
+///
+/// Arguments:
+///
+/// * `path` - &Path
+/// * `config` - &Config
+///
+/// Returns: `Result<(), InvalidRequest>`
+///
+/// Usage: ```validate_path(&path, config)?;```
+fn validate_path(path: &Path, config: &Config) -> Result<(), InvalidRequest> {
+    validate_characters(path, config)?;
+
+    // a path without a file extension can't name an image, so the request is malformed
+    // rather than in search of a missing file
+    if path.extension().is_none() || path.as_os_str().is_empty() {
+        return Err(send_400_response(
+            "The file path is invalid! It must name an image file.".to_string(),
+        ));
+    }
+
+    // ensure the requested image can actually be processed
+    if !has_supported_extension(path) {
+        return Err(send_400_response(format!(
+            "The image extension is not supported! It must be one of the following: {}.",
+            SUPPORTED_EXTENSIONS.join(", ")
+        )));
+    }
+
+    Ok(())
+}
+
+/// Ensures the requested path doesn't contain a control character, such as a null byte,
+/// which can't safely be passed on to the filesystem, or a configured forbidden character.
+///
+/// Arguments:
+///
+/// * `path` - &Path
+/// * `config` - &Config
+///
+/// Returns: `Result<(), InvalidRequest>`
+///
+/// Usage: ```validate_characters(&path, config)?;```
+fn validate_characters(path: &Path, config: &Config) -> Result<(), InvalidRequest> {
+    match has_forbidden_characters(path, &config.forbidden_path_characters) {
+        true => Err(send_400_response(
+            "The file path is invalid! It contains a forbidden character.".to_string(),
+        )),
+        false => Ok(()),
+    }
+}
+
+/// Ensures a parsed width is within `max_width`, then multiplies it by a device pixel ratio
+/// (capped at `max_width`), which must be one of the `allowed_widths` (or is snapped to the
+/// nearest one), if configured.
+///
+/// Arguments:
+///
+/// * `width` - &Parsed<Width>
+/// * `dpr` - f32 - device pixel ratio, where 1 leaves the width as is
+/// * `config` - &Config
+///
+/// Returns: `Result<Width, InvalidRequest>`
+///
+/// Usage: ```parse_width(&width, 1.0, config)?;```
+fn parse_width(width: &Parsed<Width>, dpr: f32, config: &Config) -> Result<Width, InvalidRequest> {
+    let width = match width.0 {
+        Ok(Width(width)) if width <= config.max_width => width,
+        _ => {
+            return Err(send_400_response(format!(
+                "The provided width is invalid! It must be a number between 1 and {}.",
+                config.max_width
+            )))
+        }
+    };
+
+    // a 400px slot on a 2x screen is filled by an 800px image
+    let width = ((width as f32 * dpr).round() as u32).min(config.max_width);
+
+    let allowed_widths = match &config.allowed_widths {
+        Some(allowed_widths) if !allowed_widths.is_empty() => allowed_widths,
+        _ => return Ok(Width(width)),
+    };
+
+    if allowed_widths.contains(&width) {
+        return Ok(Width(width));
+    }
+
+    match config.width_mode {
+        WidthMode::Reject => Err(send_400_response(format!(
+            "The provided width is not allowed! It must be one of the following: {}.",
+            allowed_widths
+                .iter()
+                .map(u32::to_string)
+                .collect::<Vec<String>>()
+                .join(", ")
+        ))),
+        // the smaller of two equally near widths is preferred
+        WidthMode::Snap => Ok(Width(
+            *allowed_widths
+                .iter()
+                .min_by_key(|allowed| (allowed.abs_diff(width), **allowed))
+                .unwrap(),
+        )),
+    }
+}
+
+/// Converts a supplied filter name to its resampling filter.
+///
+/// Arguments:
+///
+/// * `filter` - &str
+///
+/// Returns: `Result<FilterType, InvalidRequest>`
+///
+/// Usage: ```parse_filter(filter)?;```
+fn parse_filter(filter: &str) -> Result<FilterType, InvalidRequest> {
+    FILTERS
+        .iter()
+        .find(|(name, _)| *name == filter)
+        .map(|(_, value)| *value)
+        .ok_or_else(|| {
+            send_400_response(format!(
+                "The provided filter is invalid! It must be one of the following: {}.",
+                FILTERS
+                    .iter()
+                    .map(|(name, _)| *name)
+                    .collect::<Vec<&str>>()
+                    .join(", ")
+            ))
+        })
+}
+
+/// Retrieves a configured quality preset by its name.
+///
+/// Arguments:
+///
+/// * `preset` - &str
+/// * `config` - &Config
+///
+/// Returns: `Result<&QualityPreset, InvalidRequest>`
+///
+/// Usage: ```parse_quality_preset(preset, config)?;```
+fn parse_quality_preset<'a>(
+    preset: &str,
+    config: &'a Config,
+) -> Result<&'a QualityPreset, InvalidRequest> {
+    config.quality_presets.get(preset).ok_or_else(|| {
+        send_400_response(format!(
+            "The provided quality preset is invalid! It must be one of the following: {}.",
+            config
+                .quality_presets
+                .keys()
+                .map(String::as_str)
+                .collect::<Vec<&str>>()
+                .join(", ")
+        ))
+    })
+}
+
+/// Generates a strong entity tag of the requested image from its cache key, which is unique
+/// to its path and parameters, along with the original image's modification time and size.
+/// The same image always has the same tag, even once it's evicted or the server restarts, so
+/// a client's copy can be validated without generating the image. It's a truncated SHA-256
+/// digest, which unlike std's hashers doesn't change along with the Rust release.
+///
+/// Arguments:
+///
+/// * `req_image` - &RequestedImage
+///
+/// Returns: `Option<String>` - a quoted hash, unless the original image can't be read
+///
+/// Usage: ```get_etag(&req_image);```
+fn get_etag(req_image: &RequestedImage) -> Option<String> {
+    let meta = fs::metadata(&req_image.path).ok()?;
+    let modified = meta.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+
+    let digest = Sha256::new()
+        .chain_update(req_image.cache_key())
+        .chain_update(modified.as_nanos().to_le_bytes())
+        .chain_update(meta.len().to_le_bytes())
+        .finalize();
+
+    Some(format!("\"{}\"", &format!("{:x}", digest)[..16]))
+}
+
+/// Generates a hex SHA-256 digest of the contents of an image.
+///
+/// Arguments:
+///
+/// * `contents` - &[u8]
+///
+/// Returns: `String`
+///
+/// Usage: ```get_digest(&contents);```
+fn get_digest(contents: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(contents))
+}
+
+/// Converts a supplied `rrggbb` hex color to its red, green and blue channels.
+///
+/// Arguments:
+///
+/// * `name` - &str - name of the parameter that supplied the color
+/// * `color` - &str
+///
+/// Returns: `Result<[u8; 3], InvalidRequest>`
+///
+/// Usage: ```parse_color("tint", tint)?;```
+fn parse_color(name: &str, color: &str) -> Result<[u8; 3], InvalidRequest> {
+    parse_hex_color(color).ok_or_else(|| {
+        send_400_response(format!(
+            "The provided {} is invalid! It must be a hex color, such as ff8800.",
+            name
+        ))
+    })
+}
+
+/// Converts a supplied output format to an image format, where jpeg and webp are supported.
+///
+/// Arguments:
+///
+/// * `format` - &str
+///
+/// Returns: `Result<ImageFormat, InvalidRequest>`
+///
+/// Usage: ```parse_format(format)?;```
+fn parse_format(format: &str) -> Result<ImageFormat, InvalidRequest> {
+    match format {
+        "jpeg" | "jpg" => Ok(ImageFormat::Jpeg),
+        "webp" => Ok(ImageFormat::WebP),
+        _ => Err(send_400_response(
+            "The provided format is invalid! It must be one of the following: jpeg or webp."
+                .to_string(),
+        )),
+    }
+}
+
+/// Converts a supplied device pixel ratio to a number between 1 and `MAX_DPR`.
+///
+/// Arguments:
+///
+/// * `dpr` - &str
+///
+/// Returns: `Result<f32, InvalidRequest>`
+///
+/// Usage: ```parse_dpr(dpr)?;```
+fn parse_dpr(dpr: &str) -> Result<f32, InvalidRequest> {
+    match dpr.parse::<f32>() {
+        Ok(dpr) if (1.0..=MAX_DPR).contains(&dpr) => Ok(dpr),
+        _ => Err(send_400_response(format!(
+            "The provided dpr is invalid! It must be a number between 1 and {}.",
+            MAX_DPR
+        ))),
+    }
+}
+
+/// Converts a supplied quality to a number between 1 and 100.
+///
+/// Arguments:
+///
+/// * `quality` - &str
+///
+/// Returns: `Result<u8, InvalidRequest>`
+///
+/// Usage: ```parse_quality(quality)?;```
+fn parse_quality(quality: &str) -> Result<u8, InvalidRequest> {
+    match quality.parse::<u8>() {
+        Ok(quality) if (1..=100).contains(&quality) => Ok(quality),
+        _ => Err(send_400_response(
+            "The provided quality is invalid! It must be a number between 1 and 100.".to_string(),
+        )),
+    }
+}
+
+/// Retrieves the requested image from the cache, or generates it into the cache if it
+/// doesn't exist. Concurrent requests for the same uncached image wait for the first request
+/// to generate it, rather than each generating it themselves.
+///
+/// Arguments:
+///
+/// * `req_image` - &RequestedImage
+/// * `no_cache` - bool - whether the image is regenerated, bypassing the cache and disk
+/// * `config` - &Config
+/// * `state` - &Cache
+/// * `in_flight` - &InFlight
+/// * `resize_limit` - &ResizeLimit
+///
+/// Returns: `Result<CachedImage, InvalidRequest>`
+///
+/// Usage: ```fetch_image(&req_image, false, config, state, in_flight, resize_limit).await?;```
+async fn fetch_image(
+    req_image: &RequestedImage,
+    no_cache: bool,
+    config: &Config,
+    state: &Cache,
+    in_flight: &InFlight,
+    resize_limit: &ResizeLimit,
+) -> Result<CachedImage, InvalidRequest> {
+    // invalidate the cached image if the original image has since been replaced, or it's
+    // being regenerated (the original image is only ever read, never regenerated)
+    let is_stale = req_image.is_stale() || (no_cache && !req_image.is_original());
+    if is_stale {
+        lock_cache(config, state)
+            .await?
+            .remove(&req_image.cache_key());
+
+        cache_info!(config, "Removed stale image from cache.");
+    }
+
+    // respond with the cached image if the cache contains it
+    if !no_cache {
+        if let Some(cached_image) = get_cached_image(req_image, config, state).await? {
+            return Ok(cached_image);
+        }
+    }
+
+    // return if requested image doesn't exist, or it's empty
+    find_original(req_image)?;
+
+    // only one request at a time may generate the image, which is held until it's cached
+    let generating = in_flight
+        .lock()
+        .await
+        .entry(req_image.cache_key())
+        .or_insert_with(|| Mutex::new(()))
+        .clone();
+    let _generating = generating.lock().await;
+
+    // respond with the cached image if it was generated while waiting, without counting
+    // another miss if it wasn't
+    if !no_cache
+        && lock_cache(config, state)
+            .await?
+            .contains_key(&req_image.cache_key())
+    {
+        if let Some(cached_image) = get_cached_image(req_image, config, state).await? {
+            return Ok(cached_image);
+        }
+    }
+
+    // the image is removed from the in-flight images once it's cached, or if this request is
+    // dropped while generating it, in which case the resize is skipped if it hasn't started
+    let _entry = InFlightEntry {
+        in_flight,
+        key: req_image.cache_key(),
+    };
+    let contents = generate_image(req_image, is_stale, config, resize_limit)
+        .await
+        .map(|contents| {
+            let content_type = req_image.content_type.clone().unwrap();
+            CachedImage::new(contents, content_type, config.content_digest)
+                .with_etag(get_etag(req_image))
+        });
+
+    // store its contents into cache, but respond with the contents that were just read, as
+    // the entry isn't guaranteed to still be in the cache
+    match &contents {
+        // a large image would evict many smaller ones, so it's only ever served
+        Ok(contents) if contents.bytes.len() > config.max_cacheable_bytes => {
+            cache_info!(
+                config,
+                "Skipped caching requested image of {} bytes, which exceeds {} bytes.",
+                contents.bytes.len(),
+                config.max_cacheable_bytes
+            );
+        }
+        // the image was already generated, so it's served even if the cache can't be locked
+        Ok(contents) => match lock_cache(config, state).await {
+            Ok(mut cache) => {
+                cache.insert(req_image.cache_key(), contents.clone());
+
+                cache_info!(config, "Saved requested image into cache.");
+            }
+            Err(_) => request_warn!("Skipped caching requested image, as the cache is busy."),
+        },
+        Err(_) => (),
+    }
+
+    contents.map_err(send_400_response)
+}
+
+/// Generates the requested image in memory for a single `ephemeral` request, without reading
+/// or writing its variant on disk, nor the cache, so that one-off images never take the place
+/// of other images. Resizing still waits for a permit from the resize limit.
+///
+/// Arguments:
+///
+/// * `req_image` - &RequestedImage
+/// * `config` - &Config
+/// * `resize_limit` - &ResizeLimit
+///
+/// Returns: `Result<CachedImage, InvalidRequest>`
+///
+/// Usage: ```generate_ephemeral_image(&req_image, config, resize_limit).await?;```
+async fn generate_ephemeral_image(
+    req_image: &RequestedImage,
+    config: &Config,
+    resize_limit: &ResizeLimit,
+) -> Result<CachedImage, InvalidRequest> {
+    // return if requested image doesn't exist, or it's empty
+    find_original(req_image)?;
+
+    let contents = match req_image.is_original() {
+        true => req_image.read().await,
+        false => with_resize_permit(resize_limit, req_image.encode()).await,
+    }
+    .map_err(send_400_response)?;
+
+    let content_type = req_image.content_type.clone().unwrap();
+    Ok(
+        CachedImage::new(contents, content_type, config.content_digest)
+            .with_etag(get_etag(req_image)),
+    )
+}
+
+/// An image that's being generated by a request, whose entry is removed from the in-flight
+/// images once the request is done with it, even when the request is dropped part way through.
+struct InFlightEntry<'a> {
+    in_flight: &'a InFlight,
+    key: String,
+}
+
+impl Drop for InFlightEntry<'_> {
+    fn drop(&mut self) {
+        if let Ok(mut in_flight) = self.in_flight.try_lock() {
+            in_flight.remove(&self.key);
+            return;
+        }
+
+        // the entry can't be removed without waiting for the lock, which can't be awaited here
+        let in_flight = self.in_flight.clone();
+        let key = std::mem::take(&mut self.key);
+        rocket::tokio::spawn(async move {
+            in_flight.lock().await.remove(&key);
+        });
+    }
+}
+
+/// Retrieves the requested image from the cache. Its entity tag and digest are computed once
+/// if they're sent but weren't cached with the image, such as for an image restored from a
+/// `cache_index`.
+///
+/// Arguments:
+///
+/// * `req_image` - &RequestedImage
+/// * `config` - &Config
+/// * `state` - &Cache
+///
+/// Returns: `Result<Option<CachedImage>, InvalidRequest>`
+///
+/// Usage: ```get_cached_image(&req_image, config, state).await?;```
+async fn get_cached_image(
+    req_image: &RequestedImage,
+    config: &Config,
+    state: &Cache,
+) -> Result<Option<CachedImage>, InvalidRequest> {
+    let mut cache = lock_cache(config, state).await?;
+    let cached_image = match cache.get_mut(&req_image.cache_key()) {
+        Some(cached_image) => cached_image,
+        None => return Ok(None),
+    };
+
+    if cached_image.etag.is_none() {
+        cached_image.etag = get_etag(req_image);
+    }
+    if config.content_digest && cached_image.digest.is_none() {
+        cached_image.digest = Some(get_digest(&cached_image.bytes));
+    }
+
+    cache_info!(config, "Served requested image from cache.");
+
+    Ok(Some(cached_image.clone()))
+}
+
+/// Removes an original image from the caches along with every one of its variants, so that
+/// an image that's been replaced isn't served from memory. An original image is never stale
+/// with itself, and a variant that's only cached can't be compared with it.
+///
+/// Arguments:
+///
+/// * `config` - &Config
+/// * `path` - &Path
+/// * `state` - &Cache
+/// * `colors` - &ColorCache
+///
+/// Returns: `usize` - the number of removed images
+///
+/// Usage: ```forget_image(config, &path, state, colors).await;```
+pub async fn forget_image(
+    config: &Config,
+    path: &Path,
+    state: &Cache,
+    colors: &ColorCache,
+) -> usize {
+    let req_image = RequestedImage::new(&config.roots(), path, 0);
+    let original = req_image.cache_key();
+    let hashed = format!("{}#", original);
+    let prefix = req_image.variant_prefix(config.cache_dir.as_deref());
+    let is_image =
+        |key: &String| key == &original || key.starts_with(&hashed) || key.starts_with(&prefix);
+
     let mut cache = state.lock().await;
-    // determine if cache contains requested image
-    if !cache.contains_key(&req_image.new_pathname) {
-        // return if requested image doesn't exist
-        if !req_image.path.is_file() {
-            return Err(send_404_response("Resource was not found.".to_string()));
+    let len = cache.len();
+    cache.retain(|key, _| !is_image(key));
+    colors.lock().await.retain(|key, _| !is_image(key));
+
+    len - cache.len()
+}
+
+/// Retrieves the `Cache-Control` header of a served image, which is `immutable` when its
+/// variants are content addressed, or the `max_age_secs` of its extension (falling back to
+/// the `default`).
+///
+/// Arguments:
+///
+/// * `config` - &Config
+/// * `req_image` - &RequestedImage
+///
+/// Returns: `Option<String>`
+///
+/// Usage: ```cache_control(config, &req_image);```
+fn cache_control(config: &Config, req_image: &RequestedImage) -> Option<String> {
+    if config.content_addressed {
+        return Some(format!(
+            "public, max-age={}, immutable",
+            config.immutable_max_age_secs
+        ));
+    }
+
+    // the extension of the image as it's served, once it's converted
+    let ext = image_extension(&req_image.new_pathname_buf).unwrap_or_default();
+    let max_age = |name: &str| {
+        config
+            .max_age_secs
+            .iter()
+            .find(|(format, _)| format.eq_ignore_ascii_case(name))
+            .map(|(_, secs)| *secs)
+    };
+
+    max_age(&ext)
+        .or_else(|| max_age("default"))
+        .map(|secs| format!("public, max-age={}", secs))
+}
+
+/// Checks that the original image of a request exists, responding with a 404 if it doesn't,
+/// and that it isn't empty (e.g. an upload that was interrupted), responding with a 400 if it
+/// is, rather than decoding it or serving it as is.
+///
+/// Arguments:
+///
+/// * `req_image` - &RequestedImage
+///
+/// Returns: `Result<(), InvalidRequest>`
+///
+/// Usage: ```find_original(&req_image)?;```
+fn find_original(req_image: &RequestedImage) -> Result<(), InvalidRequest> {
+    if !req_image.path.is_file() {
+        return Err(send_404_response("Resource was not found.".to_string()));
+    }
+
+    if req_image.is_empty() {
+        return Err(send_400_response(EMPTY_IMAGE.to_string()));
+    }
+
+    Ok(())
+}
+
+/// Locks the cache, waiting up to `cache_lock_timeout_ms` for it, so that a request holding
+/// the lock for too long (or that never releases it) responds with a 503 rather than stalling
+/// every other request.
+///
+/// Arguments:
+///
+/// * `config` - &Config
+/// * `state` - &Cache
+///
+/// Returns: `Result<MutexGuard<LRUCache<String, CachedImage>>, InvalidRequest>`
+///
+/// Usage: ```lock_cache(config, state).await?;```
+async fn lock_cache(
+    config: &Config,
+    state: &Cache,
+) -> Result<MutexGuard<LRUCache<String, CachedImage>>, InvalidRequest> {
+    let timeout = Duration::from_millis(config.cache_lock_timeout_ms);
+
+    rocket::tokio::time::timeout(timeout, state.lock())
+        .await
+        .map_err(|_| {
+            request_warn!("Timed out locking the cache after {:?}.", timeout);
+
+            send_503_response("The cache is busy! Try again later.".to_string())
+        })
+}
+
+/// Retrieves a value from a cache, or awaits `compute` to produce it and inserts it into the
+/// cache. The cache is only locked while it's read and written, not while the value is being
+/// computed, so a concurrent miss for the same key may compute it again.
+///
+/// Arguments:
+///
+/// * `state` - &Mutex<LRUCache<K, V>>
+/// * `key` - K
+/// * `compute` - FnOnce() -> Future<Output = Result<V, E>>
+///
+/// Returns: `Result<V, E>`
+///
+/// Usage: ```get_or_compute(state, req_image.cache_key(), || async { req_image.encode_lqip() }).await?;```
+pub async fn get_or_compute<K, V, E, F, Fut>(
+    state: &Mutex<LRUCache<K, V>>,
+    key: K,
+    compute: F,
+) -> Result<V, E>
+where
+    K: Clone + Hash + Eq,
+    V: Clone,
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = Result<V, E>>,
+{
+    if let Some(value) = state.lock().await.get(&key) {
+        return Ok(value.clone());
+    }
+
+    let value = compute().await?;
+    state.lock().await.insert(key, value.clone());
+
+    Ok(value)
+}
+
+/// Generates the contents of the requested image, saving it to disk unless the `disk_mode`
+/// only stores it in memory, or only reads existing images from disk. Resizing waits for a
+/// permit from the resize limit, while an existing image is read without one.
+///
+/// Arguments:
+///
+/// * `req_image` - &RequestedImage
+/// * `is_stale` - bool - whether a saved image needs to be replaced
+/// * `config` - &Config
+/// * `resize_limit` - &ResizeLimit
+///
+/// Returns: `Result<Vec<u8>, String>`
+///
+/// Usage: ```generate_image(&req_image, is_stale, config, resize_limit).await;```
+async fn generate_image(
+    req_image: &RequestedImage,
+    is_stale: bool,
+    config: &Config,
+    resize_limit: &ResizeLimit,
+) -> Result<Vec<u8>, String> {
+    // a stale image can't be replaced when the disk is only read from
+    let in_memory = match config.disk_mode() {
+        DiskMode::DiskRw => false,
+        DiskMode::DiskReadOnly => is_stale || !req_image.exists(),
+        DiskMode::MemoryOnly => true,
+    };
+
+    if in_memory && !req_image.is_original() {
+        // resize the original image in memory only
+        let (contents, duration) = with_resize_permit(resize_limit, async {
+            let started = Instant::now();
+            req_image
+                .encode()
+                .await
+                .map(|contents| (contents, started.elapsed()))
+        })
+        .await?;
+        log_resize(req_image, contents.len(), duration, config).await;
+
+        return Ok(contents);
+    }
+
+    // create a new image from original if one doesn't exist already or is stale
+    let resized = is_stale || !req_image.exists();
+    let duration = match resized {
+        true => {
+            with_resize_permit(resize_limit, async {
+                let started = Instant::now();
+                req_image.save().await.map(|_| started.elapsed())
+            })
+            .await?
         }
+        false => Duration::ZERO,
+    };
 
-        // create a new image from original if one doesn't exist already
-        if !req_image.exists() {
-            match req_image.save() {
-                Ok(()) => (),
-                Err(reason) => return Err(send_400_response(reason.to_string())),
-            };
+    // read the original or new image
+    let contents = req_image.read().await?;
+
+    if resized {
+        log_resize(req_image, contents.len(), duration, config).await;
+    }
+
+    Ok(contents)
+}
+
+/// Runs a resize once a permit from the resize limit is available, so at most
+/// `max_concurrent_resizes` images are resized at once while further resizes wait their turn.
+/// Time spent waiting isn't part of the resize, so it's measured within `resize`.
+///
+/// Arguments:
+///
+/// * `resize_limit` - &ResizeLimit
+/// * `resize` - impl Future
+///
+/// Returns: `F::Output`
+///
+/// Usage: ```with_resize_permit(resize_limit, req_image.save()).await?;```
+pub async fn with_resize_permit<F: Future>(resize_limit: &ResizeLimit, resize: F) -> F::Output {
+    let _permit = resize_limit
+        .acquire()
+        .await
+        .expect("The resize limit is never closed.");
+
+    resize.await
+}
+
+/// Appends a line of json describing a resize to the configured `resize_log`, if any. The
+/// file is reopened for every line, so it can be rotated by external tooling.
+///
+/// Arguments:
+///
+/// * `req_image` - &RequestedImage
+/// * `bytes` - usize - size of the resized image
+/// * `duration` - Duration - time spent resizing the image
+/// * `config` - &Config
+///
+/// Usage: ```log_resize(&req_image, contents.len(), started.elapsed(), config).await;```
+async fn log_resize(req_image: &RequestedImage, bytes: usize, duration: Duration, config: &Config) {
+    let resize_log = match &config.resize_log {
+        Some(resize_log) => resize_log,
+        None => return,
+    };
+
+    let record = ResizeRecord {
+        path: get_string_path(&req_image.path),
+        pathname: &req_image.new_pathname,
+        ratio: req_image.ratio,
+        width: req_image.width,
+        bytes,
+        duration_ms: duration.as_secs_f64() * 1000.0,
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or_default(),
+    };
+
+    let line = match json::serde_json::to_string(&record) {
+        Ok(line) => line + "\n",
+        Err(reason) => {
+            request_warn!("Unable to serialize resize record: {}", reason);
+            return;
         }
+    };
 
-        // read the original or new image and store its contents into cache
-        match req_image.read().await {
-            Ok(contents) => cache.insert(req_image.new_pathname.clone(), contents),
-            Err(reason) => return Err(send_400_response(reason.to_string())),
-        };
+    let written = match OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(resize_log)
+        .await
+    {
+        // tokio writes in the background, so the line is flushed before the file is dropped
+        Ok(mut file) => match file.write_all(line.as_bytes()).await {
+            Ok(()) => file.flush().await,
+            Err(reason) => Err(reason),
+        },
+        Err(reason) => Err(reason),
+    };
 
-        info_!("Saved requested image into cache.");
+    if let Err(reason) = written {
+        request_warn!("Unable to write to the resize log: {}", reason);
     }
+}
 
-    // retrieve saved image from the cache
-    let cached_image = cache
-        .get(&req_image.new_pathname)
-        .expect("Unable to retrieve image entry from cache.");
+/// Logs the final statistics of the image cache once the server begins shutting down. The
+/// statistics are skipped, rather than waited on, if the cache is still locked.
+///
+/// Arguments: (none)
+///
+/// Returns: `AdHoc`
+///
+/// Usage: ```rocket::build().attach(serve::log_stats_on_shutdown());```
+pub fn log_stats_on_shutdown() -> AdHoc {
+    AdHoc::on_liftoff("cache stats", |rocket| {
+        Box::pin(async move {
+            let shutdown = rocket.shutdown();
+            let state = match rocket.state::<Cache>() {
+                Some(state) => state.clone(),
+                None => return,
+            };
 
-    info_!("Served requested image from cache.");
+            rocket::tokio::spawn(async move {
+                shutdown.await;
 
-    // respond with cached image
-    Ok(Custom(
-        req_image.content_type.unwrap(),
-        cached_image.to_vec(),
-    ))
+                let cache = match state.try_lock() {
+                    Ok(cache) => cache,
+                    Err(()) => {
+                        warn!("Unable to log cache stats, as the cache is still locked.");
+                        return;
+                    }
+                };
+
+                let stats = cache.stats();
+                let lookups = stats.hits + stats.misses;
+                let hit_ratio = match lookups {
+                    0 => 0.0,
+                    _ => stats.hits as f64 / lookups as f64 * 100.0,
+                };
+                let bytes: usize = cache.iter().map(|(_, image)| image.bytes.len()).sum();
+
+                info!(
+                    "Cache stats: {} of {} entries, {} bytes, {} hits, {} misses ({:.1}% hit ratio).",
+                    stats.len, stats.capacity, bytes, stats.hits, stats.misses, hit_ratio
+                );
+            });
+        })
+    })
+}
+
+/// Creates a file server for each of the `static_dirs`, ranked after the `static_dir` file
+/// server so that each directory is only searched when the previous ones don't contain a file.
+///
+/// Arguments:
+///
+/// * `config` - &Config
+///
+/// Returns: `Vec<Route>`
+///
+/// Usage: ```rocket.mount("/", extra_file_servers(&config));```
+fn extra_file_servers(config: &Config) -> Vec<Route> {
+    config
+        .static_dirs
+        .iter()
+        .zip(1..)
+        .flat_map(|(static_dir, rank)| {
+            let routes: Vec<Route> =
+                PrecompressedServer::new(static_dir, FILE_SERVER_RANK + rank).into();
+            routes
+        })
+        .collect()
+}
+
+// the git SHA and build timestamp are captured by the build script
+#[get("/version")]
+fn serve_version() -> Json<BuildInfo> {
+    Json(BuildInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        git_sha: env!("GIT_SHA"),
+        built_at: env!("BUILD_TIMESTAMP").parse().unwrap_or_default(),
+    })
 }
 
 pub fn main() -> AdHoc {
     AdHoc::on_ignite("serve", |rocket| async {
+        let config = Config::from_figment(rocket.figment())
+            .expect("Unable to parse the provided configuration.");
+
+        let mut cache = match config.cache_max_bytes {
+            Some(max_bytes) => {
+                LRUCache::with_limits(50, max_bytes, |image: &CachedImage| image.bytes.len())
+            }
+            None => LRUCache::<String, CachedImage>::new(50),
+        };
+        if config.verbose_cache_logging {
+            cache.set_on_evict(|pathname, _| request_info!("Evicted {} from cache.", pathname));
+        }
+        cache.set_ttl(config.cache_ttl_secs.map(Duration::from_secs));
+
         rocket
-            .mount("/", routes![serve_image])
-            .mount("/", FileServer::from(relative!("static")))
-            .manage(Mutex::new(LRUCache::<String, Vec<u8>>::new(50)))
+            .mount(
+                "/",
+                routes![
+                    serve_image,
+                    serve_pretty_image,
+                    serve_image_widths,
+                    serve_image_lqip,
+                    serve_image_srcset,
+                    serve_image_color,
+                    serve_image_meta,
+                    serve_image_blurhash,
+                    serve_image_validate,
+                    serve_image_variants,
+                    serve_version
+                ],
+            )
+            .mount(
+                "/",
+                PrecompressedServer::new(&config.static_dir, FILE_SERVER_RANK),
+            )
+            .mount("/", extra_file_servers(&config))
+            .manage(Mutex::new(cache))
+            .manage(Mutex::new(HashMap::<String, Mutex<()>>::new()))
+            .manage(Mutex::new(LRUCache::<String, Color>::new(50)))
+            .manage(Mutex::new(
+                LRUCache::<(PathBuf, SystemTime), Metadata>::new(50),
+            ))
+            .manage(Mutex::new(LRUCache::<(PathBuf, SystemTime), String>::new(
+                50,
+            )))
+            .manage(ResizeLimit::new(config.max_concurrent_resizes.max(1)))
+            .manage(config)
     })
 }
@@ -0,0 +1,217 @@
+use crate::config::Config;
+use crate::utils::{send_403_response, InvalidRequest};
+use hmac::{Hmac, Mac};
+use rocket::outcome::Outcome;
+use rocket::request::{self, FromRequest, Request};
+use sha2::Sha256;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Query parameter that carries the signature of a url.
+pub const SIGNATURE_PARAM: &str = "sig";
+
+/// Query parameter that carries when a signed url expires, in seconds since the unix epoch,
+/// which is signed along with the rest of the url.
+pub const EXPIRY_PARAM: &str = "exp";
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Signs a url's path and query (such as `/image/photo.png?width=400`) with a secret, which
+/// is the hex encoded HMAC-SHA256 of the url as it's requested.
+///
+/// Arguments:
+///
+/// * `secret` - &str
+/// * `url` - &str - without its `sig` parameter
+///
+/// Returns: `String`
+///
+/// Usage: ```sign(secret, "/image/photo.png?width=400");```
+pub fn sign(secret: &str, url: &str) -> String {
+    format!("{:x}", mac(secret, url).finalize().into_bytes())
+}
+
+/// Appends the signature of a url to its query, so that it can be requested when signed
+/// urls are required.
+///
+/// Arguments:
+///
+/// * `secret` - &str
+/// * `url` - &str
+///
+/// Returns: `String`
+///
+/// Usage: ```signed_url(secret, "/image/photo.png?width=400");```
+pub fn signed_url(secret: &str, url: &str) -> String {
+    let separator = match url.contains('?') {
+        true => '&',
+        false => '?',
+    };
+
+    format!(
+        "{}{}{}={}",
+        url,
+        separator,
+        SIGNATURE_PARAM,
+        sign(secret, url)
+    )
+}
+
+/// Determines if a bearer token matches a configured token. Their HMACs are compared rather
+/// than the tokens themselves, which takes as long however much of the tokens match, so a
+/// guess can't be refined by timing the response.
+///
+/// Arguments:
+///
+/// * `token` - &str - the configured token
+/// * `candidate` - &str
+///
+/// Returns: `bool`
+///
+/// Usage: ```tokens_match(token, header);```
+pub fn tokens_match(token: &str, candidate: &str) -> bool {
+    let expected = mac(token, token).finalize().into_bytes();
+
+    mac(token, candidate).verify_slice(&expected).is_ok()
+}
+
+/// Initializes an HMAC-SHA256 of a url, which accepts a secret of any length.
+///
+/// Arguments:
+///
+/// * `secret` - &str
+/// * `url` - &str
+///
+/// Returns: `HmacSha256`
+///
+/// Usage: ```mac(secret, url);```
+fn mac(secret: &str, url: &str) -> HmacSha256 {
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(url.as_bytes());
+
+    mac
+}
+
+/// Decodes a hex encoded signature, or `None` for anything that isn't hex.
+///
+/// Arguments:
+///
+/// * `signature` - &str
+///
+/// Returns: `Option<Vec<u8>>`
+///
+/// Usage: ```decode_hex(signature);```
+fn decode_hex(signature: &str) -> Option<Vec<u8>> {
+    if !signature.len().is_multiple_of(2) || !signature.is_ascii() {
+        return None;
+    }
+
+    (0..signature.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&signature[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// The signature of a request's url, which has to match the url's path and remaining query
+/// when a `signing_secret` is configured, along with when the url expires, if it does. Urls
+/// aren't verified when no secret is configured.
+///
+/// Like `RateLimit`, this guard never fails and the signature is verified by the handler,
+/// so that requests which are forwarded to other routes aren't rejected by it.
+pub struct SignedUrl<'r> {
+    secret: Option<&'r str>,
+    url: String,
+    signature: Option<&'r str>,
+    expires: Option<&'r str>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for SignedUrl<'r> {
+    type Error = &'static str;
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let uri = req.uri();
+        let mut url = uri.path().as_str().to_string();
+        let mut signature = None;
+        let mut expires = None;
+
+        // the url is signed with its parameters in the order that they're sent
+        let mut separator = '?';
+        for segment in uri
+            .query()
+            .into_iter()
+            .flat_map(|query| query.raw_segments())
+        {
+            match segment.as_str().split_once('=') {
+                Some((SIGNATURE_PARAM, value)) => {
+                    signature = Some(value);
+                    continue;
+                }
+                Some((EXPIRY_PARAM, value)) => expires = Some(value),
+                _ => (),
+            }
+
+            url.push(separator);
+            url.push_str(segment.as_str());
+            separator = '&';
+        }
+
+        Outcome::Success(SignedUrl {
+            secret: req
+                .rocket()
+                .state::<Config>()
+                .and_then(|config| config.signing_secret.as_deref()),
+            url,
+            signature,
+            expires,
+        })
+    }
+}
+
+impl SignedUrl<'_> {
+    /// Verifies that the url was signed with the `signing_secret`, comparing the signatures
+    /// in constant time, and that it hasn't expired. The expiry is only trusted once the
+    /// signature is verified, as it's signed along with the url.
+    ///
+    /// Arguments: (none)
+    ///
+    /// Returns: `Result<(), InvalidRequest>`
+    ///
+    /// Usage: ```signed_url.verify()?;```
+    pub fn verify(&self) -> Result<(), InvalidRequest> {
+        let secret = match self.secret {
+            Some(secret) => secret,
+            None => return Ok(()),
+        };
+
+        let signature = match self.signature {
+            Some(signature) => signature,
+            None => {
+                return Err(send_403_response(
+                    "The url must be signed with a sig parameter.".to_string(),
+                ))
+            }
+        };
+
+        decode_hex(signature)
+            .filter(|signature| mac(secret, &self.url).verify_slice(signature).is_ok())
+            .ok_or_else(|| send_403_response("The url's signature is invalid.".to_string()))?;
+
+        let expires = match self.expires {
+            Some(expires) => expires.parse::<u64>().map_err(|_| {
+                send_403_response("The url's expiry must be a unix timestamp.".to_string())
+            })?,
+            None => return Ok(()),
+        };
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or_default();
+
+        match now > expires {
+            true => Err(send_403_response("The url has expired.".to_string())),
+            false => Ok(()),
+        }
+    }
+}
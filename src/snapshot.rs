@@ -0,0 +1,126 @@
+use crate::config::Config;
+use crate::serve::{Cache, CachedImage};
+use crate::utils::image_content_type;
+use lrucache::LRUCache;
+use rocket::fairing::AdHoc;
+use rocket::tokio::task;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Writes the keys of the cached images to an index file, one per line and ordered from the
+/// most to the least recently accessed. Every key is the pathname of an image on disk, so
+/// their contents don't need to be written as well.
+///
+/// Arguments:
+///
+/// * `cache` - &LRUCache<String, CachedImage>
+/// * `index` - &Path
+///
+/// Returns: `io::Result<usize>` - the amount of keys that were written
+///
+/// Usage: ```write_index(&cache, &index)?;```
+pub fn write_index(cache: &LRUCache<String, CachedImage>, index: &Path) -> io::Result<usize> {
+    let contents: String = cache.iter().map(|(key, _)| format!("{}\n", key)).collect();
+    fs::write(index, contents)?;
+
+    Ok(cache.len())
+}
+
+/// Reads every image listed within an index file, from the least to the most recently
+/// accessed so that their order is preserved once they're inserted into the cache. Images
+/// that no longer exist, or are too large to cache, or aren't images, are skipped.
+///
+/// Arguments:
+///
+/// * `index` - &Path
+/// * `max_cacheable_bytes` - usize
+///
+/// Returns: `io::Result<Vec<(String, CachedImage)>>` - each image along with its key
+///
+/// Usage: ```cache.extend(read_index(&index, config.max_cacheable_bytes)?);```
+pub fn read_index(
+    index: &Path,
+    max_cacheable_bytes: usize,
+) -> io::Result<Vec<(String, CachedImage)>> {
+    let contents = fs::read_to_string(index)?;
+
+    let mut images = Vec::new();
+    for key in contents.lines().rev().filter(|key| !key.is_empty()) {
+        // content addressed originals and memory only images aren't saved under their key
+        let image = match fs::read(key) {
+            Ok(image) if image.len() <= max_cacheable_bytes => image,
+            _ => continue,
+        };
+        let content_type = match image_content_type(Path::new(key)) {
+            Some(content_type) => content_type,
+            None => continue,
+        };
+
+        images.push((
+            key.to_string(),
+            CachedImage::new(image, content_type, false),
+        ));
+    }
+
+    Ok(images)
+}
+
+/// Restores the cache from the configured `cache_index`, if it exists. The images are read on
+/// a blocking thread, as there may be as many of them as the cache holds.
+///
+/// Arguments: (none)
+///
+/// Returns: `AdHoc`
+///
+/// Usage: ```rocket::build().attach(snapshot::main());```
+pub fn main() -> AdHoc {
+    AdHoc::on_ignite("cache snapshot", |rocket| async {
+        let (config, state) = match (rocket.state::<Config>(), rocket.state::<Cache>()) {
+            (Some(config), Some(state)) => (config, state),
+            _ => return rocket,
+        };
+
+        // the index doesn't exist until the server has shut down once
+        let index = match &config.cache_index {
+            Some(index) if index.is_file() => index.clone(),
+            _ => return rocket,
+        };
+
+        let max_cacheable_bytes = config.max_cacheable_bytes;
+        let images =
+            match task::spawn_blocking(move || read_index(&index, max_cacheable_bytes)).await {
+                Ok(images) => images,
+                Err(reason) => Err(io::Error::from(reason)),
+            };
+        match images {
+            Ok(images) => {
+                let restored = images.len();
+                state.lock().await.extend(images);
+
+                info!("Restored {} images into the cache.", restored);
+            }
+            Err(reason) => warn!("Unable to restore the cache: {}", reason),
+        }
+
+        rocket
+    })
+}
+
+/// Writes the configured `cache_index` once the server has shut down, so the cache can be
+/// restored when it's next launched. It's awaited after the server's launch returns, rather
+/// than from a fairing, so the index is written in full before the process exits.
+///
+/// Arguments:
+///
+/// * `index` - &Path
+/// * `state` - &Cache
+///
+/// Usage: ```snapshot::save_index(&index, &state).await;```
+pub async fn save_index(index: &Path, state: &Cache) {
+    let cache = state.lock().await;
+    match write_index(&cache, index) {
+        Ok(written) => info!("Saved {} cached images into the cache index.", written),
+        Err(reason) => warn!("Unable to save the cache index: {}", reason),
+    }
+}
@@ -0,0 +1,125 @@
+use futures_locks::Mutex;
+use rocket::fairing::AdHoc;
+use rocket::serde::json::Json;
+use rocket::serde::Serialize;
+use rocket::State;
+use std::collections::HashMap;
+
+/// Amount of paths that request counts are kept for, past which the least requested path
+/// makes room for a newly requested one.
+const MAX_TRACKED_PATHS: usize = 1000;
+
+/// Amount of paths listed when a request doesn't provide `n`.
+const DEFAULT_TOP: usize = 10;
+
+pub type Counts = Mutex<RequestCounts>;
+
+/// An image path and how many times it has been requested.
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+struct TopEntry {
+    path: String,
+    requests: u64,
+}
+
+/// How many times each image path has been requested, which is kept apart from the cache so
+/// that popular images are still counted once they're evicted.
+#[derive(Debug)]
+pub struct RequestCounts {
+    counts: HashMap<String, u64>,
+    capacity: usize,
+}
+
+impl RequestCounts {
+    /// Initializes empty request counts that are kept for up to `capacity` paths.
+    ///
+    /// Arguments:
+    ///
+    /// * `capacity` - usize
+    ///
+    /// Returns: `RequestCounts`
+    ///
+    /// Usage: ```RequestCounts::with_capacity(1000);```
+    pub fn with_capacity(capacity: usize) -> Self {
+        RequestCounts {
+            counts: HashMap::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Counts a request for a path. A path that isn't counted yet replaces the least
+    /// requested path once the capacity is reached, so the counts never outgrow it.
+    ///
+    /// Arguments:
+    ///
+    /// * `path` - &str
+    ///
+    /// Usage: ```counts.record(&path);```
+    pub fn record(&mut self, path: &str) {
+        if let Some(count) = self.counts.get_mut(path) {
+            *count += 1;
+            return;
+        }
+
+        if self.counts.len() >= self.capacity {
+            let least_requested = self
+                .counts
+                .iter()
+                .min_by(|(a_path, a), (b_path, b)| a.cmp(b).then_with(|| b_path.cmp(a_path)))
+                .map(|(path, _)| path.clone());
+
+            match least_requested {
+                Some(least_requested) => self.counts.remove(&least_requested),
+                None => return,
+            };
+        }
+
+        self.counts.insert(path.to_string(), 1);
+    }
+
+    /// Retrieves the `n` most requested paths along with their counts, from the most to the
+    /// least requested, where paths with the same count are ordered by name.
+    ///
+    /// Arguments:
+    ///
+    /// * `n` - usize
+    ///
+    /// Returns: `Vec<(&str, u64)>`
+    ///
+    /// Usage: ```counts.top(10);```
+    pub fn top(&self, n: usize) -> Vec<(&str, u64)> {
+        let mut top: Vec<(&str, u64)> = self
+            .counts
+            .iter()
+            .map(|(path, count)| (path.as_str(), *count))
+            .collect();
+        top.sort_by(|(a_path, a), (b_path, b)| b.cmp(a).then_with(|| a_path.cmp(b_path)));
+        top.truncate(n);
+
+        top
+    }
+}
+
+#[get("/stats/top?<n>")]
+async fn list_top(n: Option<usize>, state: &State<Counts>) -> Json<Vec<TopEntry>> {
+    let counts = state.lock().await;
+
+    Json(
+        counts
+            .top(n.unwrap_or(DEFAULT_TOP))
+            .into_iter()
+            .map(|(path, requests)| TopEntry {
+                path: path.to_string(),
+                requests,
+            })
+            .collect(),
+    )
+}
+
+pub fn main() -> AdHoc {
+    AdHoc::on_ignite("stats", |rocket| async {
+        rocket
+            .mount("/", routes![list_top])
+            .manage(Mutex::new(RequestCounts::with_capacity(MAX_TRACKED_PATHS)))
+    })
+}
@@ -0,0 +1,108 @@
+use crate::config::Config;
+use rocket::fairing::AdHoc;
+use rocket::http::Status;
+use rocket::response::content::Html;
+use rocket::response::{self, Responder};
+use rocket::Request;
+use std::fs;
+use std::path::Path;
+
+/// Placeholder within a template that's replaced with the reason for the error.
+const REASON_PLACEHOLDER: &str = "{reason}";
+
+/// Page that's rendered for a 404 when no `not_found_template` is configured.
+const DEFAULT_NOT_FOUND_TEMPLATE: &str = "<!DOCTYPE html><html lang='en' style='height: 100%;'><head><meta charset='utf-8'><title>Resource Not Found</title></head><body style='height: 100%;margin: 0;'><div style='display: -webkit-box;display: -ms-flexbox;display: flex;-webkit-box-orient: vertical;-webkit-box-direction: normal;-ms-flex-direction: column;flex-direction: column;-webkit-box-pack: center;-ms-flex-pack: center;justify-content: center;height: 100%;'><h1 style='text-align:center;font-size:100px;margin:0;'>404 Not Found</h1><h1 style='text-align:center;'>{reason}</h1></body></div></html>";
+
+/// Page that's rendered for a 500 when no `server_error_template` is configured.
+const DEFAULT_SERVER_ERROR_TEMPLATE: &str = "<!DOCTYPE html><html lang='en' style='height: 100%;'><head><meta charset='utf-8'><title>Internal Server Error</title></head><body style='height: 100%;margin: 0;'><div style='display: -webkit-box;display: -ms-flexbox;display: flex;-webkit-box-orient: vertical;-webkit-box-direction: normal;-ms-flex-direction: column;flex-direction: column;-webkit-box-pack: center;-ms-flex-pack: center;justify-content: center;height: 100%;'><h1 style='text-align:center;font-size:100px;margin:0;'>500 Internal Server Error</h1><h1 style='text-align:center;'>{reason}</h1></body></div></html>";
+
+/// Error page templates, which are loaded once at startup.
+struct Templates {
+    not_found: String,
+    server_error: String,
+}
+
+/// An HTML error page that's rendered from the configured template for its status.
+#[derive(Debug)]
+pub struct ErrorPage {
+    status: Status,
+    reason: String,
+}
+
+impl ErrorPage {
+    /// Initialize an error page for a 404 or a 500 status.
+    ///
+    /// Arguments:
+    ///
+    /// * `status` - Status
+    /// * `reason` - String
+    ///
+    /// Usage: ```ErrorPage::new(Status::NotFound, reason);```
+    pub fn new(status: Status, reason: String) -> Self {
+        ErrorPage { status, reason }
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ErrorPage {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        let templates = req.rocket().state::<Templates>();
+
+        let not_found = self.status == Status::NotFound;
+        let template = match (not_found, templates) {
+            (true, Some(templates)) => templates.not_found.as_str(),
+            (true, None) => DEFAULT_NOT_FOUND_TEMPLATE,
+            (false, Some(templates)) => templates.server_error.as_str(),
+            (false, None) => DEFAULT_SERVER_ERROR_TEMPLATE,
+        };
+
+        let page = template.replace(REASON_PLACEHOLDER, &self.reason);
+
+        (self.status, Html(page)).respond_to(req)
+    }
+}
+
+/// Reads a configured template, or falls back to the default template when unset.
+///
+/// Arguments:
+///
+/// * `path` - Option<&Path>
+/// * `default` - &str
+///
+/// Returns: `std::io::Result<String>`
+///
+/// Usage: ```load_template(config.not_found_template.as_deref(), DEFAULT_NOT_FOUND_TEMPLATE);```
+fn load_template(path: Option<&Path>, default: &str) -> std::io::Result<String> {
+    match path {
+        Some(path) => fs::read_to_string(path),
+        None => Ok(default.to_string()),
+    }
+}
+
+pub fn main() -> AdHoc {
+    AdHoc::try_on_ignite("templates", |rocket| async {
+        let config = match Config::from_figment(rocket.figment()) {
+            Ok(config) => config,
+            Err(_) => return Err(rocket),
+        };
+
+        let not_found = load_template(
+            config.not_found_template.as_deref(),
+            DEFAULT_NOT_FOUND_TEMPLATE,
+        );
+        let server_error = load_template(
+            config.server_error_template.as_deref(),
+            DEFAULT_SERVER_ERROR_TEMPLATE,
+        );
+
+        match (not_found, server_error) {
+            (Ok(not_found), Ok(server_error)) => Ok(rocket.manage(Templates {
+                not_found,
+                server_error,
+            })),
+            (Err(reason), _) | (_, Err(reason)) => {
+                error!("Unable to read an error page template: {}", reason);
+                Err(rocket)
+            }
+        }
+    })
+}
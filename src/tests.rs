@@ -0,0 +1,3358 @@
+use crate::cleanup;
+use crate::config::Config;
+use crate::query::{ImageQuery, Parsed, Width};
+use crate::reqimage::{RequestedImage, VARIANTS_DIR};
+use crate::serve::{
+    get_or_compute, validate_request, with_resize_permit, Cache, CachedImage, ColorCache, InFlight,
+    ResizeLimit, Size,
+};
+use crate::signature::{sign, signed_url};
+use crate::snapshot;
+use crate::stats::RequestCounts;
+use crate::utils::get_string_path;
+use futures_locks::Mutex;
+use image::codecs::gif::{GifDecoder, GifEncoder};
+use image::imageops::FilterType;
+use image::{
+    AnimationDecoder, Delay, DynamicImage, Frame, GenericImageView, ImageFormat, Rgb, RgbImage,
+    Rgba, RgbaImage,
+};
+use lrucache::LRUCache;
+use rocket::figment::Figment;
+use rocket::form::Form;
+use rocket::http::{ContentType, Header, Status};
+use rocket::local::blocking::{Client, LocalResponse};
+use rocket::serde::json::{self, Value};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::{Cursor, Read};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// A static directory that's removed once a test is done with it.
+struct TestDir(PathBuf);
+
+impl TestDir {
+    fn new() -> Self {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+        let path = std::env::temp_dir().join(format!(
+            "rust-api-{}-{}",
+            process::id(),
+            COUNT.fetch_add(1, Ordering::SeqCst)
+        ));
+        fs::create_dir_all(&path).expect("Unable to create test directory");
+
+        TestDir(path)
+    }
+
+    fn path(&self) -> &Path {
+        &self.0
+    }
+
+    fn join(&self, name: &str) -> PathBuf {
+        self.0.join(name)
+    }
+
+    /// Writes a solid image, where jpegs drop the alpha channel.
+    fn image(&self, name: &str, width: u32, height: u32, pixel: [u8; 4]) -> PathBuf {
+        let path = self.join(name);
+        let image = DynamicImage::ImageRgba8(RgbaImage::from_pixel(width, height, Rgba(pixel)));
+        let image = match ImageFormat::from_path(&path) {
+            Ok(ImageFormat::Jpeg) => DynamicImage::ImageRgb8(image.to_rgb8()),
+            _ => image,
+        };
+        image.save(&path).expect("Unable to write test image");
+
+        path
+    }
+
+    /// Lists the files that were written into the directory besides `keep`.
+    fn written(&self, keep: &[&str]) -> Vec<String> {
+        list_files(&self.0, keep)
+    }
+
+    /// Lists the variants that were saved into the directory.
+    fn variants(&self) -> Vec<String> {
+        list_files(&self.join(VARIANTS_DIR), &[])
+    }
+}
+
+fn list_files(dir: &Path, keep: &[&str]) -> Vec<String> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+
+    let mut written: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .filter(|name| !keep.contains(&name.as_str()))
+        .collect();
+    written.sort();
+
+    written
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+fn figment(dir: &TestDir) -> Figment {
+    rocket::Config::figment()
+        .merge(("static_dir", dir.path()))
+        .merge(("log_level", "off"))
+}
+
+fn client(dir: &TestDir) -> Client {
+    client_with(figment(dir))
+}
+
+fn client_with(figment: Figment) -> Client {
+    Client::tracked(crate::app(figment)).expect("valid rocket instance")
+}
+
+fn cache_stats(client: &Client) -> lrucache::CacheStats {
+    let cache = client.rocket().state::<Cache>().expect("managed cache");
+    let cache = cache.try_lock().expect("unlocked cache");
+
+    cache.stats()
+}
+
+fn header(response: &LocalResponse, name: &str) -> Option<String> {
+    response.headers().get_one(name).map(String::from)
+}
+
+fn decode(response: LocalResponse) -> DynamicImage {
+    let contents = response.into_bytes().expect("response body");
+
+    image::load_from_memory(&contents).expect("decodable image")
+}
+
+/// Uploads a png as a multipart form, with the bearer token when one is given.
+fn upload(client: &Client, uri: &str, contents: &[u8], token: Option<&str>) -> Status {
+    let mut body = b"--boundary\r\nContent-Disposition: form-data; name=\"file\"; filename=\"photo.png\"\r\nContent-Type: image/png\r\n\r\n".to_vec();
+    body.extend_from_slice(contents);
+    body.extend_from_slice(b"\r\n--boundary--\r\n");
+
+    let mut request = client
+        .post(uri.to_string())
+        .header(Header::new(
+            "Content-Type",
+            "multipart/form-data; boundary=boundary",
+        ))
+        .body(body);
+    if let Some(token) = token {
+        request = request.header(Header::new("Authorization", format!("Bearer {}", token)));
+    }
+
+    request.dispatch().status()
+}
+
+fn empty_query<'r>() -> ImageQuery<'r> {
+    ImageQuery {
+        ratio: None,
+        width: None,
+        dpr: None,
+        filter: None,
+        allow_upscale: None,
+        progressive: None,
+        tint: None,
+        grayscale: None,
+        format: None,
+        bg: None,
+        quality: None,
+        webp_lossless: None,
+        quality_preset: None,
+        ephemeral: None,
+        original: None,
+    }
+}
+
+fn width(width: &str) -> Option<Parsed<Width>> {
+    Some(Parsed(width.parse()))
+}
+
+fn test_config() -> Config {
+    Figment::new().extract().expect("default configuration")
+}
+
+#[test]
+fn serves_original_image() {
+    let dir = TestDir::new();
+    dir.image("photo.png", 40, 20, [255, 0, 0, 255]);
+    let client = client(&dir);
+
+    let response = client.get("/image/photo.png").dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.content_type(), Some(ContentType::PNG));
+    assert_eq!(decode(response).dimensions(), (40, 20));
+    assert!(dir.written(&["photo.png"]).is_empty());
+}
+
+#[test]
+fn resizes_to_the_default_width_when_none_is_requested() {
+    let dir = TestDir::new();
+    dir.image("photo.png", 40, 20, [255, 0, 0, 255]);
+    dir.image("small.png", 8, 4, [255, 0, 0, 255]);
+    let client = client_with(figment(&dir).merge(("default_width", 16)));
+
+    // the default width is applied, along with a dpr
+    for (uri, width) in [
+        ("/image/photo.png", 16),
+        ("/image/photo.png?dpr=2", 32),
+        ("/image/photo.png?width=10", 10),
+        ("/image/photo.png?ratio=50", 20),
+        // a ratio of 0 still requests the original image
+        ("/image/photo.png?ratio=0", 40),
+        // and an image narrower than the default width isn't upscaled
+        ("/image/small.png", 8),
+    ] {
+        let response = client.get(uri).dispatch();
+        assert_eq!(response.status(), Status::Ok, "{}", uri);
+        assert_eq!(decode(response).width(), width, "{}", uri);
+    }
+    assert_eq!(
+        dir.variants(),
+        vec![
+            "photo_10w.png",
+            "photo_16w.png",
+            "photo_32w.png",
+            "photo_50.png"
+        ]
+    );
+}
+
+#[test]
+fn quiets_cache_logging_by_default() {
+    assert!(!test_config().verbose_cache_logging);
+
+    let dir = TestDir::new();
+    dir.image("photo.png", 40, 20, [255, 0, 0, 255]);
+    let figment = figment(&dir).merge(("verbose_cache_logging", true));
+    let config: Config = figment.extract().unwrap();
+    assert!(config.verbose_cache_logging);
+
+    // misses, saves and hits are logged without changing what's served
+    let client = client_with(figment);
+    for _ in 0..2 {
+        let response = client.get("/image/photo.png?width=10").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+    }
+    assert_eq!(cache_stats(&client).hits, 1);
+}
+
+#[test]
+fn serves_resized_image() {
+    let dir = TestDir::new();
+    dir.image("photo.png", 40, 20, [255, 0, 0, 255]);
+    let client = client(&dir);
+
+    let response = client.get("/image/photo.png?width=10").dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.content_type(), Some(ContentType::PNG));
+    assert_eq!(
+        header(&response, "X-Effective-Width").as_deref(),
+        Some("10")
+    );
+    assert_eq!(decode(response).dimensions(), (10, 5));
+    assert_eq!(dir.variants(), vec!["photo_10w.png"]);
+
+    let response = client.get("/image/photo.png?ratio=50").dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(decode(response).width(), 20);
+}
+
+#[test]
+fn multiplies_widths_by_the_dpr() {
+    let dir = TestDir::new();
+    dir.image("photo.png", 100, 50, [255, 0, 0, 255]);
+    let client = client_with(figment(&dir).merge(("max_width", 50)));
+
+    for (uri, width) in [
+        ("/image/photo.png?width=10&dpr=1", 10),
+        ("/image/photo.png?width=10&dpr=2", 20),
+        ("/image/photo.png?width=10&dpr=3", 30),
+        ("/image/photo.png?width=15&dpr=1.5", 23),
+        // the multiplied width is capped at the max width
+        ("/image/photo.png?width=40&dpr=2", 50),
+    ] {
+        let response = client.get(uri).dispatch();
+        assert_eq!(response.status(), Status::Ok, "{}", uri);
+        assert_eq!(
+            header(&response, "X-Effective-Width"),
+            Some(width.to_string()),
+            "{}",
+            uri
+        );
+        assert_eq!(decode(response).width(), width, "{}", uri);
+    }
+
+    // the width is still validated before it's multiplied
+    let response = client.get("/image/photo.png?width=60&dpr=1").dispatch();
+    assert_eq!(response.status(), Status::BadRequest);
+
+    // a width of 20 at 2x is the same image as a width of 40
+    let response = client.get("/image/photo.png?width=20&dpr=2").dispatch();
+    assert_eq!(
+        header(&response, "X-Effective-Width").as_deref(),
+        Some("40")
+    );
+    assert_eq!(
+        dir.variants(),
+        vec![
+            "photo_10w.png",
+            "photo_20w.png",
+            "photo_23w.png",
+            "photo_30w.png",
+            "photo_40w.png",
+            "photo_50w.png"
+        ]
+    );
+}
+
+#[test]
+fn saves_resized_images_into_the_cache_dir() {
+    let dir = TestDir::new();
+    let cache_dir = TestDir::new();
+    dir.image("photo.png", 40, 20, [255, 0, 0, 255]);
+    let client = client_with(figment(&dir).merge(("cache_dir", cache_dir.path())));
+
+    for uri in [
+        "/image/photo.png",
+        "/image/photo.png?width=10",
+        "/image/photo.png?ratio=50",
+    ] {
+        assert_eq!(client.get(uri).dispatch().status(), Status::Ok);
+    }
+
+    assert!(dir.written(&["photo.png"]).is_empty());
+    assert_eq!(
+        cache_dir.written(&[]),
+        vec!["photo_10w.png", "photo_50.png"]
+    );
+
+    // resized images aren't served from the static directory
+    let response = client.get("/photo_10w.png").dispatch();
+    assert_eq!(response.status(), Status::NotFound);
+}
+
+#[test]
+fn resizes_images_within_nested_directories_named_with_digits() {
+    let dir = TestDir::new();
+    for year in ["2023", "2024"] {
+        fs::create_dir_all(dir.join("gallery").join(year).join("10")).unwrap();
+    }
+    dir.image("gallery/2023/10/25.png", 40, 20, [255, 0, 0, 255]);
+    dir.image("gallery/2024/10/25.png", 40, 20, [0, 0, 255, 255]);
+    let client = client(&dir);
+
+    // images that share a name in different directories never share variants
+    let mut bodies = Vec::new();
+    for year in ["2023", "2024"] {
+        for query in ["width=20", "ratio=50"] {
+            let uri = format!("/image/gallery/{}/10/25.png?{}", year, query);
+            let response = client.get(&uri).dispatch();
+            assert_eq!(response.status(), Status::Ok, "{}", uri);
+            bodies.push(response.into_bytes().unwrap());
+        }
+
+        assert_eq!(
+            list_files(
+                &dir.join(VARIANTS_DIR).join("gallery").join(year).join("10"),
+                &[]
+            ),
+            vec!["25_20w.png", "25_50.png"]
+        );
+    }
+    assert_ne!(bodies[0], bodies[2]);
+    assert_ne!(bodies[1], bodies[3]);
+
+    let response = client
+        .get("/image/gallery/2023/10/25.png/variants")
+        .dispatch();
+    let variants: Vec<Value> = response.into_json().unwrap();
+    assert_eq!(variants.len(), 2);
+
+    // and the directories are mirrored within the cache directory as well
+    let cache_dir = TestDir::new();
+    let client = client_with(figment(&dir).merge(("cache_dir", cache_dir.path())));
+    let response = client
+        .get("/image/gallery/2023/10/25.png?width=10")
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(
+        list_files(&cache_dir.join("gallery").join("2023").join("10"), &[]),
+        vec!["25_10w.png"]
+    );
+}
+
+#[test]
+fn never_treats_variants_as_originals() {
+    let dir = TestDir::new();
+    dir.image("photo.png", 40, 20, [255, 0, 0, 255]);
+    let client = client(&dir);
+
+    client.get("/image/photo.png?ratio=50").dispatch();
+    assert_eq!(dir.variants(), vec!["photo_50.png"]);
+
+    // a variant-looking filename isn't resolved to its original image
+    for uri in [
+        "/image/photo_50.png",
+        "/image/photo_50.png?ratio=50",
+        "/image/photo_50.png?width=10",
+    ] {
+        let response = client.get(uri).dispatch();
+        assert_eq!(response.status(), Status::NotFound, "{}", uri);
+    }
+
+    // and saved variants can't be requested
+    for uri in [
+        "/.variants/photo_50.png",
+        "/image/.variants/photo_50.png",
+        "/image/.variants/photo_50.png?width=10",
+    ] {
+        let response = client.get(uri).dispatch();
+        assert_eq!(response.status(), Status::NotFound, "{}", uri);
+    }
+    assert_eq!(dir.variants(), vec!["photo_50.png"]);
+}
+
+#[test]
+fn resizes_originals_with_variant_looking_names() {
+    let dir = TestDir::new();
+    dir.image("photo.png", 40, 20, [255, 0, 0, 255]);
+    dir.image("photo_50.png", 80, 40, [0, 0, 255, 255]);
+    let client = client(&dir);
+
+    let response = client.get("/image/photo_50.png?ratio=50").dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let image = decode(response);
+    assert_eq!(image.width(), 40);
+    assert_eq!(image.get_pixel(0, 0), Rgba([0, 0, 255, 255]));
+    assert_eq!(dir.variants(), vec!["photo_50_50.png"]);
+}
+
+#[test]
+fn responds_404_for_missing_images() {
+    let dir = TestDir::new();
+    let client = client(&dir);
+
+    for uri in ["/image/missing.png", "/image/missing.png?width=10"] {
+        let response = client.get(uri).dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        assert_eq!(response.content_type(), Some(ContentType::HTML));
+    }
+}
+
+#[test]
+fn resizes_jpegs_with_upper_case_and_alias_extensions() {
+    let dir = TestDir::new();
+    dir.image("upper.JPG", 40, 20, [255, 0, 0, 255]);
+    dir.image("long.JPEG", 40, 20, [255, 0, 0, 255]);
+    // the image crate can't write a .jfif, though it's a jpeg like any other
+    fs::rename(
+        dir.image("alias.jpg", 40, 20, [255, 0, 0, 255]),
+        dir.join("alias.jfif"),
+    )
+    .unwrap();
+    let client = client(&dir);
+
+    for uri in [
+        "/image/upper.JPG?width=10",
+        "/image/long.JPEG?width=10",
+        "/image/alias.jfif?width=10",
+        "/image/alias.jfif/meta",
+    ] {
+        let response = client.get(uri).dispatch();
+        assert_eq!(response.status(), Status::Ok, "{}", uri);
+    }
+
+    let response = client.get("/image/alias.jfif?width=10").dispatch();
+    assert_eq!(response.content_type(), Some(ContentType::JPEG));
+    assert_eq!(decode(response).width(), 10);
+
+    // variants are saved with a normalized extension
+    assert_eq!(
+        dir.variants(),
+        vec!["alias_10w.jpeg", "long_10w.jpeg", "upper_10w.jpg"]
+    );
+}
+
+#[test]
+fn serves_images_with_mixed_case_extensions() {
+    let dir = TestDir::new();
+    let client = client(&dir);
+
+    for (name, content_type) in [
+        ("upper.PNG", ContentType::PNG),
+        ("mixed.Png", ContentType::PNG),
+        ("shout.BMP", ContentType::BMP),
+        ("scan.Jpg", ContentType::JPEG),
+    ] {
+        dir.image(name, 40, 20, [255, 0, 0, 255]);
+
+        for uri in [
+            format!("/image/{}", name),
+            format!("/image/{}?width=10", name),
+        ] {
+            let response = client.get(&uri).dispatch();
+            assert_eq!(response.status(), Status::Ok, "{}", uri);
+            assert_eq!(
+                response.content_type(),
+                Some(content_type.clone()),
+                "{}",
+                uri
+            );
+        }
+    }
+
+    // the originals keep their names, while their variants are named in lowercase
+    assert!(dir.join("mixed.Png").is_file());
+    assert_eq!(
+        dir.variants(),
+        vec![
+            "mixed_10w.png",
+            "scan_10w.jpg",
+            "shout_10w.bmp",
+            "upper_10w.png"
+        ]
+    );
+}
+
+#[test]
+fn distinguishes_malformed_paths_from_missing_images() {
+    let dir = TestDir::new();
+    dir.image("photo.png", 40, 20, [255, 0, 0, 255]);
+    let client = client(&dir);
+
+    // a path that can't name an image is a malformed request
+    for uri in [
+        "/image/photo",
+        "/image/photo?width=10",
+        "/image/photo/color",
+        "/image/photo.png?width=wide",
+    ] {
+        let response = client.get(uri).dispatch();
+        assert_eq!(response.status(), Status::BadRequest, "{}", uri);
+    }
+
+    // while a well-formed path can only be missing its image
+    for uri in [
+        "/image/missing.png",
+        "/image/missing.png?width=10",
+        "/image/missing.png/color",
+    ] {
+        let response = client.get(uri).dispatch();
+        assert_eq!(response.status(), Status::NotFound, "{}", uri);
+    }
+}
+
+#[test]
+fn responds_400_for_paths_with_forbidden_characters() {
+    let dir = TestDir::new();
+    dir.image("photo.png", 40, 20, [255, 0, 0, 255]);
+    fs::write(dir.join("icon.svg"), "<svg></svg>").unwrap();
+    let client = client(&dir);
+
+    for uri in [
+        "/image/pho%00to.png",
+        "/image/photo.png%00.svg",
+        "/image/pho%07to.png?width=10",
+        "/image/pho%1Bto.png/color",
+        "/image/icon%0A.svg",
+        "/image/pho%7Fto.png/meta",
+    ] {
+        let response = client.get(uri).dispatch();
+        assert_eq!(response.status(), Status::BadRequest, "{}", uri);
+    }
+
+    // further characters are only forbidden once they're configured
+    let uri = "/image/pho%7Cto.png";
+    assert_eq!(client.get(uri).dispatch().status(), Status::NotFound);
+    let client = client_with(figment(&dir).merge(("forbidden_path_characters", "<>:\"|?*")));
+    assert_eq!(client.get(uri).dispatch().status(), Status::BadRequest);
+    assert_eq!(
+        client.get("/image/photo.png").dispatch().status(),
+        Status::Ok
+    );
+}
+
+#[test]
+fn responds_400_for_invalid_requests() {
+    let dir = TestDir::new();
+    dir.image("photo.png", 40, 20, [255, 0, 0, 255]);
+    fs::write(dir.join("notes.txt"), "text").unwrap();
+    let client = client(&dir);
+
+    for uri in [
+        "/image/notes.txt",
+        "/image/photo.png?width=0",
+        "/image/photo.png?width=99999",
+        "/image/photo.png?width=wide",
+        "/image/photo.png?ratio=33",
+        "/image/photo.png?ratio=50&width=10",
+        "/image/photo.png?width=10&dpr=0",
+        "/image/photo.png?width=10&dpr=5",
+        "/image/photo.png?width=10&dpr=retina",
+        "/image/photo.png?ratio=50&dpr=2",
+        "/image/photo.png?dpr=2",
+        "/image/photo.png?width=10&filter=bicubic",
+        "/image/photo.png?progressive=true",
+        "/image/photo.png?tint=purple",
+        "/image/photo.png?bg=000000",
+        "/image/photo.png?format=avif",
+        "/image/photo.png?quality=50",
+        "/image/photo.png?format=webp&quality=0",
+        "/image/photo.png?format=jpeg&quality=101",
+        "/image/photo.png?webp_lossless=true",
+        "/image/photo.png?format=jpeg&webp_lossless=true",
+        "/image/photo.png?format=webp&bg=000000",
+    ] {
+        let response = client.get(uri).dispatch();
+        assert_eq!(response.status(), Status::BadRequest, "{}", uri);
+    }
+}
+
+#[test]
+fn serves_repeated_requests_from_cache() {
+    let dir = TestDir::new();
+    dir.image("photo.png", 40, 20, [255, 0, 0, 255]);
+    let client = client(&dir);
+
+    let first = client.get("/image/photo.png?width=10").dispatch();
+    let first = first.into_bytes().unwrap();
+    assert_eq!(cache_stats(&client).hits, 0);
+    assert_eq!(cache_stats(&client).len, 1);
+
+    let second = client.get("/image/photo.png?width=10").dispatch();
+    assert_eq!(second.into_bytes().unwrap(), first);
+    assert_eq!(cache_stats(&client).hits, 1);
+    assert_eq!(cache_stats(&client).len, 1);
+}
+
+#[test]
+fn responds_503_when_the_cache_stays_locked() {
+    let dir = TestDir::new();
+    dir.image("photo.png", 40, 20, [255, 0, 0, 255]);
+    let client = client_with(figment(&dir).merge(("cache_lock_timeout_ms", 50)));
+
+    // another request holding the lock for too long
+    let cache = client.rocket().state::<Cache>().unwrap();
+    let held = cache.try_lock().expect("unlocked cache");
+
+    let started = Instant::now();
+    let response = client.get("/image/photo.png?width=10").dispatch();
+    assert_eq!(response.status(), Status::ServiceUnavailable);
+    assert!(started.elapsed() < Duration::from_secs(5));
+
+    // the request succeeds once the lock is released
+    drop(held);
+    let response = client.get("/image/photo.png?width=10").dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(decode(response).width(), 10);
+    assert_eq!(cache_stats(&client).len, 1);
+}
+
+#[test]
+fn serves_ephemeral_images_without_writing_them() {
+    let dir = TestDir::new();
+    let original = fs::read(dir.image("photo.png", 40, 20, [255, 0, 0, 255])).unwrap();
+    fs::write(
+        dir.join("logo.svg"),
+        "<svg xmlns='http://www.w3.org/2000/svg'/>",
+    )
+    .unwrap();
+    let client = client(&dir);
+
+    for _ in 0..2 {
+        let response = client
+            .get("/image/photo.png?width=10&grayscale=true&format=webp&ephemeral=true")
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(ContentType::WEBP));
+        assert_eq!(header(&response, "X-Image-Width").as_deref(), Some("10"));
+    }
+
+    let response = client.get("/image/photo.png?ephemeral=true").dispatch();
+    assert_eq!(response.into_bytes().unwrap(), original);
+    let response = client.get("/image/logo.svg?ephemeral=true").dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let response = client
+        .get("/image/missing.png?width=10&ephemeral=true")
+        .dispatch();
+    assert_eq!(response.status(), Status::NotFound);
+
+    assert!(dir.written(&["photo.png", "logo.svg"]).is_empty());
+    let stats = cache_stats(&client);
+    assert_eq!((stats.len, stats.hits, stats.misses), (0, 0, 0));
+
+    // the same image is still saved and cached when it isn't ephemeral
+    let response = client.get("/image/photo.png?width=10").dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(dir.variants(), vec!["photo_10w.png"]);
+    assert_eq!(cache_stats(&client).len, 1);
+}
+
+#[test]
+fn regenerates_images_requested_with_no_cache() {
+    let dir = TestDir::new();
+    dir.image("photo.png", 40, 20, [255, 0, 0, 255]);
+    let log = dir.join("resize.log");
+    let client = client_with(figment(&dir).merge(("resize_log", &log)));
+    let resizes = || fs::read_to_string(&log).unwrap().lines().count();
+
+    client.get("/image/photo.png?width=10").dispatch();
+    client.get("/image/photo.png?width=10").dispatch();
+    assert_eq!(resizes(), 1);
+    assert_eq!(cache_stats(&client).hits, 1);
+
+    let response = client
+        .get("/image/photo.png?width=10")
+        .header(Header::new("Cache-Control", "max-age=0, No-Cache"))
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(decode(response).width(), 10);
+    assert_eq!(resizes(), 2);
+    assert_eq!(cache_stats(&client).hits, 1);
+
+    // the regenerated image repopulates the cache
+    client.get("/image/photo.png?width=10").dispatch();
+    assert_eq!(resizes(), 2);
+    assert_eq!(cache_stats(&client).hits, 2);
+    assert_eq!(cache_stats(&client).len, 1);
+
+    // the original image is read again, but never rewritten
+    let original = fs::read(dir.join("photo.png")).unwrap();
+    let response = client
+        .get("/image/photo.png")
+        .header(Header::new("Cache-Control", "no-cache"))
+        .dispatch();
+    assert_eq!(response.into_bytes().unwrap(), original);
+    assert_eq!(resizes(), 2);
+}
+
+#[test]
+fn reports_the_build_info() {
+    let dir = TestDir::new();
+    let client = client(&dir);
+
+    let response = client.get("/version").dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.content_type(), Some(ContentType::JSON));
+    let build: Value = response.into_json().unwrap();
+    assert_eq!(build["version"], env!("CARGO_PKG_VERSION"));
+    assert!(!build["git_sha"].as_str().unwrap().is_empty());
+    assert!(build["built_at"].as_u64().unwrap() > 0);
+}
+
+#[test]
+fn requires_signed_urls_when_a_secret_is_configured() {
+    let dir = TestDir::new();
+    dir.image("photo.png", 40, 20, [255, 0, 0, 255]);
+    let client = client_with(figment(&dir).merge(("signing_secret", "secret")));
+
+    for url in [
+        "/image/photo.png?width=10",
+        "/image/w:20/photo.png?format=webp",
+    ] {
+        let response = client.get(signed_url("secret", url)).dispatch();
+        assert_eq!(response.status(), Status::Ok, "{}", url);
+    }
+
+    let signature = sign("secret", "/image/photo.png?width=10");
+    for (url, reason) in [
+        (
+            "/image/photo.png?width=10".to_string(),
+            "The url must be signed",
+        ),
+        (
+            signed_url("other", "/image/photo.png?width=10"),
+            "The url's signature is invalid",
+        ),
+        (
+            format!("/image/photo.png?width=20&sig={}", signature),
+            "The url's signature is invalid",
+        ),
+        (
+            "/image/photo.png?width=10&sig=zz".to_string(),
+            "The url's signature is invalid",
+        ),
+    ] {
+        let response = client.get(&url).dispatch();
+        assert_eq!(response.status(), Status::Forbidden, "{}", url);
+        assert!(
+            response.into_string().unwrap().starts_with(reason),
+            "{}",
+            url
+        );
+    }
+
+    // the signature can be sent anywhere within the query
+    let response = client
+        .get(format!("/image/photo.png?sig={}&width=10", signature))
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+
+    // a batch resizes every width, so it's signed as well, along with the urls it responds with
+    for url in [
+        "/image/photo.png?widths=10,20",
+        "/image/photo.png/srcset?widths=10,20",
+    ] {
+        let response = client.get(url).dispatch();
+        assert_eq!(response.status(), Status::Forbidden, "{}", url);
+    }
+    let response = client
+        .get(signed_url("secret", "/image/photo.png?widths=10,20"))
+        .dispatch();
+    let urls: Value = response.into_json().unwrap();
+    for url in urls.as_object().unwrap().values() {
+        let url = url.as_str().unwrap();
+        assert!(url.contains("&sig="), "{}", url);
+        assert_eq!(client.get(url).dispatch().status(), Status::Ok, "{}", url);
+    }
+
+    // as are the routes that decode the original image
+    for url in [
+        "/image/photo.png?lqip=true",
+        "/image/photo.png/color",
+        "/image/photo.png/meta",
+        "/image/photo.png/blurhash",
+    ] {
+        let response = client.get(url).dispatch();
+        assert_eq!(response.status(), Status::Forbidden, "{}", url);
+        let response = client.get(signed_url("secret", url)).dispatch();
+        assert_eq!(response.status(), Status::Ok, "{}", url);
+    }
+
+    // urls aren't verified without a secret
+    let client = self::client(&dir);
+    let response = client.get("/image/photo.png?width=10&sig=zz").dispatch();
+    assert_eq!(response.status(), Status::Ok);
+}
+
+#[test]
+fn rejects_signed_urls_once_they_expire() {
+    let dir = TestDir::new();
+    dir.image("photo.png", 40, 20, [255, 0, 0, 255]);
+    let client = client_with(figment(&dir).merge(("signing_secret", "secret")));
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    let url = format!("/image/photo.png?width=10&exp={}", now + 60);
+    let response = client.get(signed_url("secret", &url)).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+
+    // the signature is valid, but the url has expired
+    let expired = format!("/image/photo.png?width=10&exp={}", now - 60);
+    let response = client.get(signed_url("secret", &expired)).dispatch();
+    assert_eq!(response.status(), Status::Forbidden);
+    assert_eq!(response.into_string().unwrap(), "The url has expired.");
+
+    // the expiry is signed, so it can't be extended
+    let extended = signed_url("secret", &expired)
+        .replace(&format!("exp={}", now - 60), &format!("exp={}", now + 60));
+    let response = client.get(extended).dispatch();
+    assert_eq!(
+        response.into_string().unwrap(),
+        "The url's signature is invalid."
+    );
+
+    let response = client
+        .get(signed_url("secret", "/image/photo.png?width=10&exp=soon"))
+        .dispatch();
+    assert_eq!(response.status(), Status::Forbidden);
+    assert!(response.into_string().unwrap().contains("unix timestamp"));
+}
+
+#[test]
+fn echoes_the_request_id() {
+    let dir = TestDir::new();
+    dir.image("photo.png", 40, 20, [255, 0, 0, 255]);
+    let client = client(&dir);
+
+    let response = client
+        .get("/image/photo.png?width=10")
+        .header(Header::new("X-Request-Id", "abc-123"))
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(
+        header(&response, "X-Request-Id").as_deref(),
+        Some("abc-123")
+    );
+
+    // a missing or unsafe id is replaced with a generated one, even on errors
+    for request in [
+        client.get("/image/missing.png"),
+        client
+            .get("/image/photo.png")
+            .header(Header::new("X-Request-Id", "abc 123\r\n")),
+    ] {
+        let response = request.dispatch();
+        let id = header(&response, "X-Request-Id").unwrap();
+        assert!(uuid::Uuid::parse_str(&id).is_ok(), "{}", id);
+    }
+}
+
+#[test]
+fn sends_a_digest_of_the_body_when_enabled() {
+    let dir = TestDir::new();
+    dir.image("photo.png", 40, 20, [255, 0, 0, 255]);
+
+    let plain = client(&dir);
+    let response = plain.get("/image/photo.png?width=10").dispatch();
+    assert_eq!(header(&response, "X-Content-SHA256"), None);
+
+    let client = client_with(figment(&dir).merge(("content_digest", true)));
+    // the first response generates the image, and the second is served from the cache
+    for _ in 0..2 {
+        let response = client.get("/image/photo.png?width=10").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let digest = header(&response, "X-Content-SHA256");
+        let body = response.into_bytes().unwrap();
+        assert_eq!(digest, Some(format!("{:x}", Sha256::digest(&body))));
+    }
+    assert_eq!(cache_stats(&client).hits, 1);
+}
+
+#[test]
+fn resizes_images_with_pretty_urls() {
+    let dir = TestDir::new();
+    fs::create_dir_all(dir.join("gallery")).unwrap();
+    dir.image("photo.jpg", 40, 20, [255, 0, 0, 255]);
+    dir.image("gallery/photo.jpg", 40, 20, [0, 0, 255, 255]);
+    let client = client(&dir);
+
+    for (pretty, uri) in [
+        ("/image/w:20/photo.jpg", "/image/photo.jpg?width=20"),
+        (
+            "/image/w:20_q:80/photo.jpg",
+            "/image/photo.jpg?width=20&quality=80",
+        ),
+        (
+            "/image/q:80_w:20/photo.jpg",
+            "/image/photo.jpg?width=20&quality=80",
+        ),
+        (
+            "/image/w:20/photo.jpg?format=webp",
+            "/image/photo.jpg?width=20&format=webp",
+        ),
+        (
+            "/image/w:10/gallery/photo.jpg",
+            "/image/gallery/photo.jpg?width=10",
+        ),
+    ] {
+        let response = client.get(pretty).dispatch();
+        assert_eq!(response.status(), Status::Ok, "{}", pretty);
+        let etag = header(&response, "ETag");
+        let body = response.into_bytes();
+
+        let response = client.get(uri).dispatch();
+        assert_eq!(header(&response, "ETag"), etag, "{}", pretty);
+        assert_eq!(response.into_bytes(), body, "{}", pretty);
+    }
+    assert_eq!(
+        dir.variants(),
+        vec![
+            "gallery",
+            "photo_20w.jpg",
+            "photo_20w.webp",
+            "photo_20w_q80.jpg"
+        ]
+    );
+
+    // a leading directory isn't mistaken for resize parameters, even when it's named like them
+    fs::create_dir_all(dir.join("w2023")).unwrap();
+    dir.image("w2023/photo.jpg", 40, 20, [0, 255, 0, 255]);
+    for uri in ["/image/gallery/photo.jpg", "/image/w2023/photo.jpg"] {
+        let response = client.get(uri).dispatch();
+        assert_eq!(response.status(), Status::Ok, "{}", uri);
+    }
+    let response = client.get("/image/w2023/photo.jpg?width=10").dispatch();
+    assert_eq!(decode(response).get_pixel(0, 0), Rgba([0, 255, 0, 255]));
+
+    for uri in [
+        "/image/w:20/photo.jpg?width=10",
+        "/image/w:20_w:30/photo.jpg",
+        "/image/w:0/photo.jpg",
+        "/image/w:20_q:0/photo.jpg",
+        "/image/h:20/photo.jpg",
+    ] {
+        let response = client.get(uri).dispatch();
+        assert_eq!(response.status(), Status::BadRequest, "{}", uri);
+    }
+    assert_eq!(
+        client.get("/image/w:20x/photo.jpg").dispatch().status(),
+        Status::NotFound
+    );
+}
+
+#[test]
+fn describes_why_an_image_cant_be_decoded() {
+    let dir = TestDir::new();
+    let photo = dir.image("photo.jpg", 400, 200, [255, 0, 0, 255]);
+    let contents = fs::read(&photo).unwrap();
+    fs::write(&photo, &contents[..contents.len() / 2]).unwrap();
+    fs::write(dir.join("photo.png"), b"\0\0\0 ftypavif\0\0\0\0").unwrap();
+    let client = client(&dir);
+
+    for (image, reason) in [
+        ("photo.jpg", "The image's data is corrupt or truncated"),
+        ("photo.png", "The image's format is unsupported"),
+    ] {
+        for uri in [
+            format!("/image/{}?width=10", image),
+            format!("/image/{}?lqip=true", image),
+            format!("/image/{}/color", image),
+        ] {
+            let response = client.get(&uri).dispatch();
+            assert_eq!(response.status(), Status::BadRequest, "{}", uri);
+            let message = response.into_string().unwrap();
+            assert!(message.starts_with(reason), "{}: {}", uri, message);
+        }
+    }
+}
+
+#[test]
+fn responds_400_for_empty_images() {
+    let dir = TestDir::new();
+    for image in ["photo.jpg", "photo.gif", "photo.webp", "logo.svg"] {
+        fs::write(dir.join(image), b"").unwrap();
+    }
+    let client = client(&dir);
+
+    for uri in [
+        "/image/photo.jpg",
+        "/image/photo.jpg?width=10",
+        "/image/photo.jpg?width=10&ephemeral=true",
+        "/image/w:10/photo.jpg",
+        "/image/photo.jpg?lqip=true",
+        "/image/photo.jpg?widths=10,20",
+        "/image/photo.jpg/color",
+        "/image/photo.jpg/meta",
+        "/image/photo.jpg/blurhash",
+        "/image/photo.gif?width=10",
+        "/image/photo.webp?width=10",
+        "/image/logo.svg",
+    ] {
+        let response = client.get(uri).dispatch();
+        assert_eq!(response.status(), Status::BadRequest, "{}", uri);
+        let message = response.into_string().unwrap();
+        assert!(
+            message.starts_with("The image is empty"),
+            "{}: {}",
+            uri,
+            message
+        );
+    }
+
+    assert!(dir.variants().is_empty());
+    assert_eq!(cache_stats(&client).len, 0);
+}
+
+#[rocket::async_test]
+async fn never_caches_more_images_than_its_capacity() {
+    let dir = TestDir::new();
+    dir.image("photo.png", 40, 20, [255, 0, 0, 255]);
+    let client = rocket::local::asynchronous::Client::tracked(crate::app(figment(&dir)))
+        .await
+        .unwrap();
+    let cache = client.rocket().state::<Cache>().expect("managed cache");
+    *cache.lock().await = LRUCache::new(1);
+
+    // every request inserts a different image, which evicts the image before it
+    let requests = [0, 20, 50, 75].map(|ratio| {
+        client
+            .get(format!("/image/photo.png?ratio={}", ratio))
+            .dispatch()
+    });
+    for response in rocket::futures::future::join_all(requests).await {
+        assert_eq!(response.status(), Status::Ok);
+        assert!(!response.into_bytes().await.unwrap().is_empty());
+        assert!(cache.lock().await.len() <= 1);
+    }
+    assert_eq!(cache.lock().await.len(), 1);
+}
+
+#[test]
+fn serves_images_from_the_configured_static_dir() {
+    let dir = TestDir::new();
+    let photo = dir.image("photo.png", 40, 20, [255, 0, 0, 255]);
+    let client = client(&dir);
+
+    for uri in ["/image/photo.png", "/photo.png"] {
+        let response = client.get(uri).dispatch();
+        assert_eq!(response.status(), Status::Ok, "{}", uri);
+        assert_eq!(
+            response.into_bytes(),
+            Some(fs::read(&photo).unwrap()),
+            "{}",
+            uri
+        );
+    }
+
+    // the crate's own static directory is no longer served
+    let response = client.get("/image/placeholder.png").dispatch();
+    assert_eq!(response.status(), Status::NotFound);
+}
+
+#[test]
+fn resizes_small_widths_with_the_fast_filter() {
+    let dir = TestDir::new();
+    let pattern = RgbaImage::from_fn(40, 20, |x, y| {
+        Rgba([
+            (x * 53 % 256) as u8,
+            (y * 97 % 256) as u8,
+            ((x + y) * 29 % 256) as u8,
+            255,
+        ])
+    });
+    pattern.save(dir.join("pattern.png")).unwrap();
+    let client = client_with(figment(&dir).merge(("fast_filter_width", 20)));
+    let resize = |uri: &str| client.get(uri).dispatch().into_bytes().unwrap();
+
+    let fast = resize("/image/pattern.png?width=10");
+    assert_eq!(fast, resize("/image/pattern.png?width=10&filter=triangle"));
+    assert_ne!(
+        fast,
+        resize("/image/pattern.png?width=10&filter=catmullrom")
+    );
+
+    // widths at or above it are still resized with the default filter
+    assert_eq!(
+        resize("/image/pattern.png?width=30"),
+        resize("/image/pattern.png?width=30&filter=catmullrom")
+    );
+}
+
+#[test]
+fn head_responds_with_the_same_headers() {
+    let dir = TestDir::new();
+    dir.image("photo.png", 40, 20, [255, 0, 0, 255]);
+    let client = client(&dir);
+
+    let head = client.head("/image/photo.png?width=10").dispatch();
+    assert_eq!(head.status(), Status::Ok);
+    let etag = header(&head, "ETag");
+    let width = header(&head, "X-Effective-Width");
+    assert!(etag.is_some());
+    assert!(head.into_bytes().unwrap_or_default().is_empty());
+    assert_eq!(cache_stats(&client).len, 1);
+
+    let get = client.get("/image/photo.png?width=10").dispatch();
+    assert_eq!(header(&get, "ETag"), etag);
+    assert_eq!(header(&get, "X-Effective-Width"), width);
+    assert_eq!(cache_stats(&client).hits, 1);
+}
+
+#[test]
+fn validates_unchanged_images_without_generating_them() {
+    let dir = TestDir::new();
+    dir.image("photo.png", 40, 20, [255, 0, 0, 255]);
+
+    let etag = {
+        let client = client(&dir);
+        let response = client.get("/image/photo.png?width=10").dispatch();
+        header(&response, "ETag").expect("etag")
+    };
+    fs::remove_dir_all(dir.join(VARIANTS_DIR)).unwrap();
+
+    // the restarted server's cache is empty, yet the client's copy is still unchanged
+    let client = client(&dir);
+    for if_none_match in [
+        etag.clone(),
+        format!("W/{}", etag),
+        format!("\"a\", {}", etag),
+    ] {
+        let response = client
+            .get("/image/photo.png?width=10")
+            .header(Header::new("If-None-Match", if_none_match))
+            .dispatch();
+        assert_eq!(response.status(), Status::NotModified);
+        assert_eq!(header(&response, "ETag").as_ref(), Some(&etag));
+        assert!(response.into_bytes().unwrap_or_default().is_empty());
+    }
+    assert_eq!(cache_stats(&client).misses, 0);
+    assert!(dir.variants().is_empty());
+
+    // another variant, or a regenerated one, is sent in full
+    for (uri, cache_control) in [
+        ("/image/photo.png?width=20", "max-age=0"),
+        ("/image/photo.png?width=10", "no-cache"),
+    ] {
+        let response = client
+            .get(uri)
+            .header(Header::new("If-None-Match", etag.clone()))
+            .header(Header::new("Cache-Control", cache_control))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok, "{}", uri);
+    }
+
+    // and so is the variant of a replaced original image
+    let original = dir.image("photo.png", 40, 20, [0, 0, 255, 255]);
+    fs::File::options()
+        .write(true)
+        .open(original)
+        .unwrap()
+        .set_modified(SystemTime::now() + Duration::from_secs(60))
+        .unwrap();
+    let response = client
+        .get("/image/photo.png?width=10")
+        .header(Header::new("If-None-Match", etag.clone()))
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_ne!(header(&response, "ETag"), Some(etag));
+}
+
+#[test]
+fn regenerates_a_stale_variant() {
+    let dir = TestDir::new();
+    dir.image("photo.png", 40, 20, [255, 0, 0, 255]);
+    let client = client(&dir);
+
+    let response = client.get("/image/photo.png?width=10").dispatch();
+    assert_eq!(decode(response).get_pixel(0, 0), Rgba([255, 0, 0, 255]));
+
+    let original = dir.image("photo.png", 40, 20, [0, 0, 255, 255]);
+    fs::File::options()
+        .write(true)
+        .open(original)
+        .unwrap()
+        .set_modified(SystemTime::now() + Duration::from_secs(60))
+        .unwrap();
+
+    let response = client.get("/image/photo.png?width=10").dispatch();
+    assert_eq!(decode(response).get_pixel(0, 0), Rgba([0, 0, 255, 255]));
+}
+
+#[test]
+fn resizes_every_frame_of_an_animated_gif() {
+    let dir = TestDir::new();
+    let frames = [[255, 0, 0, 255], [0, 0, 255, 255]].map(|pixel| {
+        Frame::from_parts(
+            RgbaImage::from_pixel(40, 20, Rgba(pixel)),
+            0,
+            0,
+            Delay::from_numer_denom_ms(100, 1),
+        )
+    });
+    let mut contents = Vec::new();
+    GifEncoder::new(&mut contents)
+        .encode_frames(frames)
+        .unwrap();
+    fs::write(dir.join("anim.gif"), contents).unwrap();
+    let client = client(&dir);
+
+    let response = client.get("/image/anim.gif?width=10").dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let contents = response.into_bytes().unwrap();
+    let frames = GifDecoder::new(Cursor::new(contents))
+        .unwrap()
+        .into_frames()
+        .collect_frames()
+        .unwrap();
+    assert_eq!(frames.len(), 2);
+    assert_eq!(frames[0].buffer().width(), 10);
+}
+
+#[test]
+fn serves_a_low_quality_placeholder() {
+    let dir = TestDir::new();
+    dir.image("photo.png", 40, 20, [255, 0, 0, 255]);
+    let client = client(&dir);
+
+    let response = client.get("/image/photo.png?lqip=true").dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert!(response
+        .into_string()
+        .unwrap()
+        .starts_with("data:image/jpeg;base64,"));
+}
+
+#[test]
+fn serves_the_average_color() {
+    let dir = TestDir::new();
+    dir.image("solid.png", 40, 20, [51, 102, 255, 255]);
+    let client = client(&dir);
+
+    let response = client.get("/image/solid.png/color").dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let color: Value = json::from_str(&response.into_string().unwrap()).unwrap();
+    assert_eq!(color["hex"], "#3366ff");
+    assert_eq!(color["r"], 51);
+
+    let response = client.get("/image/missing.png/color").dispatch();
+    assert_eq!(response.status(), Status::NotFound);
+}
+
+#[test]
+fn serves_metadata() {
+    let dir = TestDir::new();
+    let path = dir.image("photo.png", 40, 20, [255, 0, 0, 255]);
+    let client = client(&dir);
+
+    let response = client.get("/image/photo.png/meta").dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let meta: Value = json::from_str(&response.into_string().unwrap()).unwrap();
+    assert_eq!(meta["width"], 40);
+    assert_eq!(meta["height"], 20);
+    assert_eq!(meta["format"], "png");
+    assert_eq!(meta["bytes"], fs::metadata(path).unwrap().len());
+
+    let response = client.get("/image/missing.png/meta").dispatch();
+    assert_eq!(response.status(), Status::NotFound);
+}
+
+#[test]
+fn serves_a_blurhash() {
+    let dir = TestDir::new();
+    gradient(&dir, "gradient.png");
+    let client = client(&dir);
+
+    let response = client.get("/image/gradient.png/blurhash").dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let placeholder: Value = json::from_str(&response.into_string().unwrap()).unwrap();
+    let blurhash = placeholder["blurhash"].as_str().unwrap();
+
+    // 4x3 components => 1 size (`L`), 1 maximum and 4 dc characters, then 2 for each of
+    // the 11 ac components
+    assert_eq!(blurhash.len(), 28);
+    assert!(blurhash.starts_with('L'));
+    assert!(blurhash::decode(blurhash, 4, 3, 1.0).is_ok());
+
+    // the hash is cached, so it's computed the same way again
+    let response = client.get("/image/gradient.png/blurhash").dispatch();
+    let cached: Value = json::from_str(&response.into_string().unwrap()).unwrap();
+    assert_eq!(cached["blurhash"], blurhash);
+
+    let response = client.get("/image/missing.png/blurhash").dispatch();
+    assert_eq!(response.status(), Status::NotFound);
+}
+
+#[test]
+fn names_variants_after_their_parameters() {
+    let dir = TestDir::new();
+    dir.image("photo.png", 40, 20, [255, 0, 0, 255]);
+    let client = client(&dir);
+
+    for uri in [
+        "/image/photo.png?width=10&filter=nearest",
+        "/image/photo.png?width=10&grayscale=true&tint=ff0000",
+    ] {
+        assert_eq!(client.get(uri).dispatch().status(), Status::Ok);
+    }
+
+    assert_eq!(
+        dir.variants(),
+        vec!["photo_10w_gray_tintff0000.png", "photo_10w_nearest.png"]
+    );
+}
+
+#[test]
+fn clamps_widths_unless_upscaling_is_allowed() {
+    let dir = TestDir::new();
+    let original = fs::read(dir.image("photo.png", 40, 20, [255, 0, 0, 255])).unwrap();
+    let client = client(&dir);
+
+    // the original image is served as is, rather than re-encoded at its own width
+    for uri in [
+        "/image/photo.png?width=100",
+        "/image/photo.png?width=40",
+        "/image/photo.png?width=40&allow_upscale=true",
+    ] {
+        let response = client.get(uri).dispatch();
+        assert_eq!(
+            header(&response, "X-Effective-Width").as_deref(),
+            Some("40"),
+            "{}",
+            uri
+        );
+        assert_eq!(response.into_bytes().unwrap(), original, "{}", uri);
+    }
+    assert!(dir.variants().is_empty());
+
+    let response = client
+        .get("/image/photo.png?width=100&allow_upscale=true")
+        .dispatch();
+    assert_eq!(
+        header(&response, "X-Effective-Width").as_deref(),
+        Some("100")
+    );
+    assert_eq!(decode(response).width(), 100);
+}
+
+#[test]
+fn selects_a_filter_by_the_direction_of_the_resize() {
+    let roots = [PathBuf::from("static")];
+    let image = RequestedImage::new(&roots, Path::new("photo.png"), 0);
+    assert_eq!(image.resample_filter(40, 80), FilterType::Lanczos3);
+    assert_eq!(image.resample_filter(40, 20), FilterType::CatmullRom);
+    assert_eq!(image.resample_filter(40, 40), FilterType::CatmullRom);
+
+    let image = image
+        .with_filter(None, 30)
+        .with_default_filters(FilterType::Nearest, FilterType::Gaussian);
+    assert_eq!(image.resample_filter(10, 20), FilterType::Gaussian);
+    assert_eq!(image.resample_filter(40, 30), FilterType::Nearest);
+    // the fast filter only ever reduces an image
+    assert_eq!(image.resample_filter(40, 20), FilterType::Triangle);
+
+    // a requested filter is used in either direction
+    let image = image.with_filter(Some(FilterType::Triangle), 0);
+    assert_eq!(image.resample_filter(10, 20), FilterType::Triangle);
+    assert_eq!(image.resample_filter(40, 30), FilterType::Triangle);
+}
+
+#[test]
+fn upscales_and_downscales_with_the_configured_filters() {
+    let dir = TestDir::new();
+    RgbaImage::from_fn(4, 4, |x, y| match (x + y) % 2 {
+        0 => Rgba([0, 0, 0, 255]),
+        _ => Rgba([255, 255, 255, 255]),
+    })
+    .save(dir.join("checker.png"))
+    .unwrap();
+    let is_blended = |image: DynamicImage| {
+        image
+            .to_rgba8()
+            .pixels()
+            .any(|pixel| pixel[0] != 0 && pixel[0] != 255)
+    };
+
+    // the default filters blend the checker's pixels in either direction
+    let client = client(&dir);
+    let upscaled = client
+        .get("/image/checker.png?width=16&allow_upscale=true")
+        .dispatch();
+    assert!(is_blended(decode(upscaled)));
+
+    // the variants are saved elsewhere, so the variant saved above isn't read back
+    let client = client_with(
+        figment(&dir)
+            .merge(("upscale_filter", "nearest"))
+            .merge(("cache_dir", dir.join("cached"))),
+    );
+    let upscaled = client
+        .get("/image/checker.png?width=16&allow_upscale=true")
+        .dispatch();
+    assert!(!is_blended(decode(upscaled)));
+    let downscaled = client.get("/image/checker.png?width=3").dispatch();
+    assert!(is_blended(decode(downscaled)));
+
+    assert!(figment(&dir)
+        .merge(("downscale_filter", "bicubic"))
+        .extract::<Config>()
+        .is_err());
+}
+
+#[test]
+fn generates_a_batch_of_widths() {
+    let dir = TestDir::new();
+    dir.image("photo.png", 40, 20, [255, 0, 0, 255]);
+    let client = client(&dir);
+
+    let response = client.get("/image/photo.png?widths=10,20").dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let urls: Value = json::from_str(&response.into_string().unwrap()).unwrap();
+    assert_eq!(urls["10"], "/image/photo.png?width=10");
+    assert_eq!(urls["20"], "/image/photo.png?width=20");
+    assert_eq!(cache_stats(&client).len, 2);
+
+    for uri in [
+        "/image/photo.png?widths=10,,20",
+        "/image/photo.png?widths=1,2,3,4,5,6,7,8,9,10,11",
+    ] {
+        assert_eq!(client.get(uri).dispatch().status(), Status::BadRequest);
+    }
+}
+
+#[test]
+fn serves_a_srcset() {
+    let dir = TestDir::new();
+    dir.image("photo.png", 40, 20, [255, 0, 0, 255]);
+    let client = client(&dir);
+
+    let response = client
+        .get("/image/photo.png/srcset?widths=20,10")
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(
+        response.into_string().unwrap(),
+        "/image/photo.png?width=10 10w, /image/photo.png?width=20 20w"
+    );
+}
+
+#[test]
+fn serves_the_fallback_image_in_place_of_a_missing_image() {
+    let dir = TestDir::new();
+    dir.image("fallback.png", 40, 20, [255, 0, 0, 255]);
+
+    let client = client(&dir);
+    let response = client.get("/image/missing.png?width=10").dispatch();
+    assert_eq!(response.status(), Status::NotFound);
+    assert_eq!(response.content_type(), Some(ContentType::HTML));
+
+    let client = client_with(figment(&dir).merge(("fallback_image", "fallback.png")));
+    let response = client.get("/image/missing.png?width=10").dispatch();
+    assert_eq!(response.status(), Status::NotFound);
+    assert_eq!(response.content_type(), Some(ContentType::PNG));
+    assert_eq!(decode(response).width(), 10);
+}
+
+#[rocket::async_test]
+async fn coalesces_concurrent_misses() {
+    let dir = TestDir::new();
+    dir.image("photo.png", 400, 200, [255, 0, 0, 255]);
+    let log = dir.join("resize.log");
+    let figment = figment(&dir).merge(("resize_log", &log));
+    let client = rocket::local::asynchronous::Client::tracked(crate::app(figment))
+        .await
+        .unwrap();
+
+    let requests = (0..10).map(|_| client.get("/image/photo.png?width=100").dispatch());
+    for response in rocket::futures::future::join_all(requests).await {
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    assert_eq!(fs::read_to_string(log).unwrap().lines().count(), 1);
+}
+
+#[test]
+fn encodes_progressive_jpegs() {
+    let dir = TestDir::new();
+    dir.image("photo.jpg", 40, 20, [255, 0, 0, 255]);
+    let client = client(&dir);
+
+    let baseline = client.get("/image/photo.jpg?width=10").dispatch();
+    let baseline = baseline.into_bytes().unwrap();
+    let progressive = client
+        .get("/image/photo.jpg?width=10&progressive=true")
+        .dispatch();
+    assert_eq!(progressive.content_type(), Some(ContentType::JPEG));
+    let progressive = progressive.into_bytes().unwrap();
+
+    let has_marker =
+        |contents: &[u8], marker: u8| contents.windows(2).any(|bytes| bytes == [0xFF, marker]);
+    assert!(has_marker(&baseline, 0xC0) && !has_marker(&baseline, 0xC2));
+    assert!(has_marker(&progressive, 0xC2));
+}
+
+#[test]
+fn blends_images_toward_a_tint() {
+    let dir = TestDir::new();
+    dir.image("gray.png", 40, 20, [100, 100, 100, 255]);
+    let client = client(&dir);
+
+    let response = client
+        .get("/image/gray.png?width=10&tint=ff0000")
+        .dispatch();
+    assert_eq!(decode(response).get_pixel(0, 0), Rgba([177, 50, 50, 255]));
+
+    let response = client
+        .get("/image/gray.png?width=10&grayscale=true&tint=0000ff")
+        .dispatch();
+    assert_eq!(decode(response).get_pixel(0, 0), Rgba([50, 50, 177, 255]));
+}
+
+#[test]
+fn rejects_widths_that_are_not_allowed() {
+    let dir = TestDir::new();
+    dir.image("photo.png", 400, 200, [255, 0, 0, 255]);
+    let client = client_with(figment(&dir).merge(("allowed_widths", [100, 200])));
+
+    assert_eq!(
+        client.get("/image/photo.png?width=100").dispatch().status(),
+        Status::Ok
+    );
+    let response = client.get("/image/photo.png?width=150").dispatch();
+    assert_eq!(response.status(), Status::BadRequest);
+    assert!(response.into_string().unwrap().contains("100, 200"));
+}
+
+#[test]
+fn snaps_widths_to_the_nearest_allowed_width() {
+    let dir = TestDir::new();
+    dir.image("photo.png", 400, 200, [255, 0, 0, 255]);
+    let client = client_with(
+        figment(&dir)
+            .merge(("allowed_widths", [100, 200, 400]))
+            .merge(("width_mode", "snap")),
+    );
+
+    for (width, effective) in [(90, "100"), (160, "200"), (150, "100"), (410, "400")] {
+        let response = client
+            .get(format!("/image/photo.png?width={}", width))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(
+            header(&response, "X-Effective-Width").as_deref(),
+            Some(effective),
+            "{}",
+            width
+        );
+    }
+}
+
+#[test]
+fn refuses_allowed_widths_that_cant_be_requested() {
+    for widths in [[0, 100], [100, 5000]] {
+        let figment = Figment::new().merge(("allowed_widths", widths));
+        assert!(Config::from_figment(&figment).is_err(), "{:?}", widths);
+    }
+
+    let figment = Figment::new().merge(("allowed_widths", [100, 4096]));
+    assert!(Config::from_figment(&figment).is_ok());
+}
+
+#[test]
+fn limits_requests_per_client() {
+    let dir = TestDir::new();
+    dir.image("photo.png", 40, 20, [255, 0, 0, 255]);
+    let client = client_with(
+        figment(&dir)
+            .merge(("rate_limit_per_second", 0.001))
+            .merge(("rate_limit_burst", 2)),
+    );
+    let request = |ip: &str| {
+        client
+            .get("/image/photo.png?width=10")
+            .remote(format!("{}:8000", ip).parse().unwrap())
+            .dispatch()
+    };
+
+    assert_eq!(request("10.0.0.1").status(), Status::Ok);
+    assert_eq!(request("10.0.0.1").status(), Status::Ok);
+    let limited = request("10.0.0.1");
+    assert_eq!(limited.status(), Status::TooManyRequests);
+    assert!(header(&limited, "Retry-After").is_some());
+
+    // other clients and routes aren't limited
+    assert_eq!(request("10.0.0.2").status(), Status::Ok);
+    let response = client
+        .get("/image/photo.png/meta")
+        .remote("10.0.0.1:8000".parse().unwrap())
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+
+    // a client can't get a fresh bucket by sending its own `X-Real-IP`
+    let response = client
+        .get("/image/photo.png?width=10")
+        .remote("10.0.0.1:8000".parse().unwrap())
+        .header(Header::new("X-Real-IP", "10.0.0.3"))
+        .dispatch();
+    assert_eq!(response.status(), Status::TooManyRequests);
+
+    // unless it's sent by a trusted proxy, which limits each of its clients on their own
+    let client = client_with(
+        figment(&dir)
+            .merge(("rate_limit_per_second", 0.001))
+            .merge(("rate_limit_burst", 1))
+            .merge(("trusted_proxies", ["10.0.0.9"])),
+    );
+    let request = |ip: &str| {
+        client
+            .get("/image/photo.png?width=10")
+            .remote("10.0.0.9:8000".parse().unwrap())
+            .header(Header::new("X-Real-IP", ip.to_string()))
+            .dispatch()
+    };
+    assert_eq!(request("10.0.0.1").status(), Status::Ok);
+    assert_eq!(request("10.0.0.1").status(), Status::TooManyRequests);
+    assert_eq!(request("10.0.0.2").status(), Status::Ok);
+}
+
+#[test]
+fn allows_cross_origin_requests() {
+    let dir = TestDir::new();
+    dir.image("photo.png", 40, 20, [255, 0, 0, 255]);
+    let client =
+        client_with(figment(&dir).merge(("cors_allowed_origins", ["https://example.com"])));
+
+    let preflight = client
+        .options("/image/photo.png")
+        .header(Header::new("Origin", "https://example.com"))
+        .header(Header::new("Access-Control-Request-Headers", "x-custom"))
+        .dispatch();
+    assert_eq!(preflight.status(), Status::NoContent);
+    assert_eq!(
+        header(&preflight, "Access-Control-Allow-Origin").as_deref(),
+        Some("https://example.com")
+    );
+    assert_eq!(
+        header(&preflight, "Access-Control-Allow-Methods").as_deref(),
+        Some("GET, HEAD, OPTIONS")
+    );
+    assert_eq!(
+        header(&preflight, "Access-Control-Allow-Headers").as_deref(),
+        Some("x-custom")
+    );
+
+    let allowed = client
+        .get("/image/photo.png")
+        .header(Header::new("Origin", "https://example.com"))
+        .dispatch();
+    assert_eq!(
+        header(&allowed, "Access-Control-Allow-Origin").as_deref(),
+        Some("https://example.com")
+    );
+    assert_eq!(header(&allowed, "Vary").as_deref(), Some("Origin"));
+
+    let denied = client
+        .get("/image/photo.png")
+        .header(Header::new("Origin", "https://other.com"))
+        .dispatch();
+    assert_eq!(header(&denied, "Access-Control-Allow-Origin"), None);
+}
+
+#[test]
+fn compresses_text_responses() {
+    let dir = TestDir::new();
+    dir.image("photo.png", 40, 20, [255, 0, 0, 255]);
+    let client = client_with(figment(&dir).merge(("compression", true)));
+
+    let response = client
+        .get("/image/missing.png")
+        .header(Header::new("Accept-Encoding", "deflate, gzip"))
+        .dispatch();
+    assert_eq!(
+        header(&response, "Content-Encoding").as_deref(),
+        Some("gzip")
+    );
+    assert_eq!(
+        header(&response, "Vary").as_deref(),
+        Some("Accept-Encoding")
+    );
+    let mut page = String::new();
+    flate2::read::GzDecoder::new(Cursor::new(response.into_bytes().unwrap()))
+        .read_to_string(&mut page)
+        .unwrap();
+    assert!(page.contains("Resource was not found."));
+
+    // images are already compressed
+    let response = client
+        .get("/image/photo.png")
+        .header(Header::new("Accept-Encoding", "gzip"))
+        .dispatch();
+    assert_eq!(header(&response, "Content-Encoding"), None);
+    assert_eq!(header(&response, "Vary"), None);
+}
+
+#[test]
+fn varies_only_when_compression_is_negotiated() {
+    let dir = TestDir::new();
+
+    let compressing = client_with(figment(&dir).merge(("compression", true)));
+    let response = compressing.get("/image/missing.png").dispatch();
+    assert_eq!(header(&response, "Content-Encoding"), None);
+    assert_eq!(
+        header(&response, "Vary").as_deref(),
+        Some("Accept-Encoding")
+    );
+
+    let plain = client(&dir);
+    let response = plain
+        .get("/image/missing.png")
+        .header(Header::new("Accept-Encoding", "gzip"))
+        .dispatch();
+    assert_eq!(header(&response, "Vary"), None);
+}
+
+#[test]
+fn serves_brotli_precompressed_assets() {
+    let dir = TestDir::new();
+    fs::write(dir.join("app.css"), "body { color: red; }").unwrap();
+    fs::write(dir.join("app.css.br"), b"precompressed").unwrap();
+    let client = client_with(figment(&dir).merge(("compression", true)));
+
+    let response = client
+        .get("/app.css")
+        .header(Header::new("Accept-Encoding", "gzip, br"))
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.content_type(), Some(ContentType::CSS));
+    assert_eq!(header(&response, "Content-Encoding").as_deref(), Some("br"));
+    assert_eq!(
+        header(&response, "Vary").as_deref(),
+        Some("Accept-Encoding")
+    );
+    assert_eq!(response.into_bytes().unwrap(), b"precompressed");
+
+    // brotli is refused outright
+    let response = client
+        .get("/app.css")
+        .header(Header::new("Accept-Encoding", "br;q=0"))
+        .dispatch();
+    assert_eq!(header(&response, "Content-Encoding"), None);
+    assert_eq!(response.into_string().unwrap(), "body { color: red; }");
+}
+
+#[test]
+fn serves_plain_assets_without_a_brotli_sibling() {
+    let dir = TestDir::new();
+    fs::write(dir.join("app.js"), "console.log(1);").unwrap();
+    let client = client(&dir);
+
+    let response = client
+        .get("/app.js")
+        .header(Header::new("Accept-Encoding", "br"))
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.content_type(), Some(ContentType::JavaScript));
+    assert_eq!(header(&response, "Content-Encoding"), None);
+    assert_eq!(response.into_string().unwrap(), "console.log(1);");
+
+    assert_eq!(
+        client.get("/missing.js").dispatch().status(),
+        Status::NotFound
+    );
+}
+
+#[test]
+fn uploads_images_with_a_token() {
+    let dir = TestDir::new();
+    let source = TestDir::new();
+    let contents = fs::read(source.image("photo.png", 40, 20, [255, 0, 0, 255])).unwrap();
+    let upload = |client: &Client, uri: &str, token: Option<&str>| {
+        self::upload(client, uri, &contents, token)
+    };
+
+    let client = client(&dir);
+    assert_eq!(
+        upload(&client, "/image/photo.png", Some("secret")),
+        Status::Forbidden
+    );
+
+    let client = client_with(figment(&dir).merge(("upload_token", "secret")));
+    assert_eq!(
+        upload(&client, "/image/photo.png", Some("wrong")),
+        Status::Unauthorized
+    );
+    assert_eq!(
+        upload(&client, "/image/nested/photo.png", Some("secret")),
+        Status::Created
+    );
+    assert_eq!(fs::read(dir.join("nested/photo.png")).unwrap(), contents);
+    assert_eq!(
+        upload(&client, "/image/nested/photo.png", Some("secret")),
+        Status::Conflict
+    );
+    assert_eq!(
+        upload(
+            &client,
+            "/image/nested/photo.png?overwrite=true",
+            Some("secret")
+        ),
+        Status::Created
+    );
+    assert_eq!(
+        upload(&client, "/image/photo.gif", Some("secret")),
+        Status::BadRequest
+    );
+    assert_eq!(
+        upload(&client, "/image/pho%00to.png", Some("secret")),
+        Status::BadRequest
+    );
+}
+
+#[test]
+fn serves_an_overwritten_image_in_place_of_the_cached_one() {
+    let dir = TestDir::new();
+    let source = TestDir::new();
+    let red = fs::read(source.image("red.png", 40, 20, [255, 0, 0, 255])).unwrap();
+    let blue = fs::read(source.image("blue.png", 40, 20, [0, 0, 255, 255])).unwrap();
+    let client = client_with(figment(&dir).merge(("upload_token", "secret")));
+
+    for (contents, uri) in [
+        (&red, "/image/photo.png"),
+        (&blue, "/image/photo.png?overwrite=true"),
+    ] {
+        assert_eq!(
+            upload(&client, uri, contents, Some("secret")),
+            Status::Created
+        );
+
+        let response = client.get("/image/photo.png").dispatch();
+        assert_eq!(response.into_bytes().as_ref(), Some(contents));
+        let response = client.get("/image/photo.png?width=10").dispatch();
+        let pixel = decode(response).get_pixel(0, 0);
+        assert_eq!(
+            pixel,
+            image::load_from_memory(contents).unwrap().get_pixel(0, 0)
+        );
+        let response = client.get("/image/photo.png/color").dispatch();
+        let color: Value = json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(color["r"], pixel[0]);
+    }
+}
+
+#[test]
+fn responds_413_for_oversized_uploads() {
+    let dir = TestDir::new();
+    let upload = |client: &Client, contents: &[u8]| {
+        let mut body = b"--boundary\r\nContent-Disposition: form-data; name=\"file\"; filename=\"photo.png\"\r\nContent-Type: image/png\r\n\r\n".to_vec();
+        body.extend_from_slice(contents);
+        body.extend_from_slice(b"\r\n--boundary--\r\n");
+
+        client
+            .post("/image/photo.png")
+            .header(Header::new("Content-Length", body.len().to_string()))
+            .header(Header::new(
+                "Content-Type",
+                "multipart/form-data; boundary=boundary",
+            ))
+            .header(Header::new("Authorization", "Bearer secret"))
+            .body(body)
+            .dispatch()
+            .status()
+    };
+
+    let client = client_with(
+        figment(&dir)
+            .merge(("upload_token", "secret"))
+            .merge(("max_upload_bytes", 1024)),
+    );
+    assert_eq!(upload(&client, &[0; 2048]), Status::PayloadTooLarge);
+    assert!(!dir.join("photo.png").exists());
+
+    // the form limit is raised along with the upload limit
+    let client = client_with(
+        figment(&dir)
+            .merge(("upload_token", "secret"))
+            .merge(("max_upload_bytes", 4 * 1024 * 1024)),
+    );
+    let source = TestDir::new();
+    let contents = fs::read(source.image("photo.png", 40, 20, [255, 0, 0, 255])).unwrap();
+    let mut padded = contents.clone();
+    padded.resize(3 * 1024 * 1024, 0);
+    assert_eq!(upload(&client, &padded), Status::Created);
+    // whether the file streams past its limit, or the body's length outsizes the form limit
+    for len in [5, 20] {
+        assert_eq!(
+            upload(&client, &vec![0; len * 1024 * 1024]),
+            Status::PayloadTooLarge
+        );
+    }
+}
+
+#[test]
+fn lists_the_cached_variants_of_an_image() {
+    let dir = TestDir::new();
+    dir.image("photo.png", 40, 20, [255, 0, 0, 255]);
+    dir.image("icon.png", 40, 20, [0, 0, 255, 255]);
+    let client = client(&dir);
+
+    for uri in [
+        "/image/photo.png?width=10",
+        "/image/photo.png?width=20&format=webp",
+        "/image/icon.png?width=10",
+    ] {
+        assert_eq!(client.get(uri).dispatch().status(), Status::Ok);
+    }
+
+    let response = client.get("/image/photo.png/variants").dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let variants: Value = json::from_str(&response.into_string().unwrap()).unwrap();
+    let variants = variants.as_array().unwrap();
+    assert_eq!(variants.len(), 2);
+
+    // the most recently accessed variant is listed first
+    let described: Vec<(&str, u64, &str)> = variants
+        .iter()
+        .map(|variant| {
+            (
+                variant["pathname"].as_str().unwrap(),
+                variant["width"].as_u64().unwrap(),
+                variant["format"].as_str().unwrap(),
+            )
+        })
+        .collect();
+    assert!(described[0].0.ends_with("photo_20w.webp"));
+    assert_eq!((described[0].1, described[0].2), (20, "webp"));
+    assert!(described[1].0.ends_with("photo_10w.png"));
+    assert_eq!((described[1].1, described[1].2), (10, "png"));
+    assert!(variants
+        .iter()
+        .all(|variant| variant["bytes"].as_u64() > Some(0)));
+
+    let response = client.get("/image/missing.png/variants").dispatch();
+    assert_eq!(response.status(), Status::NotFound);
+}
+
+#[test]
+fn lists_the_stalest_cached_images() {
+    let dir = TestDir::new();
+    dir.image("photo.png", 40, 20, [255, 0, 0, 255]);
+    let client = client_with(figment(&dir).merge(("admin_token", "secret")));
+
+    for width in [10, 20, 10] {
+        client
+            .get(format!("/image/photo.png?width={}", width))
+            .dispatch();
+    }
+
+    let response = client.get("/admin/cache/stalest").dispatch();
+    assert_eq!(response.status(), Status::Unauthorized);
+
+    let response = client
+        .get("/admin/cache/stalest?limit=1")
+        .header(Header::new("Authorization", "Bearer secret"))
+        .dispatch();
+    let stalest: Value = json::from_str(&response.into_string().unwrap()).unwrap();
+    let pathnames: Vec<&str> = stalest
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|entry| entry["pathname"].as_str().unwrap())
+        .collect();
+    assert_eq!(pathnames.len(), 1);
+    assert!(pathnames[0].ends_with("photo_20w.png"));
+}
+
+#[test]
+fn ranks_the_most_requested_images() {
+    let dir = TestDir::new();
+    dir.image("photo.png", 40, 20, [255, 0, 0, 255]);
+    dir.image("icon.png", 40, 20, [0, 0, 255, 255]);
+    let client = client(&dir);
+
+    // every width of an image counts toward it, as do cache hits
+    for uri in [
+        "/image/icon.png",
+        "/image/photo.png?width=10",
+        "/image/photo.png?width=20",
+        "/image/photo.png?width=10",
+        "/image/icon.png?width=10",
+        "/image/missing.png",
+    ] {
+        client.get(uri).dispatch();
+    }
+
+    let response = client.get("/stats/top?n=2").dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let top: Value = json::from_str(&response.into_string().unwrap()).unwrap();
+    assert_eq!(
+        top,
+        json::json!([
+            { "path": "photo.png", "requests": 3 },
+            { "path": "icon.png", "requests": 2 },
+        ])
+    );
+
+    let response = client.get("/stats/top").dispatch();
+    let top: Value = json::from_str(&response.into_string().unwrap()).unwrap();
+    assert_eq!(top.as_array().unwrap().len(), 3);
+}
+
+#[test]
+fn bounds_the_tracked_request_counts() {
+    let mut counts = RequestCounts::with_capacity(2);
+    for path in ["a.png", "a.png", "b.png", "c.png", "c.png"] {
+        counts.record(path);
+    }
+
+    // the least requested path made room for a new one
+    assert_eq!(counts.top(10), vec![("a.png", 2), ("c.png", 2)]);
+}
+
+#[test]
+fn logs_resizes_on_a_miss_but_not_a_hit() {
+    let dir = TestDir::new();
+    dir.image("photo.png", 40, 20, [255, 0, 0, 255]);
+    let log = dir.join("resize.log");
+    let client = client_with(figment(&dir).merge(("resize_log", &log)));
+
+    client.get("/image/photo.png?width=10").dispatch();
+    client.get("/image/photo.png?width=10").dispatch();
+    client.get("/image/photo.png").dispatch();
+
+    let lines = fs::read_to_string(log).unwrap();
+    let lines: Vec<&str> = lines.lines().collect();
+    assert_eq!(lines.len(), 1);
+    let record: Value = json::from_str(lines[0]).unwrap();
+    assert_eq!(record["width"], 10);
+    assert!(record["pathname"]
+        .as_str()
+        .unwrap()
+        .ends_with("photo_10w.png"));
+    assert!(record["bytes"].as_u64().unwrap() > 0);
+}
+
+#[test]
+fn keys_variants_by_content_when_content_addressed() {
+    let dir = TestDir::new();
+    let photo = dir.image("photo.png", 40, 20, [255, 0, 0, 255]);
+    let client = client_with(figment(&dir).merge(("content_addressed", true)));
+
+    let response = client.get("/image/photo.png?width=10").dispatch();
+    assert_eq!(decode(response).get_pixel(0, 0), Rgba([255, 0, 0, 255]));
+    let before = dir.variants();
+    assert_eq!(before.len(), 1);
+
+    // variants are named after a truncated SHA-256 digest, which is stable across builds
+    let digest = format!("{:x}", Sha256::digest(fs::read(&photo).unwrap()));
+    assert_eq!(before[0], format!("photo_10w_{}.png", &digest[..16]));
+
+    // replacing the original image yields a new variant, even with an older modified time
+    dir.image("photo.png", 40, 20, [0, 0, 255, 255]);
+    let response = client.get("/image/photo.png?width=10").dispatch();
+    assert_eq!(decode(response).get_pixel(0, 0), Rgba([0, 0, 255, 255]));
+    let after = dir.variants();
+    assert_eq!(after.len(), 2);
+    assert_eq!(cache_stats(&client).len, 2);
+}
+
+#[test]
+fn short_circuits_the_favicon() {
+    let dir = TestDir::new();
+    dir.image("icon.png", 16, 16, [255, 0, 0, 255]);
+
+    let client = client(&dir);
+    let response = client.get("/favicon.ico").dispatch();
+    assert_eq!(response.status(), Status::NoContent);
+
+    let client = client_with(figment(&dir).merge(("favicon", "icon.png")));
+    let response = client.get("/favicon.ico").dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.content_type(), Some(ContentType::PNG));
+}
+
+#[test]
+fn sends_the_dimensions_of_the_served_image() {
+    let dir = TestDir::new();
+    dir.image("photo.png", 40, 20, [255, 0, 0, 255]);
+    dir.image("photo.jpg", 30, 90, [255, 0, 0, 255]);
+    fs::write(
+        dir.join("logo.svg"),
+        "<svg xmlns='http://www.w3.org/2000/svg'/>",
+    )
+    .unwrap();
+    let client = client(&dir);
+
+    let dimensions = |response: &LocalResponse| {
+        (
+            header(response, "X-Image-Width").and_then(|width| width.parse().ok()),
+            header(response, "X-Image-Height").and_then(|height| height.parse().ok()),
+        )
+    };
+
+    for uri in [
+        "/image/photo.png?width=10",
+        // served from the cache
+        "/image/photo.png?width=10",
+        "/image/photo.png?width=100",
+        "/image/photo.png?ratio=50&format=jpeg",
+        "/image/photo.jpg?width=11",
+        "/image/w:7/photo.jpg?format=webp",
+    ] {
+        let response = client.get(uri).dispatch();
+        let (width, height) = dimensions(&response);
+        let image = match response.content_type() == Some(ContentType::WEBP) {
+            true => {
+                let contents = response.into_bytes().unwrap();
+                let webp = webp::Decoder::new(&contents).decode().unwrap();
+                (webp.width(), webp.height())
+            }
+            false => decode(response).dimensions(),
+        };
+        assert_eq!((width, height), (Some(image.0), Some(image.1)), "{}", uri);
+    }
+
+    let response = client.get("/image/logo.svg").dispatch();
+    assert_eq!(dimensions(&response), (None, None));
+}
+
+#[test]
+fn computes_target_dimensions_without_resizing() {
+    let dir = TestDir::new();
+    dir.image("photo.png", 40, 20, [255, 0, 0, 255]);
+    dir.image("tall.png", 30, 91, [255, 0, 0, 255]);
+    let roots = [dir.path().to_path_buf()];
+    let client = client(&dir);
+
+    for (image, width, dimensions) in [
+        ("photo.png", 10, (10, 5)),
+        ("photo.png", 15, (15, 7)),
+        ("photo.png", 1, (1, 1)),
+        ("photo.png", 80, (80, 40)),
+        ("tall.png", 7, (7, 21)),
+        ("tall.png", 29, (29, 87)),
+    ] {
+        let req_image = RequestedImage::with_width(&roots, Path::new(image), Width(width));
+        assert_eq!(
+            req_image.target_dimensions(),
+            Ok(dimensions),
+            "{} {}",
+            image,
+            width
+        );
+
+        // the image is resized to the same dimensions
+        let response = client
+            .get(format!(
+                "/image/{}?width={}&allow_upscale=true",
+                image, width
+            ))
+            .dispatch();
+        assert_eq!(
+            decode(response).dimensions(),
+            dimensions,
+            "{} {}",
+            image,
+            width
+        );
+    }
+
+    let req_image = RequestedImage::new(&roots, Path::new("tall.png"), 50);
+    assert_eq!(req_image.target_dimensions(), Ok((15, 45)));
+    let req_image = RequestedImage::new(&roots, Path::new("tall.png"), 0);
+    assert_eq!(req_image.target_dimensions(), Ok((30, 91)));
+
+    let req_image = RequestedImage::new(&roots, Path::new("missing.png"), 50);
+    assert!(req_image.target_dimensions().is_err());
+}
+
+#[test]
+fn serves_vector_images_as_is() {
+    let dir = TestDir::new();
+    let svg = "<svg xmlns='http://www.w3.org/2000/svg' width='10' height='10'/>";
+    fs::write(dir.join("logo.svg"), svg).unwrap();
+    let client = client(&dir);
+
+    let response = client.get("/image/logo.svg?width=200").dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.content_type(), Some(ContentType::SVG));
+    assert_eq!(header(&response, "X-Effective-Width"), None);
+    assert_eq!(response.into_string().unwrap(), svg);
+    assert!(dir.written(&["logo.svg"]).is_empty());
+}
+
+#[test]
+fn serves_passthrough_formats_as_is() {
+    let dir = TestDir::new();
+    let gif = dir.image("anim.gif", 40, 20, [255, 0, 0, 255]);
+    dir.image("photo.png", 40, 20, [255, 0, 0, 255]);
+    fs::write(dir.join("asset.webp"), b"RIFF-optimized").unwrap();
+    let client = client_with(figment(&dir).merge(("passthrough_formats", ["gif", "WEBP"])));
+
+    for (uri, content_type, contents) in [
+        (
+            "/image/anim.gif?width=10&format=webp",
+            ContentType::GIF,
+            fs::read(&gif).unwrap(),
+        ),
+        (
+            "/image/asset.webp?width=10",
+            ContentType::WEBP,
+            b"RIFF-optimized".to_vec(),
+        ),
+    ] {
+        for _ in 0..2 {
+            let response = client.get(uri).dispatch();
+            assert_eq!(response.status(), Status::Ok, "{}", uri);
+            assert_eq!(
+                response.content_type(),
+                Some(content_type.clone()),
+                "{}",
+                uri
+            );
+            assert_eq!(response.into_bytes(), Some(contents.clone()), "{}", uri);
+        }
+    }
+    let stats = cache_stats(&client);
+    assert_eq!((stats.len, stats.hits), (2, 2));
+
+    // other formats are still resized
+    let response = client.get("/image/photo.png?width=10").dispatch();
+    assert_eq!(decode(response).width(), 10);
+    assert_eq!(dir.variants(), vec!["photo_10w.png"]);
+
+    let client = self::client(&dir);
+    let response = client.get("/image/anim.gif?width=10").dispatch();
+    assert_eq!(decode(response).width(), 10);
+}
+
+#[test]
+fn serves_the_original_image_when_requested() {
+    let dir = TestDir::new();
+    let photo = dir.image("photo.png", 40, 20, [255, 0, 0, 255]);
+    fs::write(dir.join("notes.txt"), b"notes").unwrap();
+    let client = client_with(figment(&dir).merge(("default_width", 16)));
+
+    for _ in 0..2 {
+        let response = client
+            .get("/image/photo.png?original=true&width=10&grayscale=true&format=webp")
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(ContentType::PNG));
+        assert_eq!(response.into_bytes(), Some(fs::read(&photo).unwrap()));
+    }
+    let stats = cache_stats(&client);
+    assert_eq!((stats.len, stats.hits), (1, 1));
+    assert!(dir.variants().is_empty());
+
+    // the default width still applies without it
+    let response = client.get("/image/photo.png").dispatch();
+    assert_eq!(decode(response).width(), 16);
+
+    let response = client.get("/image/missing.png?original=true").dispatch();
+    assert_eq!(response.status(), Status::NotFound);
+    let response = client.get("/image/notes.txt?original=true").dispatch();
+    assert_eq!(response.status(), Status::BadRequest);
+}
+
+#[test]
+fn serves_original_formats_that_are_never_resized() {
+    let dir = TestDir::new();
+    let svg = "<svg xmlns='http://www.w3.org/2000/svg' width='10' height='10'/>";
+    fs::write(dir.join("photo.svg"), svg).unwrap();
+    fs::write(dir.join("asset.webp"), b"RIFF-optimized").unwrap();
+    let client = client_with(figment(&dir).merge(("passthrough_formats", ["webp"])));
+
+    let response = client.get("/image/photo.svg?original=true").dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.content_type(), Some(ContentType::SVG));
+    assert_eq!(response.into_string().unwrap(), svg);
+
+    let response = client.get("/image/asset.webp?original=true").dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.into_bytes().unwrap(), b"RIFF-optimized");
+}
+
+#[test]
+fn sends_the_configured_cache_control_of_each_format() {
+    let dir = TestDir::new();
+    dir.image("photo.png", 40, 20, [255, 0, 0, 255]);
+    fs::write(dir.join("logo.svg"), "<svg></svg>").unwrap();
+
+    let client = self::client(&dir);
+    let response = client.get("/image/photo.png?width=10").dispatch();
+    assert_eq!(header(&response, "Cache-Control"), None);
+
+    let client = client_with(
+        figment(&dir)
+            .merge(("max_age_secs.PNG", 60))
+            .merge(("max_age_secs.default", 10)),
+    );
+    for (uri, cache_control) in [
+        ("/image/photo.png?width=10", "public, max-age=60"),
+        ("/image/photo.png", "public, max-age=60"),
+        (
+            "/image/photo.png?width=10&format=webp",
+            "public, max-age=10",
+        ),
+        ("/image/logo.svg", "public, max-age=10"),
+    ] {
+        let response = client.get(uri).dispatch();
+        assert_eq!(response.status(), Status::Ok, "{}", uri);
+        assert_eq!(
+            header(&response, "Cache-Control").as_deref(),
+            Some(cache_control),
+            "{}",
+            uri
+        );
+    }
+
+    // content addressed variants never change under the same name
+    let client = client_with(
+        figment(&dir)
+            .merge(("max_age_secs.png", 60))
+            .merge(("content_addressed", true)),
+    );
+    let response = client.get("/image/photo.png?width=10").dispatch();
+    assert_eq!(
+        header(&response, "Cache-Control").as_deref(),
+        Some("public, max-age=31536000, immutable")
+    );
+
+    let client = client_with(
+        figment(&dir)
+            .merge(("content_addressed", true))
+            .merge(("immutable_max_age_secs", 600)),
+    );
+    let response = client.get("/image/photo.png?width=10").dispatch();
+    assert_eq!(
+        header(&response, "Cache-Control").as_deref(),
+        Some("public, max-age=600, immutable")
+    );
+}
+
+#[test]
+fn flattens_transparency_when_converting_to_jpeg() {
+    let dir = TestDir::new();
+    dir.image("overlay.png", 40, 20, [255, 0, 0, 128]);
+    let client = client(&dir);
+
+    let response = client
+        .get("/image/overlay.png?format=jpeg&bg=0000ff")
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.content_type(), Some(ContentType::JPEG));
+    let pixel = decode(response).get_pixel(0, 0).0;
+    let near = |actual: u8, expected: u8| (actual as i16 - expected as i16).abs() <= 3;
+    assert!(
+        near(pixel[0], 128) && near(pixel[1], 0) && near(pixel[2], 127),
+        "{:?}",
+        pixel
+    );
+
+    // without a background, transparency is flattened onto white
+    let response = client.get("/image/overlay.png?format=jpeg").dispatch();
+    let pixel = decode(response).get_pixel(0, 0).0;
+    assert!(near(pixel[0], 255) && near(pixel[1], 127) && near(pixel[2], 127));
+    assert_eq!(
+        dir.variants(),
+        vec!["overlay_bg0000ff.jpg", "overlay_bgffffff.jpg"]
+    );
+}
+
+/// Writes a gradient, so that lossy encodings lose detail.
+fn gradient(dir: &TestDir, name: &str) -> PathBuf {
+    let path = dir.join(name);
+    RgbaImage::from_fn(64, 64, |x, y| {
+        Rgba([(x * 4) as u8, (y * 4) as u8, 128, 255])
+    })
+    .save(&path)
+    .unwrap();
+
+    path
+}
+
+#[test]
+fn encodes_lossless_webps() {
+    let dir = TestDir::new();
+    gradient(&dir, "gradient.png");
+    let client = client(&dir);
+
+    let png = client.get("/image/gradient.png?width=32").dispatch();
+    let png = decode(png);
+
+    let response = client
+        .get("/image/gradient.png?width=32&format=webp&webp_lossless=true")
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.content_type(), Some(ContentType::WEBP));
+    let contents = response.into_bytes().unwrap();
+    let webp = webp::Decoder::new(&contents).decode().unwrap();
+
+    // an opaque image is decoded without its alpha channel
+    let png = match webp.is_alpha() {
+        true => png.to_rgba8().into_raw(),
+        false => png.to_rgb8().into_raw(),
+    };
+    assert_eq!(&*webp, png.as_slice());
+
+    // a lossless webp ignores the quality, so it's the same variant
+    let response = client
+        .get("/image/gradient.png?width=32&format=webp&webp_lossless=true&quality=10")
+        .dispatch();
+    assert_eq!(response.into_bytes().unwrap(), contents);
+    assert_eq!(
+        dir.variants(),
+        vec!["gradient_32w.png", "gradient_32w_lossless.webp"]
+    );
+}
+
+#[test]
+fn resizes_webp_sources() {
+    let dir = TestDir::new();
+    let opaque = RgbImage::from_pixel(40, 20, Rgb([255, 0, 0]));
+    let opaque = webp::Encoder::from_rgb(opaque.as_raw(), 40, 20).encode(75.0);
+    fs::write(dir.join("photo.webp"), &*opaque).unwrap();
+    // the image crate alone can't decode a lossless webp with transparency
+    let transparent = RgbaImage::from_pixel(40, 20, Rgba([0, 0, 255, 128]));
+    let transparent = webp::Encoder::from_rgba(transparent.as_raw(), 40, 20).encode_lossless();
+    fs::write(dir.join("icon.webp"), &*transparent).unwrap();
+    let client = client(&dir);
+
+    for (image, alpha) in [("photo.webp", false), ("icon.webp", true)] {
+        let response = client.get(format!("/image/{}?width=10", image)).dispatch();
+        assert_eq!(response.status(), Status::Ok, "{}", image);
+        assert_eq!(response.content_type(), Some(ContentType::WEBP));
+        assert_eq!(
+            header(&response, "X-Effective-Width").as_deref(),
+            Some("10")
+        );
+        let contents = response.into_bytes().unwrap();
+        let webp = webp::Decoder::new(&contents).decode().unwrap();
+        assert_eq!(
+            (webp.width(), webp.height(), webp.is_alpha()),
+            (10, 5, alpha)
+        );
+
+        let response = client.get(format!("/image/{}/meta", image)).dispatch();
+        let meta: Value = response.into_json().unwrap();
+        assert_eq!(
+            (meta["width"].as_u64(), meta["format"].as_str()),
+            (Some(40), Some("webp"))
+        );
+    }
+
+    let response = client
+        .get("/image/photo.webp?width=10&format=jpeg")
+        .dispatch();
+    assert_eq!(response.content_type(), Some(ContentType::JPEG));
+    assert_eq!(decode(response).dimensions(), (10, 5));
+    assert_eq!(
+        dir.variants(),
+        vec!["icon_10w.webp", "photo_10w.webp", "photo_10w_bgffffff.jpg"]
+    );
+}
+
+#[test]
+fn encodes_lossy_images_with_a_quality() {
+    let dir = TestDir::new();
+    gradient(&dir, "gradient.png");
+    gradient(&dir, "gradient.jpg");
+    let client = client(&dir);
+    let size = |uri: &str| {
+        let response = client.get(uri).dispatch();
+        assert_eq!(response.status(), Status::Ok, "{}", uri);
+        response.into_bytes().unwrap().len()
+    };
+
+    let low = size("/image/gradient.png?format=webp&quality=10");
+    let default = size("/image/gradient.png?format=webp");
+    let high = size("/image/gradient.png?format=webp&quality=95");
+    assert!(
+        low < default && default < high,
+        "{} {} {}",
+        low,
+        default,
+        high
+    );
+
+    let low = size("/image/gradient.jpg?quality=10");
+    let high = size("/image/gradient.jpg?quality=95");
+    assert!(low < high, "{} {}", low, high);
+    let low = size("/image/gradient.jpg?quality=10&progressive=true");
+    let high = size("/image/gradient.jpg?quality=95&progressive=true");
+    assert!(low < high, "{} {}", low, high);
+
+    assert_eq!(
+        dir.variants(),
+        vec![
+            "gradient.webp",
+            "gradient_q10.jpg",
+            "gradient_q10.webp",
+            "gradient_q10_progressive.jpg",
+            "gradient_q95.jpg",
+            "gradient_q95.webp",
+            "gradient_q95_progressive.jpg",
+        ]
+    );
+}
+
+#[test]
+fn encodes_with_the_configured_default_quality() {
+    let dir = TestDir::new();
+    gradient(&dir, "gradient.png");
+    gradient(&dir, "gradient.jpg");
+    let bytes = |client: &Client, uri: &str| client.get(uri).dispatch().into_bytes().unwrap();
+
+    let explicit = client(&dir);
+    let low_jpeg = bytes(&explicit, "/image/gradient.jpg?width=32&quality=10");
+    let low_webp = bytes(
+        &explicit,
+        "/image/gradient.png?width=32&format=webp&quality=20",
+    );
+
+    let defaults = TestDir::new();
+    gradient(&defaults, "gradient.png");
+    gradient(&defaults, "gradient.jpg");
+    let client = client_with(
+        figment(&defaults)
+            .merge(("jpeg_quality", 10))
+            .merge(("webp_quality", 20)),
+    );
+    assert_eq!(bytes(&client, "/image/gradient.jpg?width=32"), low_jpeg);
+    assert_eq!(
+        bytes(&client, "/image/gradient.png?width=32&format=webp"),
+        low_webp
+    );
+
+    // a requested quality overrides the default
+    let high_jpeg = bytes(&client, "/image/gradient.jpg?width=32&quality=95");
+    assert!(high_jpeg.len() > low_jpeg.len());
+    assert_eq!(
+        high_jpeg,
+        bytes(&explicit, "/image/gradient.jpg?width=32&quality=95")
+    );
+}
+
+#[test]
+fn refuses_default_qualities_outside_of_1_to_100() {
+    for (name, quality) in [("jpeg_quality", 0), ("webp_quality", 101)] {
+        let figment = Figment::new().merge((name, quality));
+        assert!(Config::from_figment(&figment).is_err(), "{}", name);
+    }
+
+    let figment = Figment::new()
+        .merge(("jpeg_quality", 1))
+        .merge(("webp_quality", 100));
+    assert!(Config::from_figment(&figment).is_ok());
+}
+
+#[test]
+fn refuses_preset_qualities_outside_of_1_to_100() {
+    let preset = |quality: u8| {
+        Figment::new()
+            .merge(("quality_presets.sharp.filter", "lanczos3"))
+            .merge(("quality_presets.sharp.quality", quality))
+    };
+
+    for quality in [0, 101] {
+        assert!(
+            Config::from_figment(&preset(quality)).is_err(),
+            "{}",
+            quality
+        );
+    }
+    assert!(Config::from_figment(&preset(100)).is_ok());
+}
+
+#[test]
+fn serves_but_skips_caching_large_images() {
+    let dir = TestDir::new();
+    dir.image("photo.png", 400, 200, [255, 0, 0, 255]);
+    let client = client_with(figment(&dir).merge(("max_cacheable_bytes", 1)));
+
+    let response = client.get("/image/photo.png?width=100").dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(decode(response).width(), 100);
+    assert_eq!(cache_stats(&client).len, 0);
+}
+
+#[test]
+fn skips_the_disk_when_memory_only() {
+    let dir = TestDir::new();
+    dir.image("photo.png", 40, 20, [255, 0, 0, 255]);
+    let client = client_with(figment(&dir).merge(("memory_only", true)));
+
+    let response = client.get("/image/photo.png?width=10").dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(decode(response).width(), 10);
+    assert!(dir.written(&["photo.png"]).is_empty());
+    assert_eq!(cache_stats(&client).len, 1);
+}
+
+#[test]
+fn reads_and_writes_variants_according_to_the_disk_mode() {
+    // whether the seeded variant is served, and whether a new variant is saved
+    for (disk_mode, reads, writes) in [
+        ("disk_rw", true, true),
+        ("disk_read_only", true, false),
+        ("memory_only", false, false),
+    ] {
+        let dir = TestDir::new();
+        dir.image("photo.png", 40, 20, [255, 0, 0, 255]);
+        fs::create_dir_all(dir.join(VARIANTS_DIR)).unwrap();
+        dir.image(
+            &format!("{}/photo_10w.png", VARIANTS_DIR),
+            10,
+            5,
+            [0, 0, 255, 255],
+        );
+        let client = client_with(figment(&dir).merge(("disk_mode", disk_mode)));
+
+        let response = client.get("/image/photo.png?width=10").dispatch();
+        let expected = match reads {
+            true => Rgba([0, 0, 255, 255]),
+            false => Rgba([255, 0, 0, 255]),
+        };
+        assert_eq!(decode(response).get_pixel(0, 0), expected, "{}", disk_mode);
+
+        let response = client.get("/image/photo.png?width=20").dispatch();
+        assert_eq!(decode(response).width(), 20, "{}", disk_mode);
+        let expected = match writes {
+            true => vec!["photo_10w.png", "photo_20w.png"],
+            false => vec!["photo_10w.png"],
+        };
+        assert_eq!(dir.variants(), expected, "{}", disk_mode);
+        assert_eq!(cache_stats(&client).len, 2, "{}", disk_mode);
+    }
+}
+
+#[rocket::async_test]
+async fn removes_orphaned_variants() {
+    let dir = TestDir::new();
+    fs::create_dir_all(dir.join("gallery")).unwrap();
+    dir.image("photo.png", 40, 20, [255, 0, 0, 255]);
+    dir.image("photo_1.png", 40, 20, [255, 0, 0, 255]);
+    dir.image("gallery/kept.png", 40, 20, [255, 0, 0, 255]);
+    let client = rocket::local::asynchronous::Client::tracked(crate::app(figment(&dir)))
+        .await
+        .unwrap();
+
+    for uri in [
+        "/image/photo.png?width=10",
+        "/image/photo.png?format=webp",
+        "/image/photo_1.png?width=10",
+        "/image/gallery/kept.png?width=10",
+    ] {
+        let response = client.get(uri).dispatch().await;
+        assert_eq!(response.status(), Status::Ok, "{}", uri);
+    }
+    // a variant that could be named after an existing original image is kept
+    for orphan in ["gone_10w.png", "photo_2_10w.png", "gallery/photo_10w.png"] {
+        dir.image(
+            &format!("{}/{}", VARIANTS_DIR, orphan),
+            10,
+            5,
+            [0, 0, 0, 255],
+        );
+    }
+
+    // the variant of a deleted original image is removed from disk and the cache
+    dir.image("deleted.png", 40, 20, [255, 0, 0, 255]);
+    let response = client.get("/image/deleted.png?width=10").dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    fs::remove_file(dir.join("deleted.png")).unwrap();
+
+    let config = client.rocket().state::<Config>().unwrap();
+    let state = client.rocket().state::<Cache>().unwrap();
+    let deleted = get_string_path(dir.join(VARIANTS_DIR).join("deleted_10w.png"));
+    assert!(state.lock().await.contains_key(&deleted));
+
+    let variant_dirs = cleanup::variant_dirs(config);
+    assert_eq!(cleanup::clean_orphans(variant_dirs.clone(), state).await, 3);
+    assert!(!state.lock().await.contains_key(&deleted));
+    assert_eq!(
+        dir.variants(),
+        vec![
+            "gallery",
+            "photo.webp",
+            "photo_10w.png",
+            "photo_1_10w.png",
+            "photo_2_10w.png"
+        ]
+    );
+    assert_eq!(
+        list_files(&dir.join(VARIANTS_DIR).join("gallery"), &[]),
+        vec!["kept_10w.png"]
+    );
+    assert_eq!(cleanup::clean_orphans(variant_dirs, state).await, 0);
+}
+
+#[rocket::async_test]
+async fn removes_the_cached_images_of_deleted_originals() {
+    let dir = TestDir::new();
+    dir.image("photo.png", 40, 20, [255, 0, 0, 255]);
+    dir.image("kept.png", 40, 20, [255, 0, 0, 255]);
+    let client = rocket::local::asynchronous::Client::tracked(crate::app(figment(&dir)))
+        .await
+        .unwrap();
+
+    for uri in [
+        "/image/photo.png",
+        "/image/photo.png?width=10",
+        "/image/photo.png/color",
+        "/image/kept.png",
+    ] {
+        let response = client.get(uri).dispatch().await;
+        assert_eq!(response.status(), Status::Ok, "{}", uri);
+    }
+
+    // the cached original image is served until it's removed from the cache
+    fs::remove_file(dir.join("photo.png")).unwrap();
+    let response = client.get("/image/photo.png").dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+
+    let config = client.rocket().state::<Config>().unwrap();
+    let state = client.rocket().state::<Cache>().unwrap();
+    let colors = client.rocket().state::<ColorCache>().unwrap();
+    assert_eq!(
+        cleanup::clean_missing_originals(config, state, colors).await,
+        2
+    );
+    assert!(colors.lock().await.is_empty());
+    let response = client.get("/image/photo.png").dispatch().await;
+    assert_eq!(response.status(), Status::NotFound);
+    let response = client.get("/image/kept.png").dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(
+        cleanup::clean_missing_originals(config, state, colors).await,
+        0
+    );
+}
+
+#[rocket::async_test]
+async fn computes_missing_values_once() {
+    let state = Mutex::new(LRUCache::<String, u32>::new(2));
+    let computed = AtomicUsize::new(0);
+    let compute = || async {
+        computed.fetch_add(1, Ordering::SeqCst);
+        rocket::tokio::time::sleep(Duration::from_millis(10)).await;
+        Ok::<u32, ()>(42)
+    };
+
+    let key = "answer".to_string();
+    assert_eq!(get_or_compute(&state, key.clone(), compute).await, Ok(42));
+    assert_eq!(get_or_compute(&state, key.clone(), compute).await, Ok(42));
+    assert_eq!(computed.load(Ordering::SeqCst), 1);
+
+    // failures aren't cached
+    let failed = get_or_compute(&state, "error".to_string(), || async { Err::<u32, ()>(()) });
+    assert_eq!(failed.await, Err(()));
+    assert!(!state.lock().await.contains_key(&"error".to_string()));
+}
+
+#[rocket::async_test]
+async fn bounds_concurrent_resizes() {
+    let resize_limit = ResizeLimit::new(2);
+    let running = AtomicUsize::new(0);
+    let most_running = AtomicUsize::new(0);
+    let resize = || async {
+        let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+        most_running.fetch_max(now, Ordering::SeqCst);
+        rocket::tokio::time::sleep(Duration::from_millis(20)).await;
+        running.fetch_sub(1, Ordering::SeqCst);
+    };
+
+    // resizes beyond the limit queue rather than fail
+    let resizes = (0..6).map(|_| with_resize_permit(&resize_limit, resize()));
+    rocket::futures::future::join_all(resizes).await;
+
+    assert_eq!(most_running.load(Ordering::SeqCst), 2);
+    assert_eq!(running.load(Ordering::SeqCst), 0);
+}
+
+#[rocket::async_test]
+async fn serves_other_requests_during_a_resize() {
+    let dir = TestDir::new();
+    dir.image("large.png", 1000, 1000, [255, 0, 0, 255]);
+    dir.image("small.png", 40, 20, [0, 0, 255, 255]);
+    let client = rocket::local::asynchronous::Client::tracked(crate::app(figment(&dir)))
+        .await
+        .expect("valid rocket instance");
+
+    // the tests' runtime has a single worker thread, so a resize that blocked it would
+    // finish before the static file could be served
+    let finished = std::sync::Mutex::new(Vec::new());
+    let resize = async {
+        let response = client
+            .get("/image/large.png?width=900&filter=lanczos3")
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        finished.lock().unwrap().push("large");
+    };
+    let serve = async {
+        // only request the static file once the resize is underway
+        rocket::tokio::time::sleep(Duration::from_millis(100)).await;
+        let response = client.get("/small.png").dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        finished.lock().unwrap().push("small");
+    };
+    rocket::futures::join!(resize, serve);
+
+    assert_eq!(*finished.lock().unwrap(), vec!["small", "large"]);
+}
+
+#[test]
+fn caps_the_blocking_threads_of_the_runtime() {
+    let dir = TestDir::new();
+
+    // the most blocking tasks that ran at once on a runtime built from a configuration
+    let concurrency = |figment: Figment| {
+        let runtime = crate::build_runtime(&figment).unwrap();
+        let running = Arc::new(AtomicUsize::new(0));
+        let most = Arc::new(AtomicUsize::new(0));
+
+        runtime.block_on(async {
+            let tasks: Vec<_> = (0..4)
+                .map(|_| {
+                    let (running, most) = (running.clone(), most.clone());
+                    rocket::tokio::task::spawn_blocking(move || {
+                        let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                        most.fetch_max(now, Ordering::SeqCst);
+                        thread::sleep(Duration::from_millis(100));
+                        running.fetch_sub(1, Ordering::SeqCst);
+                    })
+                })
+                .collect();
+            for task in tasks {
+                task.await.unwrap();
+            }
+        });
+
+        most.load(Ordering::SeqCst)
+    };
+
+    assert_eq!(
+        concurrency(figment(&dir).merge(("max_blocking_threads", 1))),
+        1
+    );
+    assert!(concurrency(figment(&dir)) > 1);
+}
+
+#[rocket::async_test]
+async fn skips_resizes_of_dropped_requests() {
+    let dir = TestDir::new();
+    dir.image("photo.png", 40, 20, [255, 0, 0, 255]);
+    let figment = figment(&dir).merge(("max_concurrent_resizes", 1));
+    let client = rocket::local::asynchronous::Client::tracked(crate::app(figment))
+        .await
+        .expect("valid rocket instance");
+    let rocket = client.rocket();
+    let in_flight = rocket.state::<InFlight>().unwrap();
+
+    // a request that's dropped while it waits for a resize never resizes its image
+    let permit = rocket
+        .state::<ResizeLimit>()
+        .unwrap()
+        .acquire()
+        .await
+        .unwrap();
+    let request = client.get("/image/photo.png?width=10").dispatch();
+    let dropped = rocket::tokio::time::timeout(Duration::from_millis(50), request).await;
+    assert!(dropped.is_err());
+    drop(permit);
+
+    rocket::tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(dir.variants().is_empty());
+    assert!(in_flight.lock().await.is_empty());
+
+    let response = client.get("/image/photo.png?width=10").dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(dir.variants(), vec!["photo_10w.png"]);
+}
+
+#[rocket::async_test]
+async fn finishes_but_skips_caching_resizes_of_dropped_requests() {
+    let dir = TestDir::new();
+    dir.image("large.png", 1000, 1000, [255, 0, 0, 255]);
+    let client = rocket::local::asynchronous::Client::tracked(crate::app(figment(&dir)))
+        .await
+        .expect("valid rocket instance");
+    let rocket = client.rocket();
+
+    // a resize that's underway can't be interrupted, so its image is still saved
+    let request = client
+        .get("/image/large.png?width=900&filter=lanczos3")
+        .dispatch();
+    let dropped = rocket::tokio::time::timeout(Duration::from_millis(100), request).await;
+    assert!(dropped.is_err());
+
+    for _ in 0..200 {
+        if !dir.variants().is_empty() {
+            break;
+        }
+        rocket::tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    assert_eq!(dir.variants(), vec!["large_900w_lanczos3.png"]);
+
+    // but it isn't cached on behalf of a request that's gone
+    assert!(rocket.state::<Cache>().unwrap().lock().await.is_empty());
+    assert!(rocket.state::<InFlight>().unwrap().lock().await.is_empty());
+}
+
+#[test]
+fn searches_static_directories_in_order() {
+    let first = TestDir::new();
+    let second = TestDir::new();
+    first.image("shared.png", 40, 20, [255, 0, 0, 255]);
+    second.image("shared.png", 40, 20, [0, 0, 255, 255]);
+    second.image("second.png", 40, 20, [0, 255, 0, 255]);
+    let client = client_with(figment(&first).merge(("static_dirs", [second.path()])));
+
+    let response = client.get("/image/second.png?width=10").dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(decode(response).get_pixel(0, 0), Rgba([0, 255, 0, 255]));
+    assert_eq!(second.variants(), vec!["second_10w.png"]);
+
+    let response = client.get("/image/shared.png?width=10").dispatch();
+    assert_eq!(decode(response).get_pixel(0, 0), Rgba([255, 0, 0, 255]));
+
+    let response = client.get("/second.png").dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(
+        client.get("/image/missing.png").dispatch().status(),
+        Status::NotFound
+    );
+}
+
+#[test]
+fn purges_expired_images() {
+    let dir = TestDir::new();
+    dir.image("photo.png", 40, 20, [255, 0, 0, 255]);
+    let client = client_with(
+        figment(&dir)
+            .merge(("admin_token", "secret"))
+            .merge(("cache_ttl_secs", 1)),
+    );
+    let purge = || {
+        client
+            .post("/admin/cache/purge")
+            .header(Header::new("Authorization", "Bearer secret"))
+            .dispatch()
+            .into_string()
+            .unwrap()
+    };
+
+    client.get("/image/photo.png?width=10").dispatch();
+    client.get("/image/photo.png?width=20").dispatch();
+    assert_eq!(purge(), r#"{"purged":0}"#);
+
+    thread::sleep(Duration::from_millis(1100));
+    assert_eq!(purge(), r#"{"purged":2}"#);
+    assert_eq!(cache_stats(&client).len, 0);
+}
+
+#[test]
+fn round_trips_the_cache_index() {
+    let dir = TestDir::new();
+    dir.image("photo.png", 40, 20, [255, 0, 0, 255]);
+    dir.image("other.png", 40, 20, [0, 0, 255, 255]);
+    let index = dir.join("cache.index");
+    let figment = || figment(&dir).merge(("cache_index", &index));
+
+    let client = client_with(figment());
+    for uri in [
+        "/image/photo.png?width=10",
+        "/image/other.png?width=10",
+        "/image/photo.png?width=20",
+        "/image/photo.png?width=10",
+    ] {
+        client.get(uri).dispatch();
+    }
+    // the index is written once the server has shut down, which only `main` waits for
+    let keys: Vec<String> = {
+        let cache = client
+            .rocket()
+            .state::<Cache>()
+            .unwrap()
+            .try_lock()
+            .unwrap();
+        assert_eq!(snapshot::write_index(&cache, &index).unwrap(), 3);
+        cache.iter().map(|(key, _)| key.clone()).collect()
+    };
+
+    // and read back into the cache in the same order once it's launched again
+    fs::remove_file(dir.join(VARIANTS_DIR).join("other_10w.png")).unwrap();
+    let client = client_with(figment());
+    let restored: Vec<String> = {
+        let cache = client
+            .rocket()
+            .state::<Cache>()
+            .unwrap()
+            .try_lock()
+            .unwrap();
+        cache.iter().map(|(key, _)| key.clone()).collect()
+    };
+    let expected: Vec<String> = keys
+        .into_iter()
+        .filter(|key| !key.ends_with("other_10w.png"))
+        .collect();
+    assert_eq!(restored, expected);
+
+    let response = client.get("/image/photo.png?width=20").dispatch();
+    assert_eq!(decode(response).width(), 20);
+    assert_eq!(cache_stats(&client).hits, 1);
+}
+
+#[test]
+fn restores_only_cacheable_images_from_an_index() {
+    let dir = TestDir::new();
+    let small = dir.image("small.png", 4, 4, [255, 0, 0, 255]);
+    let large = dir.image("large.png", 400, 400, [255, 0, 0, 255]);
+    let index = dir.join("cache.index");
+    fs::write(
+        &index,
+        format!(
+            "{}\n{}\n{}\n",
+            get_string_path(&small),
+            get_string_path(dir.join("missing.png")),
+            get_string_path(&large)
+        ),
+    )
+    .unwrap();
+    let max_cacheable_bytes = fs::metadata(&small).unwrap().len() as usize;
+
+    let mut cache = LRUCache::new(10);
+    let images = snapshot::read_index(&index, max_cacheable_bytes).unwrap();
+    assert_eq!(images.len(), 1);
+    cache.extend(images);
+    assert_eq!(
+        cache
+            .get(&get_string_path(&small))
+            .map(|image| &image.bytes),
+        Some(&fs::read(small).unwrap())
+    );
+
+    let written = snapshot::write_index(&cache, &dir.join("written.index")).unwrap();
+    assert_eq!(written, 1);
+    assert!(snapshot::read_index(&dir.join("missing.index"), 0).is_err());
+}
+
+#[test]
+fn caches_the_content_type_and_etag_of_images() {
+    let dir = TestDir::new();
+    dir.image("photo.png", 40, 20, [255, 0, 0, 255]);
+    let client = client(&dir);
+
+    let response = client
+        .get("/image/photo.png?width=20&format=webp")
+        .dispatch();
+    let etag = header(&response, "ETag");
+    assert_eq!(response.content_type(), Some(ContentType::WEBP));
+
+    let cache = client.rocket().state::<Cache>().expect("managed cache");
+    let cached = cache.try_lock().expect("unlocked cache");
+    let (key, image) = cached.iter().next().expect("cached image");
+    assert_eq!(image.content_type, ContentType::WEBP);
+    assert_eq!(image.etag, etag);
+
+    // the restored image is read back from disk with the same content type, while its entity
+    // tag is only known once it's served
+    let index = dir.join("cache.index");
+    snapshot::write_index(&cached, &index).unwrap();
+    let mut restored = LRUCache::new(10);
+    restored.extend(snapshot::read_index(&index, usize::MAX).unwrap());
+    let restored: &CachedImage = restored.get(key).expect("restored image");
+    assert_eq!(restored.bytes, image.bytes);
+    assert_eq!(restored.content_type, image.content_type);
+    assert_eq!(restored.etag, None);
+    drop(cached);
+
+    let hit = client
+        .get("/image/photo.png?width=20&format=webp")
+        .dispatch();
+    assert_eq!(cache_stats(&client).hits, 1);
+    assert_eq!(hit.content_type(), Some(ContentType::WEBP));
+    assert_eq!(header(&hit, "ETag"), etag);
+}
+
+#[test]
+fn renders_configured_error_templates() {
+    let dir = TestDir::new();
+    let template = dir.join("404.html");
+    fs::write(&template, "<p>Missing: {reason}</p>").unwrap();
+    let client = client_with(figment(&dir).merge(("not_found_template", &template)));
+
+    for uri in ["/image/missing.png", "/missing"] {
+        let response = client.get(uri).dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        assert_eq!(response.content_type(), Some(ContentType::HTML));
+        assert_eq!(
+            response.into_string().unwrap(),
+            "<p>Missing: Resource was not found.</p>"
+        );
+    }
+}
+
+#[test]
+fn validates_requests_without_resizing() {
+    let dir = TestDir::new();
+    dir.image("photo.png", 40, 20, [255, 0, 0, 255]);
+    let client = client(&dir);
+
+    let response = client
+        .get("/image/photo.png/validate?width=10&filter=nearest")
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(
+        response.into_string().unwrap(),
+        r#"{"valid":true,"width":10,"height":5}"#
+    );
+
+    let svg = "<svg xmlns='http://www.w3.org/2000/svg'/>";
+    fs::write(dir.join("logo.svg"), svg).unwrap();
+    let response = client.get("/image/logo.svg/validate?width=10").dispatch();
+    assert_eq!(response.into_string().unwrap(), r#"{"valid":true}"#);
+
+    for (uri, status) in [
+        ("/image/missing.png/validate", Status::NotFound),
+        ("/image/photo.txt/validate", Status::BadRequest),
+        ("/image/photo.png/validate?width=99999", Status::BadRequest),
+        ("/image/photo.png/validate?ratio=33", Status::BadRequest),
+        (
+            "/image/photo.png/validate?filter=bicubic",
+            Status::BadRequest,
+        ),
+    ] {
+        assert_eq!(client.get(uri).dispatch().status(), status, "{}", uri);
+    }
+
+    assert!(dir.written(&["photo.png", "logo.svg"]).is_empty());
+    assert_eq!(cache_stats(&client).len, 0);
+}
+
+#[test]
+fn parses_widths() {
+    assert_eq!("200".parse(), Ok(Width(200)));
+    assert_eq!(" 64 ".parse(), Ok(Width(64)));
+    for invalid in ["0", "-5", "wide", "", "1.5", "99999999999"] {
+        assert!(invalid.parse::<Width>().is_err(), "{}", invalid);
+    }
+
+    // an invalid width is kept by the query so it's rejected, rather than discarded
+    let query = Form::<ImageQuery>::parse("width=200").unwrap();
+    assert_eq!(query.width, Some(Parsed(Ok(Width(200)))));
+    let query = Form::<ImageQuery>::parse("width=wide").unwrap();
+    assert!(matches!(query.width, Some(Parsed(Err(_)))));
+    let query = Form::<ImageQuery>::parse("ratio=50").unwrap();
+    assert_eq!(query.width, None);
+}
+
+#[test]
+fn quality_presets_select_a_filter_and_quality() {
+    let config = test_config();
+    let preset = |preset| ImageQuery {
+        width: width("200"),
+        quality_preset: Some(preset),
+        ..empty_query()
+    };
+
+    for (name, filter, quality) in [
+        ("fast", FilterType::Triangle, 60),
+        ("balanced", FilterType::CatmullRom, 75),
+        ("best", FilterType::Lanczos3, 90),
+    ] {
+        let validated = validate_request(&config, Path::new("photo.jpg"), &preset(name)).unwrap();
+        assert_eq!(validated.filter, Some(filter), "{}", name);
+        assert_eq!(validated.quality, Some(quality), "{}", name);
+
+        // only the filter applies to a format without a quality
+        let validated = validate_request(&config, Path::new("photo.png"), &preset(name)).unwrap();
+        assert_eq!(validated.filter, Some(filter), "{}", name);
+        assert_eq!(validated.quality, None, "{}", name);
+    }
+
+    // a requested filter or quality takes precedence over the preset's
+    let query = ImageQuery {
+        filter: Some("nearest"),
+        quality: Some("40"),
+        ..preset("best")
+    };
+    let validated = validate_request(&config, Path::new("photo.jpg"), &query).unwrap();
+    assert_eq!(validated.filter, Some(FilterType::Nearest));
+    assert_eq!(validated.quality, Some(40));
+
+    assert!(validate_request(&config, Path::new("photo.jpg"), &preset("sharpest")).is_err());
+
+    // the configured presets replace the default presets
+    let config: Config = Figment::new()
+        .merge(("quality_presets.sharp.filter", "gaussian"))
+        .merge(("quality_presets.sharp.quality", 95))
+        .extract()
+        .unwrap();
+    let validated = validate_request(&config, Path::new("photo.jpg"), &preset("sharp")).unwrap();
+    assert_eq!(validated.filter, Some(FilterType::Gaussian));
+    assert_eq!(validated.quality, Some(95));
+    assert!(validate_request(&config, Path::new("photo.jpg"), &preset("fast")).is_err());
+
+    let invalid = Figment::new().merge(("quality_presets.sharp.filter", "bicubic"));
+    assert!(invalid
+        .merge(("quality_presets.sharp.quality", 95))
+        .extract::<Config>()
+        .is_err());
+}
+
+#[test]
+fn validate_request_parses_a_query() {
+    let config = test_config();
+    let query = ImageQuery {
+        width: width("200"),
+        filter: Some("nearest"),
+        progressive: Some(true),
+        tint: Some("3366ff"),
+        format: Some("jpeg"),
+        bg: Some("000000"),
+        ..empty_query()
+    };
+
+    let validated = validate_request(&config, Path::new("photo.png"), &query).unwrap();
+    assert_eq!(validated.size, Size::Width(Width(200)));
+    assert_eq!(validated.filter, Some(FilterType::Nearest));
+    assert!(validated.progressive);
+    assert_eq!(validated.tint, Some([0x33, 0x66, 0xff]));
+    assert_eq!(validated.format, Some(ImageFormat::Jpeg));
+    assert_eq!(validated.background, [0, 0, 0]);
+
+    let validated = validate_request(&config, Path::new("photo.png"), &empty_query()).unwrap();
+    assert_eq!(validated.size, Size::Ratio(0));
+    assert_eq!(validated.background, [255, 255, 255]);
+    assert_eq!(validated.quality, None);
+
+    let query = ImageQuery {
+        format: Some("webp"),
+        quality: Some("40"),
+        ..empty_query()
+    };
+    let validated = validate_request(&config, Path::new("photo.png"), &query).unwrap();
+    assert_eq!(validated.format, Some(ImageFormat::WebP));
+    assert_eq!(validated.quality, Some(40));
+    assert!(!validated.lossless);
+
+    // a lossless webp ignores the quality
+    let query = ImageQuery {
+        webp_lossless: Some(true),
+        ..query
+    };
+    let validated = validate_request(&config, Path::new("photo.png"), &query).unwrap();
+    assert_eq!(validated.quality, None);
+    assert!(validated.lossless);
+
+    // vector images ignore their query
+    let query = ImageQuery {
+        width: width("wide"),
+        ..empty_query()
+    };
+    let validated = validate_request(&config, Path::new("logo.svg"), &query).unwrap();
+    assert!(validated.passthrough);
+}
+
+#[test]
+fn validate_request_rejects_invalid_queries() {
+    let config = test_config();
+    let path = Path::new("photo.png");
+    let invalid = [
+        ImageQuery {
+            width: width("0"),
+            ..empty_query()
+        },
+        ImageQuery {
+            ratio: Some("33"),
+            ..empty_query()
+        },
+        ImageQuery {
+            ratio: Some("50"),
+            width: width("10"),
+            ..empty_query()
+        },
+        ImageQuery {
+            filter: Some("bicubic"),
+            ..empty_query()
+        },
+        ImageQuery {
+            progressive: Some(true),
+            ..empty_query()
+        },
+        ImageQuery {
+            tint: Some("3366f"),
+            ..empty_query()
+        },
+        ImageQuery {
+            bg: Some("000000"),
+            ..empty_query()
+        },
+        ImageQuery {
+            format: Some("avif"),
+            ..empty_query()
+        },
+    ];
+
+    for query in invalid.iter() {
+        assert!(validate_request(&config, path, query).is_err());
+    }
+    assert!(validate_request(&config, Path::new("notes.txt"), &empty_query()).is_err());
+    assert!(validate_request(&config, Path::new("photo"), &empty_query()).is_err());
+}
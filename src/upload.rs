@@ -0,0 +1,183 @@
+use crate::config::Config;
+use crate::serve::{forget_image, Cache, ColorCache};
+use crate::signature::tokens_match;
+use crate::utils::{
+    get_file_path, get_string_path, has_forbidden_characters, has_supported_extension,
+    image_content_type, is_safe_path, send_400_response, send_409_response, send_413_response,
+    InvalidRequest, SUPPORTED_EXTENSIONS,
+};
+use rocket::data::{ByteUnit, Limits};
+use rocket::fairing::AdHoc;
+use rocket::form::{Form, FromForm};
+use rocket::fs::TempFile;
+use rocket::http::{ContentType, Status};
+use rocket::outcome::Outcome;
+use rocket::request::{self, FromRequest, Request};
+use rocket::response::status::Created;
+use rocket::State;
+use std::ffi::OsStr;
+use std::path::PathBuf;
+use tokio::fs;
+
+#[derive(FromForm)]
+struct Upload<'r> {
+    file: TempFile<'r>,
+}
+
+/// Request guard that ensures an `Authorization: Bearer <token>` header matches the
+/// configured `upload_token`. Uploads are disabled when no token is configured.
+struct UploadToken;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for UploadToken {
+    type Error = &'static str;
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let token = req
+            .rocket()
+            .state::<Config>()
+            .and_then(|config| config.upload_token.as_ref());
+
+        let header = req
+            .headers()
+            .get_one("Authorization")
+            .and_then(|header| header.strip_prefix("Bearer "));
+
+        match (token, header) {
+            (Some(token), Some(header)) if tokens_match(token, header) => {
+                Outcome::Success(UploadToken)
+            }
+            (None, _) => Outcome::Failure((Status::Forbidden, "Uploads are disabled.")),
+            _ => Outcome::Failure((Status::Unauthorized, "The upload token is invalid.")),
+        }
+    }
+}
+
+/// Request guard that refuses an upload with a 413 when its `Content-Length` outsizes the
+/// form limit, before any of its body is read.
+struct UploadLength;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for UploadLength {
+    type Error = &'static str;
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let limit = req.limits().get("data-form").unwrap_or(Limits::DATA_FORM);
+        let length = req
+            .headers()
+            .get_one("Content-Length")
+            .and_then(|length| length.parse::<u64>().ok());
+
+        match length {
+            Some(length) if length > limit.as_u64() => {
+                Outcome::Failure((Status::PayloadTooLarge, "The uploaded image is too large."))
+            }
+            _ => Outcome::Success(UploadLength),
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+#[post("/image/<path..>?<overwrite>", data = "<upload>")]
+async fn upload_image(
+    path: PathBuf,
+    overwrite: Option<bool>,
+    mut upload: Form<Upload<'_>>,
+    _token: UploadToken,
+    _length: UploadLength,
+    config: &State<Config>,
+    state: &State<Cache>,
+    colors: &State<ColorCache>,
+) -> Result<Created<String>, InvalidRequest> {
+    // ensure the path can't escape the root directory
+    if path.as_os_str().is_empty() || !is_safe_path(&path) {
+        return Err(send_400_response("The file path is invalid.".to_string()));
+    }
+
+    if has_forbidden_characters(&path, &config.forbidden_path_characters) {
+        return Err(send_400_response(
+            "The file path is invalid! It contains a forbidden character.".to_string(),
+        ));
+    }
+
+    // ensure the uploaded image can actually be processed
+    if !has_supported_extension(&path) {
+        return Err(send_400_response(format!(
+            "The image extension is not supported! It must be one of the following: {}.",
+            SUPPORTED_EXTENSIONS.join(", ")
+        )));
+    }
+
+    // ensure the uploaded content type matches the path's extension
+    let content_type = image_content_type(&path);
+    if upload.file.content_type() != content_type.as_ref() {
+        return Err(send_400_response(
+            "The uploaded content type doesn't match the file extension.".to_string(),
+        ));
+    }
+
+    // a limit configured for the file's extension can outsize `max_upload_bytes`
+    if upload.file.len() > config.max_upload_bytes {
+        return Err(send_413_response(format!(
+            "The uploaded image is too large! It must be at most {} bytes.",
+            config.max_upload_bytes
+        )));
+    }
+
+    let filepath = get_file_path(&config.static_dir, &path);
+    if filepath.exists() && !overwrite.unwrap_or(false) {
+        return Err(send_409_response(
+            "The image already exists! Use ?overwrite=true to replace it.".to_string(),
+        ));
+    }
+
+    // write the uploaded image into the root directory => <rootdir><path>
+    let saved = match filepath.parent() {
+        Some(parent) => fs::create_dir_all(parent).await,
+        None => Ok(()),
+    };
+    if let Err(reason) = saved.and(upload.file.copy_to(&filepath).await) {
+        return Err(send_400_response(format!(
+            "Unable to save image: {}",
+            reason
+        )));
+    }
+
+    info_!("Saved uploaded image to {}.", get_string_path(&filepath));
+
+    // the image that was replaced, along with its variants, mustn't be served from the cache
+    let forgotten = forget_image(config, &path, state, colors).await;
+    if forgotten > 0 {
+        info_!(
+            "Removed {} images of the replaced image from cache.",
+            forgotten
+        );
+    }
+
+    Ok(Created::new(format!("/image/{}", get_string_path(&path))))
+}
+
+pub fn main() -> AdHoc {
+    AdHoc::on_ignite("upload", |rocket| async {
+        let max_upload_bytes = match rocket.state::<Config>() {
+            Some(config) => config.max_upload_bytes,
+            None => return rocket.mount("/", routes![upload_image]),
+        };
+
+        // an upload is refused with a 413 as soon as it streams past the limit, rather than
+        // once it's been read in full. The form is given twice the room, so that the file
+        // reaches its limit well before the form is cut short, which is only a 400 unless the
+        // `Content-Length` already gave it away
+        let limits: Limits = rocket.figment().extract_inner("limits").unwrap_or_default();
+        let data_form = limits
+            .get("data-form")
+            .unwrap_or(Limits::DATA_FORM)
+            .max(ByteUnit::from(max_upload_bytes.saturating_mul(2)));
+        let limits = limits
+            .limit("file", ByteUnit::from(max_upload_bytes))
+            .limit("data-form", data_form);
+
+        let figment = rocket.figment().clone().merge(("limits", limits));
+        rocket.configure(figment).mount("/", routes![upload_image])
+    })
+}
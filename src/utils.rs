@@ -1,38 +1,232 @@
-use rocket::fs::relative;
-use rocket::response::content::Html;
-use rocket::response::status::{BadRequest, NotFound};
-use std::path::{Path, PathBuf};
+use crate::templates::ErrorPage;
+use rocket::http::{ContentType, Header, Status};
+use rocket::response::content::Custom;
+use rocket::response::status::{self, BadRequest, Conflict, Forbidden, NotFound};
+use rocket::response::{self, Responder};
+use rocket::Request;
+use std::ffi::OsStr;
+use std::path::{Component, Path, PathBuf};
+
+/// Image extensions that can be decoded, resized and re-encoded.
+pub const SUPPORTED_EXTENSIONS: [&str; 9] = [
+    "bmp", "gif", "ico", "jpeg", "jpg", "png", "tif", "tiff", "webp",
+];
+
+/// Extensions of vector images, which are served as is rather than resized.
+pub const VECTOR_EXTENSIONS: [&str; 1] = ["svg"];
+
+/// Less common extensions of jpegs, which are treated as `jpeg`.
+pub const JPEG_ALIASES: [&str; 3] = ["jfif", "jif", "jpe"];
 
 #[derive(Debug, Responder)]
 pub enum InvalidRequest {
-    NotFnd(NotFound<Html<String>>),
+    NotModified(NotModified),
+    NotFnd(ErrorPage),
     BadReq(BadRequest<String>),
+    Forbidden(Forbidden<String>),
+    Conflict(Conflict<String>),
+    TooLarge(status::Custom<String>),
+    Fallback(FallbackImage),
+    TooMany(TooManyRequests),
+    ServerErr(ErrorPage),
+    Unavailable(status::Custom<String>),
+}
+
+/// A 429 response that tells the client how many seconds to wait before retrying.
+#[derive(Debug, Responder)]
+#[response(status = 429)]
+pub struct TooManyRequests {
+    inner: String,
+    retry_after: Header<'static>,
+}
+
+/// A 304 response, without a body, for a client whose copy of an image is unchanged.
+#[derive(Debug, Responder)]
+#[response(status = 304)]
+pub struct NotModified {
+    inner: (),
+    etag: Header<'static>,
 }
 
-/// Converts a string into a path buffer.
+/// A fallback image that responds with a 404 status, which is boxed to keep
+/// `InvalidRequest` small.
+#[derive(Debug)]
+pub struct FallbackImage(Box<NotFound<Custom<Vec<u8>>>>);
+
+impl<'r> Responder<'r, 'static> for FallbackImage {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        self.0.respond_to(req)
+    }
+}
+
+/// Joins a pathbuf with the configured root directory.
 ///
 /// Arguments:
 ///
+/// * `root` - &Path
 /// * `path` - String
 ///
-/// Returns: `&'static str`
+/// Returns: `PathBuf`
 ///
-/// Usage: ```get_file_path(path);```
-pub fn get_root_dir() -> &'static str {
-    Path::new(relative!("static")).to_str().unwrap()
+/// Usage: ```get_file_path(root, path);```
+pub fn get_file_path(root: &Path, path: impl AsRef<Path>) -> PathBuf {
+    root.join(path)
 }
 
-/// Joins a pathbuf with a relative path to the `static` folder.
+/// Retrieves the first of the root directories that contains a file at a relative path, or
+/// the first root directory when none of them do.
 ///
 /// Arguments:
 ///
-/// * `path` - String
+/// * `roots` - &[PathBuf] - must contain at least one directory
+/// * `path` - &Path
 ///
-/// Returns: `PathBuf`
+/// Returns: `&Path`
+///
+/// Usage: ```find_root(&config.roots(), &path);```
+pub fn find_root<'a>(roots: &'a [PathBuf], path: &Path) -> &'a Path {
+    // a path that isn't relative and normal could be joined onto any directory
+    let found = match is_safe_path(path) {
+        true => roots.iter().find(|root| root.join(path).is_file()),
+        false => None,
+    };
+
+    found
+        .or_else(|| roots.first())
+        .expect("At least one root directory is required")
+}
+
+/// Determines if a path has an extension that is within `SUPPORTED_EXTENSIONS`.
+///
+/// Arguments:
+///
+/// * `path` - &Path
+///
+/// Returns: `bool`
+///
+/// Usage: ```has_supported_extension(&path);```
+pub fn has_supported_extension(path: &Path) -> bool {
+    image_extension(path).is_some_and(|ext| SUPPORTED_EXTENSIONS.contains(&ext.as_str()))
+}
+
+/// Retrieves the extension of a path in lowercase, where jpeg aliases are normalized to
+/// `jpeg` => `photo.JFIF` => `jpeg`
+///
+/// Arguments:
+///
+/// * `path` - &Path
+///
+/// Returns: `Option<String>`
+///
+/// Usage: ```image_extension(&path);```
+pub fn image_extension(path: &Path) -> Option<String> {
+    let ext = path
+        .extension()
+        .and_then(OsStr::to_str)?
+        .to_ascii_lowercase();
+
+    match JPEG_ALIASES.contains(&ext.as_str()) {
+        true => Some("jpeg".to_string()),
+        false => Some(ext),
+    }
+}
+
+/// Retrieves the content type of an image from its path's extension, regardless of the
+/// extension's case or whether it's a jpeg alias.
+///
+/// Arguments:
+///
+/// * `path` - &Path
+///
+/// Returns: `Option<ContentType>`
+///
+/// Usage: ```image_content_type(&path);```
+pub fn image_content_type(path: &Path) -> Option<ContentType> {
+    image_extension(path).and_then(|ext| ContentType::from_extension(&ext))
+}
+
+/// Determines if a path has an extension that is within `VECTOR_EXTENSIONS`.
+///
+/// Arguments:
+///
+/// * `path` - &Path
+///
+/// Returns: `bool`
+///
+/// Usage: ```is_vector(&path);```
+pub fn is_vector(path: &Path) -> bool {
+    image_extension(path).is_some_and(|ext| VECTOR_EXTENSIONS.contains(&ext.as_str()))
+}
+
+/// Determines if a path is served as is rather than resized, which is either a vector image
+/// or an image whose extension is within the configured `passthrough_formats`.
+///
+/// Arguments:
+///
+/// * `path` - &Path
+/// * `passthrough_formats` - &[String]
+///
+/// Returns: `bool`
+///
+/// Usage: ```is_passthrough(&path, &config.passthrough_formats);```
+pub fn is_passthrough(path: &Path, passthrough_formats: &[String]) -> bool {
+    is_vector(path)
+        || image_extension(path).is_some_and(|ext| {
+            passthrough_formats
+                .iter()
+                .any(|format| format.eq_ignore_ascii_case(&ext))
+        })
+}
+
+/// Converts a `rrggbb` hex color to its red, green and blue channels.
+///
+/// Arguments:
+///
+/// * `color` - &str
+///
+/// Returns: `Option<[u8; 3]>`
+///
+/// Usage: ```parse_hex_color(color);```
+pub fn parse_hex_color(color: &str) -> Option<[u8; 3]> {
+    if color.len() != 6 || !color.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    let channel = |i: usize| u8::from_str_radix(&color[i..i + 2], 16).ok();
+
+    Some([channel(0)?, channel(2)?, channel(4)?])
+}
+
+/// Determines if a path contains a control character, such as a null byte, or any of the
+/// `forbidden` characters, which some filesystems can't store in a filename.
+///
+/// Arguments:
+///
+/// * `path` - &Path
+/// * `forbidden` - &str
+///
+/// Returns: `bool`
+///
+/// Usage: ```has_forbidden_characters(&path, &config.forbidden_path_characters);```
+pub fn has_forbidden_characters(path: &Path, forbidden: &str) -> bool {
+    path.to_string_lossy()
+        .chars()
+        .any(|c| c.is_control() || forbidden.contains(c))
+}
+
+/// Determines if a relative path only contains normal components, so that it can't escape
+/// the directory that it's joined to.
+///
+/// Arguments:
+///
+/// * `path` - &Path
 ///
-/// Usage: ```get_file_path(path);```
-pub fn get_file_path(path: impl AsRef<Path>) -> PathBuf {
-    Path::new(relative!("static")).join(path)
+/// Returns: `bool`
+///
+/// Usage: ```is_safe_path(&path);```
+pub fn is_safe_path(path: &Path) -> bool {
+    path.components()
+        .all(|component| matches!(component, Component::Normal(_)))
 }
 
 /// Converts a path buffer into a string.
@@ -48,6 +242,22 @@ pub fn get_string_path(path: impl AsRef<Path>) -> String {
     path.as_ref().to_str().unwrap().into()
 }
 
+/// Reusable 304 response.
+///
+/// Arguments:
+///
+/// * `etag` - String - the entity tag that the client's copy matches
+///
+/// Returns: `NotModified`
+///
+/// Usage: ```send_304_response(etag);```
+pub fn send_304_response(etag: String) -> InvalidRequest {
+    InvalidRequest::NotModified(NotModified {
+        inner: (),
+        etag: Header::new("ETag", etag),
+    })
+}
+
 /// Reusable 400 response.
 ///
 /// Arguments:
@@ -61,18 +271,113 @@ pub fn send_400_response(reason: String) -> InvalidRequest {
     InvalidRequest::BadReq(BadRequest(Some(reason)))
 }
 
-/// Reusable 404 response.
+/// Reusable 403 response.
 ///
 /// Arguments:
 ///
-/// * `reason` - &str
+/// * `reason` - String
 ///
-/// Returns: `NotFound(Html<String>)`
+/// Returns: `Forbidden<String>`
 ///
-/// Usage: ```send_error_response(reason);```
+/// Usage: ```send_403_response(reason);```
+pub fn send_403_response(reason: String) -> InvalidRequest {
+    InvalidRequest::Forbidden(Forbidden(Some(reason)))
+}
+
+/// Reusable 409 response.
+///
+/// Arguments:
+///
+/// * `reason` - String
+///
+/// Returns: `Conflict<String>`
+///
+/// Usage: ```send_409_response(reason);```
+pub fn send_409_response(reason: String) -> InvalidRequest {
+    InvalidRequest::Conflict(Conflict(Some(reason)))
+}
+
+/// Reusable 413 response.
+///
+/// Arguments:
+///
+/// * `reason` - String
+///
+/// Returns: `status::Custom<String>`
+///
+/// Usage: ```send_413_response(reason);```
+pub fn send_413_response(reason: String) -> InvalidRequest {
+    InvalidRequest::TooLarge(status::Custom(Status::PayloadTooLarge, reason))
+}
+
+/// Reusable 429 response.
+///
+/// Arguments:
+///
+/// * `retry_after` - u64 - seconds until the client can retry
+///
+/// Returns: `TooManyRequests`
+///
+/// Usage: ```send_429_response(retry_after);```
+pub fn send_429_response(retry_after: u64) -> InvalidRequest {
+    InvalidRequest::TooMany(TooManyRequests {
+        inner: format!("Too many requests! Try again in {} second(s).", retry_after),
+        retry_after: Header::new("Retry-After", retry_after.to_string()),
+    })
+}
+
+/// Reusable 404 response that responds with a fallback image in place of a missing image.
+///
+/// Arguments:
+///
+/// * `content_type` - ContentType
+/// * `contents` - Vec<u8>
+///
+/// Returns: `FallbackImage`
+///
+/// Usage: ```send_fallback_response(content_type, contents);```
+pub fn send_fallback_response(content_type: ContentType, contents: Vec<u8>) -> InvalidRequest {
+    InvalidRequest::Fallback(FallbackImage(Box::new(NotFound(Custom(
+        content_type,
+        contents,
+    )))))
+}
+
+/// Reusable 404 response, which renders the configured `not_found_template`.
+///
+/// Arguments:
+///
+/// * `reason` - String
+///
+/// Returns: `ErrorPage`
+///
+/// Usage: ```send_404_response(reason);```
 pub fn send_404_response(reason: String) -> InvalidRequest {
-    InvalidRequest::NotFnd(NotFound(Html(format!(
-    "<!DOCTYPE html><html lang='en' style='height: 100%;'><head><meta charset='utf-8'><title>Resource Not Found</title></head><body style='height: 100%;margin: 0;'><div style='display: -webkit-box;display: -ms-flexbox;display: flex;-webkit-box-orient: vertical;-webkit-box-direction: normal;-ms-flex-direction: column;flex-direction: column;-webkit-box-pack: center;-ms-flex-pack: center;justify-content: center;height: 100%;'><h1 style='text-align:center;font-size:100px;margin:0;'>404 Not Found</h1><h1 style='text-align:center;'>{}</h1></body></div></html>",
-    reason
-  ))))
+    InvalidRequest::NotFnd(ErrorPage::new(Status::NotFound, reason))
+}
+
+/// Reusable 500 response, which renders the configured `server_error_template`.
+///
+/// Arguments:
+///
+/// * `reason` - String
+///
+/// Returns: `ErrorPage`
+///
+/// Usage: ```send_500_response(reason);```
+pub fn send_500_response(reason: String) -> InvalidRequest {
+    InvalidRequest::ServerErr(ErrorPage::new(Status::InternalServerError, reason))
+}
+
+/// Reusable 503 response.
+///
+/// Arguments:
+///
+/// * `reason` - String
+///
+/// Returns: `status::Custom<String>`
+///
+/// Usage: ```send_503_response(reason);```
+pub fn send_503_response(reason: String) -> InvalidRequest {
+    InvalidRequest::Unavailable(status::Custom(Status::ServiceUnavailable, reason))
 }